@@ -26,13 +26,27 @@ if command -v tput &> /dev/null; then
 fi
 
 # Utils
+# Hosted kernel is a program for the machine it's built on
+host_target() {
+	rustc -vV | sed -n 's/^host: //p'
+}
+
 build() {
-	if ! cargo build "$@"; then
+	if [ "$ARCH" = "hosted" ]; then
+		cargo build --target "$(host_target)" "$@"
+		return
+	fi
+
+	if ! cargo build --target "src/arch/$ARCH/target.json" "$@"; then
 		return 1
 	fi
 
 	KERNEL="target/target/debug/satan"
 	GRUB_CFG="src/arch/$ARCH/grub"
+	# Architectures without GRUB boot the kernel directly
+	if [ ! -d "$GRUB_CFG" ]; then
+		return 0
+	fi
 	if [ "$KERNEL" -nt "bin/os.iso" ] || [ "$GRUB_CFG" -nt "bin/os.iso" ]; then
 		echo "${green}Building the system...${normal}"
 		rm -rf bin/iso bin/os.iso
@@ -49,16 +63,31 @@ build() {
 }
 
 run() {
-	if [ "$EMULATOR" = "bochs" ]; then
+	if [ "$ARCH" = "hosted" ]; then
+		"target/$(host_target)/debug/satan"
+	elif [ "$EMULATOR" = "bochs" ]; then
 		bochs -q
+	elif [ "$QEMU_SYSTEM" = "riscv64" ]; then
+		qemu-system-riscv64 -M virt -nographic -no-reboot -kernel target/target/debug/satan
+	elif [ "$QEMU_SYSTEM" = "aarch64" ]; then
+		qemu-system-aarch64 -M virt -cpu cortex-a57 -nographic -no-reboot -kernel target/target/debug/satan
 	else
 		qemu-system-"$QEMU_SYSTEM" -d guest_errors -no-reboot -cdrom bin/os.iso
 	fi
 }
 
 debug() {
-	if [ "$EMULATOR" = "bochs" ]; then
+	if [ "$ARCH" = "hosted" ]; then
+		# Emulated page faults are SIGSEGVs, that the kernel handles
+		rust-gdb "target/$(host_target)/debug/satan" -ex "handle SIGSEGV nostop noprint"
+	elif [ "$EMULATOR" = "bochs" ]; then
 		bochs -q
+	elif [ "$QEMU_SYSTEM" = "riscv64" ]; then
+		qemu-system-riscv64 -M virt -nographic -no-reboot -kernel target/target/debug/satan -s -S &
+		rust-gdb target/target/debug/satan -x gdbinit
+	elif [ "$QEMU_SYSTEM" = "aarch64" ]; then
+		qemu-system-aarch64 -M virt -cpu cortex-a57 -nographic -no-reboot -kernel target/target/debug/satan -s -S &
+		rust-gdb target/target/debug/satan -x gdbinit
 	else
 		qemu-system-"$QEMU_SYSTEM" -d guest_errors -no-reboot -cdrom bin/os.iso -s -S &
 		rust-gdb target/target/debug/satan -x gdbinit
@@ -78,7 +107,9 @@ print() {
 help() {
 	cat <<-EOF
 	SATAN Build system
-	Usage: ./build.sh [--arch x86] [--toolchain i686-elf] [--quemu-system x86_64] [command]
+	Usage: ./build.sh [--arch x86/x32] [--toolchain i686-elf] [--quemu-system x86_64] [command]
+	Architectures: x86/x32, x86/x64, riscv64 and aarch64 (with the same --qemu-system)
+	and hosted, which runs the kernel as a Linux process
 	When ran without command, REPL mode will be entered
 	Commands:
 	build - build kernel and OS
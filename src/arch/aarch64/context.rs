@@ -0,0 +1,74 @@
+/// Saved state of a thread, that is not running. Callee-saved
+/// registers are stored on it's stack, only the stack pointer is kept
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Context {
+    sp: usize,
+}
+
+/// Stack frame, that [`switch_context`] leaves on the stack of a thread it
+/// switched away from: callee-saved registers x19-x28, frame pointer and
+/// link register. New threads start in [`thread_entry`] with entry
+/// and argument in x19 and x20
+#[repr(C)]
+struct Frame {
+    x: [usize; 10],
+    fp: usize,
+    lr: usize,
+}
+
+impl crate::arch::ContextTrait for Context {
+    fn new(stack_top: memory_addr::VirtAddr, entry: extern "C" fn(usize) -> !, arg: usize) -> Self {
+        let sp = stack_top.as_usize() - core::mem::size_of::<Frame>();
+        let mut x = [0; 10];
+        x[0] = entry as usize;
+        x[1] = arg;
+        let frame = Frame {
+            x,
+            fp: 0,
+            lr: thread_entry as *const () as usize,
+        };
+        unsafe {
+            (sp as *mut Frame).write(frame);
+        }
+        Self { sp }
+    }
+
+    unsafe fn switch_to(from: *mut Self, to: *const Self) {
+        unsafe { switch_context(from, to) }
+    }
+}
+
+/// Store callee-saved registers on the stack, save the stack pointer
+/// into `from`, then load the one from `to` and restore it's registers
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(from: *mut Context, to: *const Context) {
+    core::arch::naked_asm!(
+        "sub sp, sp, #{frame_size}",
+        "stp x19, x20, [sp, #0]",
+        "stp x21, x22, [sp, #16]",
+        "stp x23, x24, [sp, #32]",
+        "stp x25, x26, [sp, #48]",
+        "stp x27, x28, [sp, #64]",
+        "stp x29, x30, [sp, #80]",
+        "mov x9, sp",
+        "str x9, [x0]",
+        "ldr x9, [x1]",
+        "mov sp, x9",
+        "ldp x19, x20, [sp, #0]",
+        "ldp x21, x22, [sp, #16]",
+        "ldp x23, x24, [sp, #32]",
+        "ldp x25, x26, [sp, #48]",
+        "ldp x27, x28, [sp, #64]",
+        "ldp x29, x30, [sp, #80]",
+        "add sp, sp, #{frame_size}",
+        "ret",
+        frame_size = const core::mem::size_of::<Frame>(),
+    );
+}
+
+/// First switch to a new thread returns here. Entry never returns
+#[unsafe(naked)]
+unsafe extern "C" fn thread_entry() -> ! {
+    core::arch::naked_asm!("mov x0, x20", "blr x19", "brk #1");
+}
@@ -0,0 +1,28 @@
+pub struct Cpu;
+
+impl crate::arch::CpuTrait for Cpu {
+    const MAX_CPUS: usize = 16;
+
+    fn cpu_id() -> usize {
+        use aarch64_cpu::registers::{Readable as _, MPIDR_EL1};
+        // Lowest affinity level is the core number on QEMU virt
+        (MPIDR_EL1.get() & 0xff) as usize
+    }
+
+    fn disable_interrupts() -> bool {
+        use aarch64_cpu::registers::{Readable as _, DAIF};
+        let enabled = !DAIF.is_set(DAIF::I);
+        super::interrupts::disable();
+        enabled
+    }
+
+    fn enable_interrupts() {
+        super::interrupts::enable();
+    }
+
+    fn wait_for_interrupt() {
+        // wfi wakes up on a pending interrupt, even if it is masked
+        aarch64_cpu::asm::wfi();
+        super::interrupts::enable();
+    }
+}
@@ -0,0 +1,64 @@
+use memory_addr::PhysAddr;
+
+/// PL011 UART on QEMU virt. It's reachable through the
+/// direct mapping, that the bootstrap sets up
+const UART_BASE: usize = 0x0900_0000;
+/// Data register
+const UART_DR: usize = 0x00;
+/// Flag register
+const UART_FR: usize = 0x18;
+/// Transmit FIFO full flag
+const UART_FR_TXFF: u32 = 1 << 5;
+
+/// Writes to the PL011 serial port
+struct Writer;
+
+impl Writer {
+    fn write_byte(&mut self, byte: u8) {
+        let base = super::memory::phys2virt(PhysAddr::from_usize(UART_BASE));
+        unsafe {
+            let flags = (base + UART_FR).as_ptr_of::<u32>();
+            while flags.read_volatile() & UART_FR_TXFF != 0 {
+                core::hint::spin_loop();
+            }
+            (base + UART_DR)
+                .as_mut_ptr_of::<u32>()
+                .write_volatile(byte as u32);
+        }
+    }
+}
+
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+static WRITER: crate::sync::IrqSafeMutex<Writer> = crate::sync::irq_safe_mutex(Writer);
+
+pub struct EarlyLogger;
+impl crate::arch::LoggerTrait for EarlyLogger {
+    fn _print(args: core::fmt::Arguments) {
+        use core::fmt::Write as _;
+        WRITER.lock().write_fmt(args).unwrap();
+    }
+
+    fn _panic(args: core::fmt::Arguments) -> ! {
+        use core::fmt::Write as _;
+        let mut writer = WRITER.lock();
+        // Red, like the VGA logger on x86
+        writer.write_str("\x1b[31m").unwrap();
+        writer.write_fmt(args).unwrap();
+        writer.write_str("\x1b[0m").unwrap();
+        super::interrupts::disable();
+        loop {
+            aarch64_cpu::asm::wfi();
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use memory_addr::{PhysAddr, VirtAddr};
+
+/// Interrupt ids from 1020 up are special, 1023 means there is no pending interrupt
+const SPECIAL_INTERRUPTS: u32 = 1020;
+/// Interrupts below this are private to a CPU: SGIs and PPIs
+const PRIVATE_INTERRUPTS: u32 = 32;
+
+// Distributor registers
+const GICD_CTLR: usize = 0x0000;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ITARGETSR: usize = 0x0800;
+const GICD_IROUTER: usize = 0x6000;
+/// Enable both interrupt groups and, on GICv3, affinity routing
+const GICD_CTLR_ENABLE: u32 = (1 << 0) | (1 << 1) | (1 << 4);
+/// Register write is in progress (GICv3)
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+// GICv2 CPU interface registers
+const GICC_CTLR: usize = 0x00;
+const GICC_PMR: usize = 0x04;
+const GICC_IAR: usize = 0x0c;
+const GICC_EOIR: usize = 0x10;
+
+// GICv3 redistributor registers. Private interrupts are configured in the
+// SGI frame, with the same layout as the distributor
+const GICR_WAKER: usize = 0x0014;
+const GICR_SGI_FRAME: usize = 0x10000;
+const GICR_IGROUPR0: usize = 0x0080;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// Priority mask, that lets all interrupts through
+const PRIORITY_MASK: u32 = 0xff;
+/// Priority of every enabled interrupt
+const PRIORITY: u8 = 0xa0;
+
+/// Generic Interrupt Controller. GICv2 has a memory mapped CPU interface,
+/// GICv3 uses system registers for it and has a redistributor per CPU
+#[derive(Clone, Copy, Debug)]
+enum Gic {
+    V2 {
+        distributor: VirtAddr,
+        cpu_interface: VirtAddr,
+    },
+    V3 {
+        distributor: VirtAddr,
+        redistributor: VirtAddr,
+    },
+}
+
+static GIC: spin::Once<Gic> = spin::Once::new();
+
+/// Read a GIC register
+fn read(base: VirtAddr, offset: usize) -> u32 {
+    unsafe { (base + offset).as_ptr_of::<u32>().read_volatile() }
+}
+
+/// Write a GIC register
+fn write(base: VirtAddr, offset: usize, value: u32) {
+    unsafe { (base + offset).as_mut_ptr_of::<u32>().write_volatile(value) }
+}
+
+impl Gic {
+    /// Find the GIC in the device tree. Both versions have two register
+    /// ranges: the distributor first, then the CPU interface or the redistributors
+    fn probe(device_tree: &fdt::Fdt) -> Option<Self> {
+        let ranges = |node: fdt::node::FdtNode| {
+            let mut reg = node.reg()?.map(|region| {
+                super::memory::phys2virt(PhysAddr::from_usize(region.starting_address as usize))
+            });
+            Some((reg.next()?, reg.next()?))
+        };
+
+        if let Some(node) = device_tree.find_compatible(&["arm,gic-v3"]) {
+            let (distributor, redistributor) = ranges(node)?;
+            return Some(Self::V3 {
+                distributor,
+                redistributor,
+            });
+        }
+        let node = device_tree.find_compatible(&[
+            "arm,gic-400",
+            "arm,cortex-a15-gic",
+            "arm,cortex-a9-gic",
+        ])?;
+        let (distributor, cpu_interface) = ranges(node)?;
+        Some(Self::V2 {
+            distributor,
+            cpu_interface,
+        })
+    }
+
+    /// Enable the distributor and the CPU interface of this CPU
+    fn init(&self) {
+        match *self {
+            Self::V2 {
+                distributor,
+                cpu_interface,
+            } => {
+                write(distributor, GICD_CTLR, GICD_CTLR_ENABLE);
+                write(cpu_interface, GICC_PMR, PRIORITY_MASK);
+                write(cpu_interface, GICC_CTLR, 1);
+            }
+            Self::V3 {
+                distributor,
+                redistributor,
+            } => {
+                write(distributor, GICD_CTLR, GICD_CTLR_ENABLE);
+                while read(distributor, GICD_CTLR) & GICD_CTLR_RWP != 0 {
+                    core::hint::spin_loop();
+                }
+
+                let waker = read(redistributor, GICR_WAKER);
+                write(
+                    redistributor,
+                    GICR_WAKER,
+                    waker & !GICR_WAKER_PROCESSOR_SLEEP,
+                );
+                while read(redistributor, GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+                    core::hint::spin_loop();
+                }
+                // Group 1 interrupts are signalled as IRQs
+                write(redistributor + GICR_SGI_FRAME, GICR_IGROUPR0, u32::MAX);
+
+                unsafe {
+                    core::arch::asm!(
+                        "msr S3_0_C12_C12_5, {sre}", // ICC_SRE_EL1, system register interface
+                        "isb",
+                        "msr S3_0_C4_C6_0, {pmr}",   // ICC_PMR_EL1
+                        "msr S3_0_C12_C12_7, {on}",  // ICC_IGRPEN1_EL1
+                        "isb",
+                        sre = in(reg) 1usize,
+                        pmr = in(reg) PRIORITY_MASK as usize,
+                        on = in(reg) 1usize,
+                        options(nostack),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Enable an interrupt and send it to this CPU
+    fn enable(&self, id: u32) {
+        let registers = match *self {
+            Self::V3 { redistributor, .. } if id < PRIVATE_INTERRUPTS => {
+                redistributor + GICR_SGI_FRAME
+            }
+            Self::V2 { distributor, .. } | Self::V3 { distributor, .. } => distributor,
+        };
+        if id >= PRIVATE_INTERRUPTS {
+            match *self {
+                Self::V2 { distributor, .. } => unsafe {
+                    (distributor + GICD_ITARGETSR + id as usize)
+                        .as_mut_ptr()
+                        .write_volatile(1u8);
+                },
+                Self::V3 { distributor, .. } => {
+                    // Route to the CPU with affinity 0.0.0.0
+                    let router = distributor + GICD_IROUTER + id as usize * 8;
+                    unsafe { router.as_mut_ptr_of::<u64>().write_volatile(0) };
+                }
+            }
+        }
+        unsafe {
+            (registers + GICD_IPRIORITYR + id as usize)
+                .as_mut_ptr()
+                .write_volatile(PRIORITY);
+        }
+        let index = id as usize / 32;
+        write(registers, GICD_ISENABLER + index * 4, 1 << (id % 32));
+    }
+
+    /// Get the id of the pending interrupt, it becomes active
+    fn acknowledge(&self) -> u32 {
+        match *self {
+            Self::V2 { cpu_interface, .. } => read(cpu_interface, GICC_IAR) & 0x3ff,
+            Self::V3 { .. } => {
+                let id: usize;
+                unsafe {
+                    // ICC_IAR1_EL1
+                    core::arch::asm!("mrs {}, S3_0_C12_C12_0", out(reg) id, options(nostack));
+                }
+                id as u32 & 0xffffff
+            }
+        }
+    }
+
+    /// Signal the end of the interrupt handling
+    fn end_of_interrupt(&self, id: u32) {
+        match *self {
+            Self::V2 { cpu_interface, .. } => write(cpu_interface, GICC_EOIR, id),
+            Self::V3 { .. } => unsafe {
+                // ICC_EOIR1_EL1
+                core::arch::asm!("msr S3_0_C12_C12_1, {}", in(reg) id as usize, options(nostack));
+            },
+        }
+    }
+}
+
+/// Handle an IRQ exception
+pub(super) fn handle_interrupt() {
+    let gic = GIC.get().expect("Interrupt before the GIC is setup");
+    let id = gic.acknowledge();
+    if id >= SPECIAL_INTERRUPTS {
+        // Spurious, the interrupt was taken by another CPU or withdrawn
+        return;
+    }
+    match id {
+        super::timer::INTERRUPT_ID => super::timer::tick(),
+        _ => crate::println!("Unhandled interrupt {}", id),
+    }
+    gic.end_of_interrupt(id);
+}
+
+/// Enable an interrupt on this CPU
+pub(super) fn enable_interrupt(id: u32) {
+    GIC.get().expect("GIC is not setup").enable(id);
+}
+
+/// Find and enable the interrupt controller
+pub(super) fn setup(device_tree: &fdt::Fdt) {
+    let gic = GIC.call_once(|| Gic::probe(device_tree).expect("No GIC in the device tree"));
+    gic.init();
+    crate::println!(
+        "GIC{} is setup",
+        match gic {
+            Gic::V2 { .. } => "v2",
+            Gic::V3 { .. } => "v3",
+        }
+    );
+}
@@ -0,0 +1,183 @@
+use aarch64_cpu::registers::{Readable as _, Writeable as _, ESR_EL1, FAR_EL1, VBAR_EL1};
+
+/// Registers saved by the exception entry. General purpose
+/// registers are indexed by their number
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct InterruptStackFrame {
+    pub(super) registers: [usize; 31],
+    pub(super) sp: usize,
+    pub(super) elr: usize,
+    pub(super) spsr: usize,
+}
+
+/// Size of the saved state on the stack, keeps the stack 16 byte aligned
+const FRAME_SIZE: usize = core::mem::size_of::<InterruptStackFrame>().next_multiple_of(16);
+
+/// Kinds of exceptions, the vector of each kind is repeated for every source
+const SYNCHRONOUS: usize = 0;
+const IRQ: usize = 1;
+/// Vectors of exceptions, that come from a lower exception level, start here
+const LOWER_EL: usize = 8;
+
+/// Write not Read bit of the data abort syndrome
+const ISS_WNR: u64 = 1 << 6;
+
+// The table has 16 vectors of 0x80 bytes: synchronous, IRQ, FIQ and SError for the
+// current EL with SP_EL0, the current EL with SP_ELx, lower EL in AArch64 and in AArch32.
+// Each one saves two registers, so that it can pass it's number to the common part
+core::arch::global_asm!(
+    ".macro vector kind",
+    ".balign 0x80",
+    "sub sp, sp, #{frame_size}",
+    "stp x0, x1, [sp]",
+    "mov x1, #\\kind",
+    "b trap_common",
+    ".endm",
+    ".section .text",
+    ".balign 0x800",
+    ".global exception_vectors",
+    "exception_vectors:",
+    ".irp kind, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15",
+    "vector \\kind",
+    ".endr",
+    "trap_common:",
+    "stp x2, x3, [sp, #2*8]",
+    "stp x4, x5, [sp, #4*8]",
+    "stp x6, x7, [sp, #6*8]",
+    "stp x8, x9, [sp, #8*8]",
+    "stp x10, x11, [sp, #10*8]",
+    "stp x12, x13, [sp, #12*8]",
+    "stp x14, x15, [sp, #14*8]",
+    "stp x16, x17, [sp, #16*8]",
+    "stp x18, x19, [sp, #18*8]",
+    "stp x20, x21, [sp, #20*8]",
+    "stp x22, x23, [sp, #22*8]",
+    "stp x24, x25, [sp, #24*8]",
+    "stp x26, x27, [sp, #26*8]",
+    "stp x28, x29, [sp, #28*8]",
+    "str x30, [sp, #30*8]",
+    "add x2, sp, #{frame_size}",
+    "mrs x3, elr_el1",
+    "stp x2, x3, [sp, #31*8]",
+    "mrs x2, spsr_el1",
+    "str x2, [sp, #33*8]",
+    "mov x0, sp",
+    "bl {trap_handler}",
+    "ldr x2, [sp, #32*8]",
+    "msr elr_el1, x2",
+    "ldr x2, [sp, #33*8]",
+    "msr spsr_el1, x2",
+    "ldp x0, x1, [sp]",
+    "ldp x2, x3, [sp, #2*8]",
+    "ldp x4, x5, [sp, #4*8]",
+    "ldp x6, x7, [sp, #6*8]",
+    "ldp x8, x9, [sp, #8*8]",
+    "ldp x10, x11, [sp, #10*8]",
+    "ldp x12, x13, [sp, #12*8]",
+    "ldp x14, x15, [sp, #14*8]",
+    "ldp x16, x17, [sp, #16*8]",
+    "ldp x18, x19, [sp, #18*8]",
+    "ldp x20, x21, [sp, #20*8]",
+    "ldp x22, x23, [sp, #22*8]",
+    "ldp x24, x25, [sp, #24*8]",
+    "ldp x26, x27, [sp, #26*8]",
+    "ldp x28, x29, [sp, #28*8]",
+    "ldr x30, [sp, #30*8]",
+    "add sp, sp, #{frame_size}",
+    "eret",
+    frame_size = const FRAME_SIZE,
+    trap_handler = sym trap_handler,
+);
+
+extern "C" {
+    fn exception_vectors();
+}
+
+/// Central exception handler, `kind` is the number of the vector
+extern "C" fn trap_handler(frame: &mut InterruptStackFrame, kind: usize) {
+    match kind % 4 {
+        SYNCHRONOUS => synchronous_exception(frame, kind >= LOWER_EL),
+        IRQ => {
+            super::gic::handle_interrupt();
+            crate::thread::scheduler::irq_return();
+        }
+        _ => panic!(
+            "Unexpected exception (vector {}) at {:#x}",
+            kind, frame.elr
+        ),
+    }
+}
+
+/// Handle an exception caused by an instruction
+fn synchronous_exception(frame: &mut InterruptStackFrame, from_user: bool) {
+    use aarch64_cpu::registers::ESR_EL1::EC::Value as ExceptionClass;
+
+    let syndrome = ESR_EL1.extract();
+    let class = syndrome.read_as_enum(ESR_EL1::EC);
+    match class {
+        Some(
+            ExceptionClass::DataAbortCurrentEL
+            | ExceptionClass::DataAbortLowerEL
+            | ExceptionClass::InstrAbortCurrentEL
+            | ExceptionClass::InstrAbortLowerEL,
+        ) => {
+            use crate::memory::MappingFlags;
+
+            let address = memory_addr::VirtAddr::from_usize(FAR_EL1.get() as usize);
+            let mut access = match class {
+                Some(ExceptionClass::InstrAbortCurrentEL | ExceptionClass::InstrAbortLowerEL) => {
+                    MappingFlags::EXECUTE
+                }
+                _ if syndrome.read(ESR_EL1::ISS) & ISS_WNR != 0 => MappingFlags::WRITE,
+                _ => MappingFlags::READ,
+            };
+            if from_user {
+                access |= MappingFlags::USER;
+            }
+            let Err(err) = super::memory::handle_page_fault(address, access) else {
+                return;
+            };
+            // Accessing user memory is allowed to fault
+            if let Some(fixup) = super::memory::exception_fixup(frame.elr) {
+                frame.elr = fixup;
+                return;
+            }
+            crate::println!("{}", err);
+            panic!("Page fault at {:#x}", frame.elr);
+        }
+        Some(ExceptionClass::Brk64) => {
+            crate::println!("Test breakpoint\n{:#x?}", frame);
+            // Unlike x86, return address points to the breakpoint itself
+            frame.elr += 4;
+        }
+        _ => panic!(
+            "Exception class {:#x} at {:#x}\nFault address: {:#x}",
+            syndrome.read(ESR_EL1::EC),
+            frame.elr,
+            FAR_EL1.get()
+        ),
+    }
+}
+
+/// Mask IRQs on this CPU
+pub(super) fn disable() {
+    unsafe {
+        core::arch::asm!("msr daifset, #2", options(nomem, nostack));
+    }
+}
+
+/// Unmask IRQs on this CPU
+pub(super) fn enable() {
+    unsafe {
+        core::arch::asm!("msr daifclr, #2", options(nomem, nostack));
+    }
+}
+
+/// Install the exception vectors and unmask IRQs. Nothing
+/// interrupts the CPU until the GIC is set up
+pub(super) fn setup() {
+    VBAR_EL1.set(exception_vectors as unsafe extern "C" fn() as usize as u64);
+    enable();
+    crate::println!("Exception vectors are setup");
+}
@@ -0,0 +1,269 @@
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+
+use super::PageSize;
+/// Physical page table entry types
+mod entry {
+    pub(super) use super::super::PTEntry;
+}
+
+use crate::memory::address_space::nested_page_table::{NestedPageTable, NestedPageTableLevel};
+use crate::memory::address_space::AddressSpaceTrait;
+use crate::memory::{FaultError, FrameFlags, Vma, VmaError};
+use crate::memory::{MappingError, MappingResult, PageAllocatorTrait};
+
+/// Interface page table entry types
+mod if_entry {
+    pub(super) use crate::memory::address_space::nested_page_table::PageTableEntry;
+    pub(super) use crate::memory::MappingFlags;
+}
+
+/// Memory areas of every address space, by address of the top level page table
+static AREAS: crate::sync::IrqSafeRwLock<
+    alloc::collections::BTreeMap<PhysAddr, crate::memory::address_space::vma::VmaSet>,
+> = crate::sync::irq_safe_rw_lock(alloc::collections::BTreeMap::new());
+
+/// Address space struct
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AddressSpace(PageTableLevel);
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PageTableLevel(PhysAddr, usize);
+
+impl AddressSpace {
+    pub(super) fn from_paddr(addr: PhysAddr) -> Self {
+        Self(PageTableLevel(addr, super::TOP_LEVEL_BITS))
+    }
+
+    /// Get the address space that is currently active on this CPU. Kernel half
+    /// always uses the kernel top level table, so only the user half is switched
+    pub fn current() -> Self {
+        use aarch64_cpu::registers::TTBR0_EL1;
+        Self::from_paddr(PhysAddr::from_usize(
+            TTBR0_EL1.get_baddr() as usize,
+        ))
+    }
+
+    /// Switch to this address space
+    pub fn activate(&self) {
+        use aarch64_cpu::registers::TTBR0_EL1;
+        TTBR0_EL1.set_baddr(self.0 .0.as_usize() as u64);
+        super::flush_tlb(None);
+    }
+
+    /// Handle a fault on a page that is not present by
+    /// mapping it, if it belongs to a memory area
+    pub fn handle_fault(
+        &self,
+        vaddr: VirtAddr,
+        access: if_entry::MappingFlags,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> Result<(), FaultError> {
+        // Populating allocates memory and takes the page table lock, so
+        // it's done on a copy of the area, without holding the areas lock
+        let area = AREAS
+            .read()
+            .get(&self.0 .0)
+            .and_then(|areas| areas.find(vaddr))
+            .cloned()
+            .ok_or(FaultError::NoArea(vaddr))?;
+        if !area.allows(access) {
+            return Err(FaultError::AccessViolation(vaddr, access));
+        }
+        self.populate(&area, vaddr, alloc)?;
+        Ok(())
+    }
+
+    /// Get flags of the page mapped at vaddr, empty if nothing is mapped
+    pub fn page_flags(&self, vaddr: VirtAddr) -> MappingResult<if_entry::MappingFlags> {
+        let mut level = self.top_level();
+        loop {
+            match level.get_entry(vaddr)? {
+                if_entry::PageTableEntry::Level(sublevel) => level = sublevel,
+                if_entry::PageTableEntry::Page(_, flags) => return Ok(flags),
+            }
+        }
+    }
+}
+
+impl PageTableLevel {
+    /// Allocate and clear a new page table
+    fn new(bits: usize, alloc: &impl PageAllocatorTrait<PageSize>) -> Option<Self> {
+        let addr = alloc.alloc(PageSize::Size4K)?;
+        if let Some(frame) = crate::memory::frame::frames().and_then(|frames| frames.get(addr)) {
+            frame.insert_flags(FrameFlags::PAGE_TABLE);
+        }
+        let level = PageTableLevel(addr, bits);
+        level.page_table().fill(entry::PTEntry::NULL);
+        Some(level)
+    }
+
+    /// Page table of this level. All page tables are accessed through the direct mapping
+    #[allow(clippy::mut_from_ref)]
+    fn page_table(&self) -> &mut super::PageTable {
+        unsafe { &mut *super::phys2virt(self.0).as_mut_ptr_of() }
+    }
+
+    /// Get the page table entry associated with this address
+    #[allow(clippy::mut_from_ref)]
+    fn entry(&self, vaddr: VirtAddr) -> &mut entry::PTEntry {
+        let mask = super::PAGE_TABLE_ENTRIES - 1;
+        let index = (vaddr.as_usize() >> self.1) & mask;
+        &mut self.page_table()[index]
+    }
+}
+
+impl NestedPageTable for AddressSpace {
+    type PageSize = PageSize;
+    type Level = PageTableLevel;
+
+    fn top_level(&self) -> Self::Level {
+        self.0.clone()
+    }
+}
+
+impl NestedPageTableLevel for PageTableLevel {
+    type PageSize = PageSize;
+
+    fn region_size(&self) -> usize {
+        1 << self.1
+    }
+
+    fn new_sublevel(&self, alloc: &impl PageAllocatorTrait<Self::PageSize>) -> Option<Self> {
+        PageTableLevel::new(self.1 - super::PAGE_LEVEL_BITS, alloc)
+    }
+
+    fn free_sublevel(
+        &self,
+        sublevel: Self,
+        alloc: &impl PageAllocatorTrait<Self::PageSize>,
+    ) -> MappingResult<()> {
+        if let Some(frame) =
+            crate::memory::frame::frames().and_then(|frames| frames.get(sublevel.0))
+        {
+            frame.remove_flags(FrameFlags::PAGE_TABLE);
+        }
+        alloc.free(sublevel.0, PageSize::Size4K);
+        Ok(())
+    }
+
+    fn set_entry(
+        &self,
+        vaddr: VirtAddr,
+        new_entry: crate::memory::address_space::nested_page_table::PageTableEntry<Self>,
+    ) -> MappingResult<()> {
+        if matches!(new_entry, if_entry::PageTableEntry::Page(_, _)) {
+            debug_assert!(vaddr.is_aligned(1usize << self.1));
+        }
+
+        *self.entry(vaddr) = match new_entry {
+            if_entry::PageTableEntry::Level(level) => entry::PTEntry::new_page_table(level.0),
+            if_entry::PageTableEntry::Page(paddr, flags) => {
+                entry::PTEntry::new_page(paddr, flags.into(), self.1 == 12)
+            }
+        };
+
+        // TODO: Check if this page table is currently active
+        if self.1 > 12 {
+            // Whole range of a table could be cached
+            super::flush_tlb(None);
+        } else {
+            super::flush_tlb(Some(vaddr));
+        }
+        Ok(())
+    }
+
+    fn get_entry(&self, vaddr: VirtAddr) -> MappingResult<if_entry::PageTableEntry<Self>> {
+        let entry = *self.entry(vaddr);
+        if self.1 > 12 && entry.is_page_table() {
+            Ok(if_entry::PageTableEntry::Level(PageTableLevel(
+                entry.address(),
+                self.1 - super::PAGE_LEVEL_BITS,
+            )))
+        } else {
+            Ok(if_entry::PageTableEntry::Page(
+                entry.address(),
+                entry.flags().into(),
+            ))
+        }
+    }
+
+    fn write_page(
+        &self,
+        paddr: PhysAddr,
+        page_size: Self::PageSize,
+        mut write: impl FnMut(usize, &mut [u8]),
+    ) {
+        // Whole page is reachable through the direct mapping
+        let page = unsafe {
+            core::slice::from_raw_parts_mut(
+                super::phys2virt(paddr).as_mut_ptr(),
+                page_size.into(),
+            )
+        };
+        write(0, page);
+    }
+}
+
+impl AddressSpaceTrait<PageSize> for AddressSpace {
+    fn map_alloc(
+        &self,
+        vaddr: VirtAddr,
+        size: usize,
+        flags: if_entry::MappingFlags,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> MappingResult<VirtAddr> {
+        <Self as NestedPageTable>::map_alloc(self, vaddr, size, flags, alloc)
+    }
+
+    fn unmap_free(
+        &self,
+        vaddr: VirtAddr,
+        size: usize,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> MappingResult<()> {
+        <Self as NestedPageTable>::unmap_free(self, vaddr, size, alloc)
+    }
+
+    fn reserve(&self, area: Vma) -> Result<(), VmaError> {
+        AREAS.write().entry(self.0 .0).or_default().insert(area)
+    }
+
+    fn release(
+        &self,
+        vaddr: VirtAddr,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> Result<(), VmaError> {
+        let area = AREAS
+            .write()
+            .get_mut(&self.0 .0)
+            .ok_or(VmaError::NotFound(vaddr))?
+            .remove(vaddr)?;
+        <Self as NestedPageTable>::unmap_free(self, area.start, area.size, alloc)?;
+        Ok(())
+    }
+
+    fn fork(&self, alloc: &impl PageAllocatorTrait<PageSize>) -> MappingResult<Self> {
+        let top_level = self.top_level();
+        let child =
+            PageTableLevel::new(top_level.1, alloc).ok_or(MappingError::PageAllocationFailed)?;
+
+        self.fork_into(&child, VirtAddr::from_usize(0), super::USER_END, alloc)?;
+
+        // Kernel half is translated by the kernel top level table through TTBR1,
+        // but it's copied anyway, so that the child can be walked like the parent
+        let step = top_level.region_size();
+        for vaddr in (super::KERNEL_HALF_START..=usize::MAX).step_by(step) {
+            let vaddr = VirtAddr::from_usize(vaddr);
+            let entry = top_level.get_entry(vaddr)?;
+            if entry.mapped() {
+                child.set_entry(vaddr, entry)?;
+            }
+        }
+
+        let mut areas = AREAS.write();
+        if let Some(parent_areas) = areas.get(&top_level.0).cloned() {
+            areas.insert(child.0, parent_areas);
+        }
+        Ok(Self(child))
+    }
+}
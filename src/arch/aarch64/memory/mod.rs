@@ -0,0 +1,429 @@
+use crate::memory::boot_memory_map::{BootMemoryMap, BootMemoryMapError, RegionKind};
+use crate::sync::{mutex, Mutex};
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+
+/// Copying to and from user memory
+mod user;
+pub(super) use user::exception_fixup;
+
+mod page_size;
+pub use page_size::PageSize;
+
+/// Translation table descriptor and it's flags
+mod page_table_entry;
+use page_table_entry::PTEntry;
+
+/// Address space implementation
+mod address_space;
+pub use address_space::AddressSpace;
+
+/// Use standard zone-based page allocator
+pub type PageAllocator = crate::memory::page_allocator::ZonedBuddy<0x1000>;
+
+extern "C" {
+    #[link_name = "kernel_top_level_page_table"]
+    static mut KERNEL_TOP_LEVEL_PAGE_TABLE: PageTable;
+}
+
+linker_symbol! {
+    kernel_start(KERNEL_START) => "kernel_start";
+    rodata_start(RODATA_START) => "rodata_start";
+    data_start(DATA_START) => "data_start";
+    kernel_end(KERNEL_END) => "kernel_end";
+    kernel_reserved_end(KERNEL_RESERVED_END) => "kernel_reserved_end";
+}
+
+/// Offset of the kernel image from it's physical address, same as in the linker script
+const KERNEL_OFFSET: usize = 0xffff_ffff_0000_0000;
+
+/// Convert a virtual address in the kernel address space to physical by subtracting the offset
+fn kernel_virt2phys(vaddr: VirtAddr) -> PhysAddr {
+    PhysAddr::from_usize(vaddr.as_usize() - KERNEL_OFFSET)
+}
+
+/// Number of bits each table takes off the vitual address
+const PAGE_LEVEL_BITS: usize = 9;
+
+/// Number of bits of the virtual address, that an entry of the top level page table covers
+const TOP_LEVEL_BITS: usize = 39;
+
+/// Number of page table entries in a page table
+const PAGE_TABLE_ENTRIES: usize = 1 << PAGE_LEVEL_BITS;
+
+/// End of the user half of the address space, translated through TTBR0_EL1
+const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// Start of the kernel half of the address space, translated through TTBR1_EL1.
+/// Both halves use the same top level table, so the kernel half starts in the middle of it
+const KERNEL_HALF_START: usize = 0xffff_8000_0000_0000;
+
+/// All physical memory is mapped at the start of the kernel half. There are no
+/// recursive mappings on AArch64, so translation tables are accessed through it
+const DIRECT_MAP_START: usize = KERNEL_HALF_START;
+/// Physical memory beyond this is not used
+const DIRECT_MAP_SIZE: usize = 0x20_0000_0000;
+/// Physical memory, that the bootstrap maps into the direct mapping
+const BOOTSTRAP_DIRECT_MAP_SIZE: usize = 0x1_0000_0000;
+
+/// Virtual memory range reserved for the kernel heap. Free kernel
+/// memory between the kernel and the heap is used by vmalloc.
+/// Everything is in the same gigabyte as the kernel image
+pub(super) const KERNEL_HEAP_START: usize = 0xffff_ffff_7000_0000;
+pub(super) const KERNEL_HEAP_SIZE: usize = 0x10000000;
+
+/// Page table type
+type PageTable = [PTEntry; PAGE_TABLE_ENTRIES];
+
+/// Identity mapping, that the bootstrap needs to enable the MMU. It takes the whole
+/// first entry of the top level table
+const IDENTITY_MAP_START: usize = 0;
+
+static PAGE_ALLOCATOR: PageAllocator = PageAllocator::new();
+
+/// Physical memory map, built from the device tree
+static BOOT_MEMORY_MAP: Mutex<BootMemoryMap> = mutex(BootMemoryMap::new());
+
+/// Convert a physical address to virtual in the direct mapping. The first gigabyte
+/// holds devices on QEMU virt and is mapped as device memory
+pub(super) fn phys2virt(paddr: PhysAddr) -> VirtAddr {
+    debug_assert!(paddr.as_usize() < DIRECT_MAP_SIZE);
+    VirtAddr::from_usize(DIRECT_MAP_START + paddr.as_usize())
+}
+
+/// Convert a virtual address in the direct mapping to physical
+fn virt2phys(vaddr: VirtAddr) -> PhysAddr {
+    debug_assert!(vaddr.as_usize() >= DIRECT_MAP_START);
+    PhysAddr::from_usize(vaddr.as_usize() - DIRECT_MAP_START)
+}
+
+/// Invalidate TLB entries of a page on all CPUs, or the whole TLB
+fn flush_tlb(vaddr: Option<VirtAddr>) {
+    /// Virtual page number bits of the TLBI operand
+    const PAGE_NUMBER_MASK: usize = (1 << 44) - 1;
+
+    unsafe {
+        match vaddr {
+            Some(vaddr) => core::arch::asm!(
+                "dsb ishst",
+                "tlbi vaae1is, {}",
+                "dsb ish",
+                "isb",
+                in(reg) (vaddr.as_usize() >> 12) & PAGE_NUMBER_MASK,
+                options(nostack),
+            ),
+            None => core::arch::asm!(
+                "dsb ishst",
+                "tlbi vmalle1is",
+                "dsb ish",
+                "isb",
+                options(nostack),
+            ),
+        }
+    }
+}
+
+pub struct Memory;
+impl crate::arch::MemoryTrait for Memory {
+    type PageSize = PageSize;
+    type PageAllocator = PageAllocator;
+    type AddressSpace = AddressSpace;
+
+    fn page_allocator() -> &'static Self::PageAllocator {
+        &PAGE_ALLOCATOR
+    }
+
+    fn kernel_address_space() -> Self::AddressSpace {
+        let kernel_address_space =
+            VirtAddr::from_usize(&raw const KERNEL_TOP_LEVEL_PAGE_TABLE as _);
+        AddressSpace::from_paddr(kernel_virt2phys(kernel_address_space))
+    }
+
+    fn vmalloc_range() -> (VirtAddr, VirtAddr) {
+        (
+            kernel_reserved_end().align_up_4k(),
+            VirtAddr::from_usize(KERNEL_HEAP_START),
+        )
+    }
+
+    fn alloc_kernel_page() -> Option<VirtAddr> {
+        PAGE_ALLOCATOR.alloc(PageSize::Size4K.into()).map(phys2virt)
+    }
+
+    fn free_kernel_page(page: VirtAddr) {
+        PAGE_ALLOCATOR.free(virt2phys(page), PageSize::Size4K.into());
+    }
+
+    fn heap_stats() -> crate::memory::HeapStats {
+        super::allocator::stats()
+    }
+
+    fn memory_stats() -> crate::memory::MemoryStats {
+        let page_tables = crate::memory::frame::frames().map_or(0, |frames| {
+            frames.count(crate::memory::FrameFlags::PAGE_TABLE) * usize::from(PageSize::Size4K)
+        });
+        let map = BOOT_MEMORY_MAP.lock();
+        crate::memory::MemoryStats {
+            zones: PAGE_ALLOCATOR.zone_stats(),
+            page_tables,
+            heap: Self::heap_stats(),
+            slab: crate::memory::slab::stats(),
+            reserved: RegionKind::ALL
+                .into_iter()
+                .filter(|&kind| kind != RegionKind::Free)
+                .map(|kind| (kind, map.total(kind)))
+                .filter(|&(_, size)| size > 0)
+                .collect(),
+        }
+    }
+
+    fn boot_memory_map() -> BootMemoryMap {
+        BOOT_MEMORY_MAP.lock().clone()
+    }
+
+    fn release_acpi_memory() {
+        // Device tree is used instead of ACPI
+    }
+
+    fn user_range() -> (VirtAddr, VirtAddr) {
+        (VirtAddr::from_usize(0), VirtAddr::from_usize(USER_END))
+    }
+
+    unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+        unsafe { user::copy_user(dst, src, len) }
+    }
+}
+
+/// Setup paging. Device tree is at `device_tree_addr` in physical memory
+pub(super) fn setup_paging(device_tree: &fdt::Fdt, device_tree_addr: PhysAddr) {
+    let mut map = BOOT_MEMORY_MAP.lock();
+    *map = boot_memory_map(device_tree, device_tree_addr).expect("Failed to build boot memory map");
+    map_physical_memory(map.memory_end());
+    setup_frame_database(&mut map).expect("Failed to set up the frame database");
+    crate::println!("Boot memory map:\n{}", *map);
+
+    // Add zones to the page allocator
+    for region in map.regions_of(RegionKind::Free) {
+        add_zone(region.start, region.end);
+    }
+
+    protect_kernel().expect("Failed to protect kernel sections");
+    unmap_identity().expect("Failed to unmap the identity mapping");
+}
+
+/// Extend the direct mapping, that the bootstrap has set up, to all physical memory.
+/// 1 GiB blocks are mapped in the static level 1 table, no tables have to be allocated
+fn map_physical_memory(end: PhysAddr) {
+    use crate::memory::address_space::nested_page_table::{
+        NestedPageTable as _, NestedPageTableLevel as _,
+    };
+    use crate::memory::MappingFlags;
+
+    let top_level = <Memory as crate::arch::MemoryTrait>::kernel_address_space().top_level();
+    let block_size = usize::from(PageSize::Size1G);
+    let end = end.as_usize().align_up(block_size);
+    for paddr in (BOOTSTRAP_DIRECT_MAP_SIZE..end).step_by(block_size) {
+        top_level
+            .map_page(
+                phys2virt(PhysAddr::from_usize(paddr)),
+                PhysAddr::from_usize(paddr),
+                PageSize::Size1G,
+                MappingFlags::PRESENT
+                    | MappingFlags::READ
+                    | MappingFlags::WRITE
+                    | MappingFlags::GLOBAL,
+                &PAGE_ALLOCATOR,
+            )
+            .expect("Failed to map physical memory");
+    }
+}
+
+/// Change permissions of the kernel image: only code is executable
+/// and only data is writable. The bootstrap maps it with 4 KiB pages
+fn protect_kernel() -> crate::memory::MappingResult<()> {
+    use crate::memory::address_space::nested_page_table::NestedPageTable as _;
+    use crate::memory::MappingFlags;
+
+    let address_space = <Memory as crate::arch::MemoryTrait>::kernel_address_space();
+    let sections = [
+        (
+            kernel_start(),
+            rodata_start(),
+            MappingFlags::READ | MappingFlags::EXECUTE,
+        ),
+        (rodata_start(), data_start(), MappingFlags::READ),
+        (
+            data_start(),
+            kernel_end().align_up_4k(),
+            MappingFlags::READ | MappingFlags::WRITE,
+        ),
+    ];
+    for (start, end, flags) in sections {
+        address_space.protect(start, end - start, flags | MappingFlags::GLOBAL)?;
+    }
+    Ok(())
+}
+
+/// Unmap the identity mapping, that the bootstrap used to enable the MMU.
+/// It's translation tables are static and aren't freed
+fn unmap_identity() -> crate::memory::MappingResult<()> {
+    use crate::memory::address_space::nested_page_table::{
+        NestedPageTable as _, NestedPageTableLevel as _, PageTableEntry,
+    };
+    use crate::memory::MappingFlags;
+
+    let top_level = <Memory as crate::arch::MemoryTrait>::kernel_address_space().top_level();
+    let unmapped = PageTableEntry::Page(PhysAddr::from_usize(0), MappingFlags::empty());
+    top_level.set_entry(VirtAddr::from_usize(IDENTITY_MAP_START), unmapped)
+}
+
+/// Build a sanitized memory map out of device tree memory nodes
+/// and memory used by the firmware and the kernel
+fn boot_memory_map(
+    device_tree: &fdt::Fdt,
+    device_tree_addr: PhysAddr,
+) -> Result<BootMemoryMap, BootMemoryMapError> {
+    let mut map = BootMemoryMap::new();
+    let region = |start: *const u8, size: usize| {
+        let start = start as usize;
+        (
+            PhysAddr::from_usize(start),
+            PhysAddr::from_usize(start.saturating_add(size)),
+        )
+    };
+
+    for memory in device_tree.memory().regions() {
+        let (start, end) = region(memory.starting_address, memory.size.unwrap_or(0));
+        // Memory outside of the direct mapping is not used
+        if start.as_usize() >= DIRECT_MAP_SIZE {
+            continue;
+        }
+        let end = end.min(PhysAddr::from_usize(DIRECT_MAP_SIZE));
+        map.add(start, end, RegionKind::Free)?;
+    }
+
+    // Firmware memory, if there is any, is reserved either in the
+    // memory reservation block or by the reserved-memory node
+    for reservation in device_tree.memory_reservations() {
+        let (start, end) = region(reservation.address(), reservation.size());
+        map.add(start, end, RegionKind::Reserved)?;
+    }
+    if let Some(reserved) = device_tree.find_node("/reserved-memory") {
+        for node in reserved.children() {
+            for reservation in node.reg().into_iter().flatten() {
+                let (start, end) =
+                    region(reservation.starting_address, reservation.size.unwrap_or(0));
+                map.add(start, end, RegionKind::Reserved)?;
+            }
+        }
+    }
+
+    map.add(
+        kernel_virt2phys(kernel_start()),
+        kernel_virt2phys(kernel_end()),
+        RegionKind::Kernel,
+    )?;
+    map.add(
+        device_tree_addr,
+        device_tree_addr + device_tree.total_size(),
+        RegionKind::BootInfo,
+    )?;
+    if let Some(chosen) = device_tree.find_node("/chosen") {
+        let initrd_start = chosen
+            .property("linux,initrd-start")
+            .and_then(|p| p.as_usize());
+        let initrd_end = chosen
+            .property("linux,initrd-end")
+            .and_then(|p| p.as_usize());
+        if let (Some(start), Some(end)) = (initrd_start, initrd_end) {
+            map.add(
+                PhysAddr::from_usize(start),
+                PhysAddr::from_usize(end),
+                RegionKind::Module,
+            )?;
+        }
+    }
+
+    map.sanitize()?;
+    Ok(map)
+}
+
+/// Allocate the page frame database from free memory in the map. It is
+/// accessed through the direct mapping, so it doesn't have to be mapped
+fn setup_frame_database(map: &mut BootMemoryMap) -> Result<(), BootMemoryMapError> {
+    use crate::memory::FrameDatabase;
+
+    let size = FrameDatabase::size(map.memory_start(), map.memory_end()).align_up_4k();
+    let start = map
+        .regions_of(RegionKind::Free)
+        .map(|region| (region.start.align_up_4k(), region.end))
+        .find(|&(start, end)| end > start && end - start >= size)
+        .map(|(start, _)| start)
+        .expect("Not enough memory for the frame database");
+    map.add(start, start + size, RegionKind::FrameDatabase)?;
+    map.sanitize()?;
+
+    let frames = unsafe { crate::memory::frame::init(phys2virt(start), map) };
+    crate::println!(
+        "Frame database: {} frames at {:#x}",
+        frames.frames().len(),
+        start
+    );
+    Ok(())
+}
+
+/// Add a free physical memory range to the page allocator
+fn add_zone(start: PhysAddr, end: PhysAddr) {
+    let start = start.align_up_4k();
+    let end = end.align_down_4k();
+    if end <= start {
+        return;
+    }
+
+    if PAGE_ALLOCATOR
+        .add_zone(start.as_usize(), end - start)
+        .is_err()
+    {
+        crate::println!("Failed to add memory zone {:#x} to {:#x}", start, end);
+    }
+}
+
+/// Try to resolve a page fault: copy a copy-on-write
+/// page or map a page of a memory area
+pub(super) fn handle_page_fault(
+    vaddr: VirtAddr,
+    access: crate::memory::MappingFlags,
+) -> Result<(), crate::memory::FaultError> {
+    use crate::memory::address_space::nested_page_table::NestedPageTable as _;
+    use crate::memory::{FaultError, MappingFlags};
+
+    let address_space = AddressSpace::current();
+    if !address_space
+        .page_flags(vaddr)?
+        .contains(MappingFlags::PRESENT)
+    {
+        address_space.handle_fault(vaddr, access, &PAGE_ALLOCATOR)
+    } else if access.contains(MappingFlags::WRITE)
+        && address_space.resolve_cow(vaddr, &PAGE_ALLOCATOR)?
+    {
+        Ok(())
+    } else {
+        Err(FaultError::AccessViolation(vaddr, access))
+    }
+}
+
+macro_rules! linker_symbol {
+    ($($name: ident ($symbol_name: ident) => $link_name: literal;)*) => {
+        $(
+            extern "C" {
+                #[link_name = $link_name]
+                static $symbol_name: u8;
+            }
+
+            fn $name() -> VirtAddr {
+                VirtAddr::from_usize(unsafe { &$symbol_name } as *const _ as _)
+            }
+        )*
+    };
+}
+
+use linker_symbol;
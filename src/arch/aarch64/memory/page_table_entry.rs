@@ -0,0 +1,153 @@
+use crate::memory::MappingFlags;
+use memory_addr::PhysAddr;
+
+/// Bits of a descriptor, that hold the output address
+const ADDRESS_MASK: usize = 0x0000_ffff_ffff_f000;
+
+bitflags::bitflags! {
+    /// Stage 1 translation table descriptor flags, 4 KiB granule
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[repr(transparent)]
+    pub(super) struct PTEFlags: usize {
+        /// Valid
+        const VALID     = 1 << 0;
+        /// Next level table, or a page at the last level. Block otherwise
+        const TABLE     = 1 << 1;
+        /// Device memory, attribute 1 of MAIR_EL1. Normal memory (attribute 0) otherwise
+        const DEVICE    = 1 << 2;
+        /// Accessible from EL0, AP[1]
+        const USER      = 1 << 6;
+        /// Read-only, AP[2]
+        const READ_ONLY = 1 << 7;
+        /// Inner shareable
+        const SHAREABLE = 3 << 8;
+        /// Access flag, there is a fault on access without it
+        const ACCESSED  = 1 << 10;
+        /// Not global, tagged with the ASID
+        const NOT_GLOBAL = 1 << 11;
+        /// Privileged execute-never
+        const PXN       = 1 << 53;
+        /// Unprivileged execute-never
+        const UXN       = 1 << 54;
+        /// Copy-on-write (reserved for software); the page is shared read-only
+        const COW       = 1 << 55;
+    }
+}
+
+impl From<MappingFlags> for PTEFlags {
+    fn from(value: MappingFlags) -> Self {
+        // Permissions are encoded by the absence of bits, so
+        // an entry that is not present is left empty
+        if !value.contains(MappingFlags::PRESENT) {
+            return Self::empty();
+        }
+        // Access flag is not managed by the hardware everywhere, so it's set upfront
+        let mut flags = Self::VALID | Self::ACCESSED;
+        if !value.contains(MappingFlags::WRITE) {
+            flags |= Self::READ_ONLY;
+        }
+        if value.contains(MappingFlags::UNCACHED) {
+            flags |= Self::DEVICE;
+        } else {
+            flags |= Self::SHAREABLE;
+        }
+        // Kernel never executes user memory, like with SMEP on x86
+        if value.contains(MappingFlags::USER) {
+            flags |= Self::USER | Self::PXN;
+            if !value.contains(MappingFlags::EXECUTE) {
+                flags |= Self::UXN;
+            }
+        } else {
+            flags |= Self::UXN;
+            if !value.contains(MappingFlags::EXECUTE) {
+                flags |= Self::PXN;
+            }
+        }
+        if !value.contains(MappingFlags::GLOBAL) {
+            flags |= Self::NOT_GLOBAL;
+        }
+        if value.contains(MappingFlags::COPY_ON_WRITE) {
+            flags |= Self::COW;
+        }
+        flags
+    }
+}
+
+impl From<PTEFlags> for MappingFlags {
+    fn from(value: PTEFlags) -> Self {
+        if !value.contains(PTEFlags::VALID) {
+            return Self::empty();
+        }
+        // Valid entries are always readable
+        let mut flags = Self::PRESENT | Self::READ;
+        if !value.contains(PTEFlags::READ_ONLY) {
+            flags |= Self::WRITE;
+        }
+        let execute_never = if value.contains(PTEFlags::USER) {
+            flags |= Self::USER;
+            PTEFlags::UXN
+        } else {
+            PTEFlags::PXN
+        };
+        if !value.contains(execute_never) {
+            flags |= Self::EXECUTE;
+        }
+        if value.contains(PTEFlags::DEVICE) {
+            flags |= Self::UNCACHED;
+        }
+        if !value.contains(PTEFlags::NOT_GLOBAL) {
+            flags |= Self::GLOBAL;
+        }
+        if value.contains(PTEFlags::COW) {
+            flags |= Self::COPY_ON_WRITE;
+        }
+        flags
+    }
+}
+
+/// Translation table descriptor
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub(super) struct PTEntry(usize);
+
+impl core::fmt::Debug for PTEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PTEntry({:#x})", self.0)
+    }
+}
+
+impl PTEntry {
+    pub(super) const NULL: Self = Self(0);
+
+    /// Create a new descriptor of a page at the last level or a block above it.
+    /// Page size is defined by the level of the table
+    pub(super) fn new_page(addr: PhysAddr, flags: PTEFlags, last_level: bool) -> Self {
+        let mut flags = flags;
+        if last_level && flags.contains(PTEFlags::VALID) {
+            flags |= PTEFlags::TABLE;
+        }
+        Self((addr.as_usize() & ADDRESS_MASK) | flags.bits())
+    }
+
+    /// Create a new descriptor of a next level table. Access rights are
+    /// controlled by the descriptors of the table itself
+    pub(super) fn new_page_table(addr: PhysAddr) -> Self {
+        Self((addr.as_usize() & ADDRESS_MASK) | (PTEFlags::VALID | PTEFlags::TABLE).bits())
+    }
+
+    /// Get flags of this descriptor
+    pub(super) fn flags(&self) -> PTEFlags {
+        PTEFlags::from_bits_truncate(self.0)
+    }
+
+    /// Whether this descriptor points to the next level table.
+    /// Only meaningful above the last level
+    pub(super) fn is_page_table(&self) -> bool {
+        self.flags().contains(PTEFlags::VALID | PTEFlags::TABLE)
+    }
+
+    /// Get the address this descriptor holds
+    pub(super) fn address(&self) -> PhysAddr {
+        PhysAddr::from_usize(self.0 & ADDRESS_MASK)
+    }
+}
@@ -0,0 +1,59 @@
+/// Entry of the exception fixup table. If an instruction at `instruction`
+/// faults, execution continues at `fixup` instead of panicking
+#[repr(C)]
+struct ExTableEntry {
+    instruction: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    #[link_name = "ex_table_start"]
+    static EX_TABLE_START: ExTableEntry;
+    #[link_name = "ex_table_end"]
+    static EX_TABLE_END: ExTableEntry;
+}
+
+/// Find where to continue after a fault at ip, if it's allowed to fault
+pub(in crate::arch::aarch64) fn exception_fixup(ip: usize) -> Option<usize> {
+    let table = unsafe {
+        let start = &raw const EX_TABLE_START;
+        let end = &raw const EX_TABLE_END;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table
+        .iter()
+        .find(|entry| entry.instruction == ip)
+        .map(|entry| entry.fixup)
+}
+
+/// Copy len bytes from src to dst, allowing access to user memory.
+/// Returns the number of bytes left, if the copy faulted
+///
+/// # Safety
+/// Kernel side of the copy must be valid for len bytes
+pub(super) unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let left: usize;
+    unsafe {
+        // Both the load and the store can fault, the byte isn't counted then.
+        // Addresses are only incremented, if the access succeeds
+        core::arch::asm!(
+            "0: cbz {len}, 3f",
+            "1: ldrb {byte:w}, [{src}], #1",
+            "2: strb {byte:w}, [{dst}], #1",
+            "sub {len}, {len}, #1",
+            "b 0b",
+            "3:",
+            ".pushsection .ex_table, \"a\"",
+            ".balign 8",
+            ".quad 1b, 3b",
+            ".quad 2b, 3b",
+            ".popsection",
+            byte = out(reg) _,
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            len = inout(reg) len => left,
+            options(nostack),
+        );
+    }
+    left
+}
@@ -0,0 +1,64 @@
+core::arch::global_asm!(include_str!("boot.S"));
+
+/// Early logging facilities
+mod early_logger;
+
+/// CPU Interface
+mod cpu;
+
+/// Exception vectors
+mod interrupts;
+
+/// Generic Interrupt Controller
+mod gic;
+
+/// Generic timer
+mod timer;
+
+/// Global allocator, kernel heap
+mod allocator;
+
+/// Kernel thread context switching
+mod context;
+
+/// Paging implementation
+mod memory;
+
+#[cfg(feature = "kernel-tests")]
+mod tests;
+
+/// Arch implementation
+pub struct Arch;
+impl crate::arch::ArchTrait for Arch {
+    type EarlyLogger = early_logger::EarlyLogger;
+    type Cpu = cpu::Cpu;
+    type Memory = memory::Memory;
+    type Context = context::Context;
+    type Timer = timer::Timer;
+}
+
+/// Kernel setup function. First thing that is called after assembly
+/// bootstrap enables the MMU, with the physical address of the device tree
+#[no_mangle]
+pub extern "C" fn ksetup(device_tree_addr: usize) -> ! {
+    crate::println!("Hello, SATAN!");
+    interrupts::setup();
+
+    let device_tree_addr = memory_addr::PhysAddr::from_usize(device_tree_addr);
+    let device_tree_ptr = memory::phys2virt(device_tree_addr);
+    let device_tree = match unsafe { fdt::Fdt::from_ptr(device_tree_ptr.as_ptr()) } {
+        Ok(device_tree) => device_tree,
+        Err(err) => panic!("Failed to parse the device tree: {:?}", err),
+    };
+
+    memory::setup_paging(&device_tree, device_tree_addr);
+    gic::setup(&device_tree);
+    timer::setup();
+
+    #[cfg(feature = "kernel-tests")]
+    tests::run();
+
+    loop {
+        aarch64_cpu::asm::wfi();
+    }
+}
@@ -0,0 +1,43 @@
+use aarch64_cpu::registers::{Readable as _, Writeable as _, CNTFRQ_EL0, CNTP_CTL_EL0, CNTP_TVAL_EL0};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Timer interrupts per second
+const TICK_RATE: u64 = 100;
+
+/// Interrupt of the non-secure EL1 physical timer, PPI 14
+pub(super) const INTERRUPT_ID: u32 = 30;
+
+/// Ticks of the system counter between timer interrupts
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer interrupts since the timer was set up
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Program the next timer interrupt
+fn schedule() {
+    CNTP_TVAL_EL0.set(INTERVAL.load(Ordering::SeqCst));
+}
+
+/// Handle a timer interrupt. Setting the timer clears the pending interrupt
+pub(super) fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    schedule();
+    crate::thread::scheduler::tick();
+}
+
+pub struct Timer;
+
+impl crate::arch::TimerTrait for Timer {
+    fn ticks() -> u64 {
+        TICKS.load(Ordering::SeqCst)
+    }
+}
+
+/// Start periodic timer interrupts of the generic timer
+pub(super) fn setup() {
+    INTERVAL.store(CNTFRQ_EL0.get() / TICK_RATE, Ordering::SeqCst);
+    schedule();
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+    super::gic::enable_interrupt(INTERRUPT_ID);
+    crate::println!("Timer is setup, {} Hz", TICK_RATE);
+}
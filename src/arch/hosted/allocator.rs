@@ -0,0 +1,20 @@
+use crate::memory::heap::{Heap, HeapStats};
+
+/// Static arena used before paging is set up. Should
+/// be enough to add first zones to the page allocator
+const ARENA_SIZE: usize = 0x4000;
+static mut ARENA: [u8; ARENA_SIZE] = [0; ARENA_SIZE];
+
+#[global_allocator]
+static ALLOCATOR: Heap = unsafe {
+    Heap::new(
+        talc::Span::from_slice(core::ptr::addr_of_mut!(ARENA)),
+        super::memory::KERNEL_HEAP_START,
+        super::memory::KERNEL_HEAP_SIZE,
+    )
+};
+
+/// Get kernel heap usage statistics
+pub(super) fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
@@ -0,0 +1,151 @@
+/// Saved state of a thread, that is not running. Callee-saved
+/// registers are stored on it's stack, only the stack pointer is kept
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Context {
+    sp: usize,
+}
+
+/// Stack frame, that [`switch_context`] leaves on the stack of a thread it
+/// switched away from: callee-saved registers and the return address.
+/// New threads start in [`thread_entry`] with entry and argument in registers
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+struct Frame {
+    r15: usize,
+    r14: usize,
+    /// Argument of a new thread
+    r13: usize,
+    /// Entry point of a new thread
+    r12: usize,
+    rbx: usize,
+    rbp: usize,
+    ret: usize,
+}
+
+/// Host code uses floating point, so the lower halves of v8-v15 are saved too
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+struct Frame {
+    /// Entry point and argument of a new thread are in x19 and x20
+    x: [usize; 10],
+    fp: usize,
+    lr: usize,
+    d: [u64; 8],
+}
+
+/// Space left between the frame of a new thread and the top of it's stack, so
+/// that the stack is 16 byte aligned, when [`thread_entry`] calls the entry
+#[cfg(target_arch = "x86_64")]
+const FRAME_OFFSET: usize = 16;
+#[cfg(target_arch = "aarch64")]
+const FRAME_OFFSET: usize = 0;
+
+impl crate::arch::ContextTrait for Context {
+    fn new(stack_top: memory_addr::VirtAddr, entry: extern "C" fn(usize) -> !, arg: usize) -> Self {
+        let sp = stack_top.as_usize() - FRAME_OFFSET - core::mem::size_of::<Frame>();
+        #[cfg(target_arch = "x86_64")]
+        let frame = Frame {
+            r15: 0,
+            r14: 0,
+            r13: arg,
+            r12: entry as usize,
+            rbx: 0,
+            rbp: 0,
+            ret: thread_entry as *const () as usize,
+        };
+        #[cfg(target_arch = "aarch64")]
+        let frame = {
+            let mut x = [0; 10];
+            x[0] = entry as usize;
+            x[1] = arg;
+            Frame {
+                x,
+                fp: 0,
+                lr: thread_entry as *const () as usize,
+                d: [0; 8],
+            }
+        };
+        unsafe {
+            (sp as *mut Frame).write(frame);
+        }
+        Self { sp }
+    }
+
+    unsafe fn switch_to(from: *mut Self, to: *const Self) {
+        unsafe { switch_context(from, to) }
+    }
+}
+
+/// Push callee-saved registers, save the stack pointer into `from`,
+/// then load the one from `to` and pop it's registers
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(from: *mut Context, to: *const Context) {
+    core::arch::naked_asm!(
+        "push %rbp",
+        "push %rbx",
+        "push %r12",
+        "push %r13",
+        "push %r14",
+        "push %r15",
+        "mov %rsp, (%rdi)",
+        "mov (%rsi), %rsp",
+        "pop %r15",
+        "pop %r14",
+        "pop %r13",
+        "pop %r12",
+        "pop %rbx",
+        "pop %rbp",
+        "ret",
+        options(att_syntax),
+    );
+}
+
+#[cfg(target_arch = "aarch64")]
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(from: *mut Context, to: *const Context) {
+    core::arch::naked_asm!(
+        "sub sp, sp, #{frame_size}",
+        "stp x19, x20, [sp, #0]",
+        "stp x21, x22, [sp, #16]",
+        "stp x23, x24, [sp, #32]",
+        "stp x25, x26, [sp, #48]",
+        "stp x27, x28, [sp, #64]",
+        "stp x29, x30, [sp, #80]",
+        "stp d8, d9, [sp, #96]",
+        "stp d10, d11, [sp, #112]",
+        "stp d12, d13, [sp, #128]",
+        "stp d14, d15, [sp, #144]",
+        "mov x9, sp",
+        "str x9, [x0]",
+        "ldr x9, [x1]",
+        "mov sp, x9",
+        "ldp x19, x20, [sp, #0]",
+        "ldp x21, x22, [sp, #16]",
+        "ldp x23, x24, [sp, #32]",
+        "ldp x25, x26, [sp, #48]",
+        "ldp x27, x28, [sp, #64]",
+        "ldp x29, x30, [sp, #80]",
+        "ldp d8, d9, [sp, #96]",
+        "ldp d10, d11, [sp, #112]",
+        "ldp d12, d13, [sp, #128]",
+        "ldp d14, d15, [sp, #144]",
+        "add sp, sp, #{frame_size}",
+        "ret",
+        frame_size = const core::mem::size_of::<Frame>(),
+    );
+}
+
+/// First switch to a new thread returns here. Entry never returns
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+unsafe extern "C" fn thread_entry() -> ! {
+    core::arch::naked_asm!("mov %r13, %rdi", "call *%r12", "ud2", options(att_syntax));
+}
+
+#[cfg(target_arch = "aarch64")]
+#[unsafe(naked)]
+unsafe extern "C" fn thread_entry() -> ! {
+    core::arch::naked_asm!("mov x0, x20", "blr x19", "brk #1");
+}
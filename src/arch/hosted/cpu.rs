@@ -0,0 +1,41 @@
+pub struct Cpu;
+
+/// Change the mask of the timer signal, which is the only
+/// interrupt. Returns true if it was not masked before
+fn mask_timer(how: libc::c_int) -> bool {
+    unsafe {
+        let mut set: libc::sigset_t = core::mem::zeroed();
+        let mut old: libc::sigset_t = core::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGALRM);
+        libc::sigprocmask(how, &set, &mut old);
+        libc::sigismember(&old, libc::SIGALRM) == 0
+    }
+}
+
+impl crate::arch::CpuTrait for Cpu {
+    const MAX_CPUS: usize = 1;
+
+    fn cpu_id() -> usize {
+        // The whole kernel is a single host thread
+        0
+    }
+
+    fn disable_interrupts() -> bool {
+        mask_timer(libc::SIG_BLOCK)
+    }
+
+    fn enable_interrupts() {
+        mask_timer(libc::SIG_UNBLOCK);
+    }
+
+    fn wait_for_interrupt() {
+        // Waits with no signals masked, the mask is restored afterwards
+        unsafe {
+            let mut set: libc::sigset_t = core::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigsuspend(&set);
+        }
+        Self::enable_interrupts();
+    }
+}
@@ -0,0 +1,38 @@
+/// Writes to a file descriptor of the host process
+struct Writer(libc::c_int);
+
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let written = unsafe { libc::write(self.0, bytes.as_ptr().cast(), bytes.len()) };
+            if written <= 0 {
+                return Err(core::fmt::Error);
+            }
+            bytes = &bytes[written as usize..];
+        }
+        Ok(())
+    }
+}
+
+static WRITER: crate::sync::IrqSafeMutex<Writer> =
+    crate::sync::irq_safe_mutex(Writer(libc::STDOUT_FILENO));
+
+pub struct EarlyLogger;
+impl crate::arch::LoggerTrait for EarlyLogger {
+    fn _print(args: core::fmt::Arguments) {
+        use core::fmt::Write as _;
+        WRITER.lock().write_fmt(args).unwrap();
+    }
+
+    fn _panic(args: core::fmt::Arguments) -> ! {
+        use core::fmt::Write as _;
+        let mut writer = WRITER.lock();
+        // Red, like the VGA logger on x86
+        writer.write_str("\x1b[31m").unwrap();
+        writer.write_fmt(args).unwrap();
+        writer.write_str("\x1b[0m\n").unwrap();
+        // Panics might come from signal handlers, where exit isn't safe
+        unsafe { libc::_exit(1) }
+    }
+}
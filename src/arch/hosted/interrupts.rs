@@ -0,0 +1,58 @@
+use libc::{c_int, c_void, siginfo_t};
+
+/// Signature of a signal handler, that gets the signal info and the context
+pub(super) type SignalHandler = extern "C" fn(c_int, *mut siginfo_t, *mut c_void);
+
+/// Install a handler for a signal. Interrupted system calls are restarted.
+/// Like on hardware, the timer interrupt is masked while any handler runs
+pub(super) fn set_handler(signal: c_int, handler: SignalHandler) {
+    unsafe {
+        let mut action: libc::sigaction = core::mem::zeroed();
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaddset(&mut action.sa_mask, libc::SIGALRM);
+        if libc::sigaction(signal, &action, core::ptr::null_mut()) != 0 {
+            panic!("Failed to set a handler for signal {}", signal);
+        }
+    }
+}
+
+/// Accessing emulated memory, that is not mapped in the host, raises SIGSEGV
+extern "C" fn page_fault(_signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    use crate::memory::MappingFlags;
+    use memory_addr::VirtAddr;
+
+    let address = VirtAddr::from_usize(unsafe { (*info).si_addr() } as usize);
+    // The signal doesn't tell the kind of access. A page, that is not
+    // present, is mapped for reading, a fault on a present page is a write
+    let access = match super::memory::AddressSpace::current().page_flags(address) {
+        Ok(flags) if flags.contains(MappingFlags::PRESENT) => MappingFlags::WRITE,
+        _ => MappingFlags::READ,
+    };
+    if let Err(err) = super::memory::handle_page_fault(address, access) {
+        crate::println!("{}", err);
+        panic!("Page fault at {:#x}", instruction_pointer(context));
+    }
+}
+
+/// Instruction pointer of the interrupted code, saved in the signal context
+fn instruction_pointer(context: *mut c_void) -> usize {
+    let context = unsafe { &*context.cast::<libc::ucontext_t>() };
+    #[cfg(target_arch = "x86_64")]
+    return context.uc_mcontext.gregs[libc::REG_RIP as usize] as usize;
+    #[cfg(target_arch = "aarch64")]
+    return context.uc_mcontext.pc as usize;
+}
+
+/// Breakpoints raise SIGTRAP
+extern "C" fn breakpoint(_signal: c_int, info: *mut siginfo_t, _context: *mut c_void) {
+    crate::println!("Test breakpoint, code {}", unsafe { (*info).si_code });
+}
+
+/// Install handlers for signals, that stand in for exceptions
+pub(super) fn setup() {
+    set_handler(libc::SIGSEGV, page_fault);
+    set_handler(libc::SIGTRAP, breakpoint);
+    crate::println!("Signal handlers are setup");
+}
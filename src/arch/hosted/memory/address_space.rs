@@ -0,0 +1,336 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+
+use super::PageSize;
+/// Physical page table entry types
+mod entry {
+    pub(super) use super::super::PTEntry;
+}
+
+use crate::memory::address_space::nested_page_table::{NestedPageTable, NestedPageTableLevel};
+use crate::memory::address_space::AddressSpaceTrait;
+use crate::memory::{FaultError, FrameFlags, Vma, VmaError};
+use crate::memory::{MappingError, MappingResult, PageAllocatorTrait};
+
+/// Interface page table entry types
+mod if_entry {
+    pub(super) use crate::memory::address_space::nested_page_table::PageTableEntry;
+    pub(super) use crate::memory::MappingFlags;
+}
+
+/// Memory areas of every address space, by address of the top level page table
+static AREAS: crate::sync::IrqSafeRwLock<
+    alloc::collections::BTreeMap<PhysAddr, crate::memory::address_space::vma::VmaSet>,
+> = crate::sync::irq_safe_rw_lock(alloc::collections::BTreeMap::new());
+
+/// Top level page table of the address space, that is mirrored in the host
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Address space struct
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AddressSpace(PageTableLevel);
+
+/// Page table level: it's physical address, bits of the virtual address
+/// it covers and the top level page table of it's address space
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PageTableLevel(PhysAddr, usize, PhysAddr);
+
+impl AddressSpace {
+    pub(super) fn from_paddr(addr: PhysAddr) -> Self {
+        Self(PageTableLevel(addr, super::TOP_LEVEL_BITS, addr))
+    }
+
+    /// Allocate an empty address space
+    pub(super) fn new(alloc: &impl PageAllocatorTrait<PageSize>) -> Option<Self> {
+        let addr = PageTableLevel::alloc_table(alloc)?;
+        Some(Self::from_paddr(addr))
+    }
+
+    /// Build the kernel address space in page tables at `tables`, that were reserved
+    /// at boot. The memory file starts zeroed, so the page tables are empty. Kernel
+    /// half is shared between all address spaces, so it's top level entries are set upfront
+    pub(super) fn new_kernel(tables: PhysAddr) -> MappingResult<Self> {
+        let address_space = Self::from_paddr(tables);
+        let top_level = address_space.top_level();
+        let step = top_level.region_size();
+        for (index, vaddr) in (super::KERNEL_HALF_START..super::KERNEL_HALF_END)
+            .step_by(step)
+            .enumerate()
+        {
+            let table = tables + (index + 1) * usize::from(PageSize::Size4K);
+            let sublevel = PageTableLevel(table, top_level.1 - super::PAGE_LEVEL_BITS, tables);
+            top_level.set_entry(
+                VirtAddr::from_usize(vaddr),
+                if_entry::PageTableEntry::Level(sublevel),
+            )?;
+        }
+        Ok(address_space)
+    }
+
+    /// Get the address space that is currently active on this CPU
+    pub fn current() -> Self {
+        Self::from_paddr(PhysAddr::from_usize(CURRENT.load(Ordering::SeqCst)))
+    }
+
+    /// Switch to this address space. The host mappings of the user half are
+    /// replaced with pages of this address space, kernel half is shared
+    pub fn activate(&self) {
+        CURRENT.store(self.0 .0.as_usize(), Ordering::SeqCst);
+        let user_size = super::USER_END - super::USER_START;
+        let user_start = VirtAddr::from_usize(super::USER_START);
+        super::host_map(
+            user_start,
+            user_size,
+            PhysAddr::from_usize(0),
+            if_entry::MappingFlags::empty(),
+        );
+        self.0.mirror(user_start, user_size);
+    }
+
+    /// Handle a fault on a page that is not present by
+    /// mapping it, if it belongs to a memory area
+    pub fn handle_fault(
+        &self,
+        vaddr: VirtAddr,
+        access: if_entry::MappingFlags,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> Result<(), FaultError> {
+        // Populating allocates memory and takes the page table lock, so
+        // it's done on a copy of the area, without holding the areas lock
+        let area = AREAS
+            .read()
+            .get(&self.0 .0)
+            .and_then(|areas| areas.find(vaddr))
+            .cloned()
+            .ok_or(FaultError::NoArea(vaddr))?;
+        if !area.allows(access) {
+            return Err(FaultError::AccessViolation(vaddr, access));
+        }
+        self.populate(&area, vaddr, alloc)?;
+        Ok(())
+    }
+
+    /// Get flags of the page mapped at vaddr, empty if nothing is mapped
+    pub fn page_flags(&self, vaddr: VirtAddr) -> MappingResult<if_entry::MappingFlags> {
+        let mut level = self.top_level();
+        loop {
+            match level.get_entry(vaddr)? {
+                if_entry::PageTableEntry::Level(sublevel) => level = sublevel,
+                if_entry::PageTableEntry::Page(_, flags) => return Ok(flags),
+            }
+        }
+    }
+}
+
+impl PageTableLevel {
+    /// Allocate and clear a new page table
+    fn alloc_table(alloc: &impl PageAllocatorTrait<PageSize>) -> Option<PhysAddr> {
+        let addr = alloc.alloc(PageSize::Size4K)?;
+        if let Some(frame) = crate::memory::frame::frames().and_then(|frames| frames.get(addr)) {
+            frame.insert_flags(FrameFlags::PAGE_TABLE);
+        }
+        let table: &mut super::PageTable = unsafe { &mut *super::phys2virt(addr).as_mut_ptr_of() };
+        table.fill(entry::PTEntry::NULL);
+        Some(addr)
+    }
+
+    /// Whether changes to this page table have to be mirrored in the host. Kernel
+    /// half is shared, so it's mirrored no matter which address space is changed
+    fn is_mirrored(&self, vaddr: VirtAddr) -> bool {
+        self.2.as_usize() == CURRENT.load(Ordering::SeqCst)
+            || vaddr.as_usize() >= super::KERNEL_HALF_START
+    }
+
+    /// Mirror all pages of a range, that are present in this page table, in the host
+    fn mirror(&self, vaddr: VirtAddr, size: usize) {
+        for vaddr in (vaddr.as_usize()..vaddr.as_usize() + size).step_by(self.region_size()) {
+            let vaddr = VirtAddr::from_usize(vaddr);
+            match self.get_entry(vaddr) {
+                Ok(if_entry::PageTableEntry::Level(sublevel)) => {
+                    sublevel.mirror(vaddr, self.region_size())
+                }
+                Ok(if_entry::PageTableEntry::Page(paddr, flags))
+                    if flags.contains(if_entry::MappingFlags::PRESENT) =>
+                {
+                    super::host_map(vaddr, self.region_size(), paddr, flags)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Page table of this level. All page tables are accessed through the direct mapping
+    #[allow(clippy::mut_from_ref)]
+    fn page_table(&self) -> &mut super::PageTable {
+        unsafe { &mut *super::phys2virt(self.0).as_mut_ptr_of() }
+    }
+
+    /// Get the page table entry associated with this address
+    #[allow(clippy::mut_from_ref)]
+    fn entry(&self, vaddr: VirtAddr) -> &mut entry::PTEntry {
+        let mask = super::PAGE_TABLE_ENTRIES - 1;
+        let index = (vaddr.as_usize() >> self.1) & mask;
+        &mut self.page_table()[index]
+    }
+}
+
+impl NestedPageTable for AddressSpace {
+    type PageSize = PageSize;
+    type Level = PageTableLevel;
+
+    fn top_level(&self) -> Self::Level {
+        self.0.clone()
+    }
+}
+
+impl NestedPageTableLevel for PageTableLevel {
+    type PageSize = PageSize;
+
+    fn region_size(&self) -> usize {
+        1 << self.1
+    }
+
+    fn new_sublevel(&self, alloc: &impl PageAllocatorTrait<Self::PageSize>) -> Option<Self> {
+        let addr = PageTableLevel::alloc_table(alloc)?;
+        Some(PageTableLevel(
+            addr,
+            self.1 - super::PAGE_LEVEL_BITS,
+            self.2,
+        ))
+    }
+
+    fn free_sublevel(
+        &self,
+        sublevel: Self,
+        alloc: &impl PageAllocatorTrait<Self::PageSize>,
+    ) -> MappingResult<()> {
+        if let Some(frame) =
+            crate::memory::frame::frames().and_then(|frames| frames.get(sublevel.0))
+        {
+            frame.remove_flags(FrameFlags::PAGE_TABLE);
+        }
+        alloc.free(sublevel.0, PageSize::Size4K);
+        Ok(())
+    }
+
+    fn set_entry(
+        &self,
+        vaddr: VirtAddr,
+        new_entry: crate::memory::address_space::nested_page_table::PageTableEntry<Self>,
+    ) -> MappingResult<()> {
+        if matches!(new_entry, if_entry::PageTableEntry::Page(_, _)) {
+            debug_assert!(vaddr.is_aligned(1usize << self.1));
+        }
+
+        *self.entry(vaddr) = match new_entry {
+            if_entry::PageTableEntry::Level(level) => entry::PTEntry::new_page_table(level.0),
+            if_entry::PageTableEntry::Page(paddr, flags) => {
+                if self.is_mirrored(vaddr) {
+                    super::host_map(vaddr, self.region_size(), paddr, flags);
+                }
+                entry::PTEntry::new_page(paddr, flags)
+            }
+        };
+        Ok(())
+    }
+
+    fn get_entry(&self, vaddr: VirtAddr) -> MappingResult<if_entry::PageTableEntry<Self>> {
+        let entry = *self.entry(vaddr);
+        if self.1 > 12 && entry.is_page_table() {
+            Ok(if_entry::PageTableEntry::Level(PageTableLevel(
+                entry.address(),
+                self.1 - super::PAGE_LEVEL_BITS,
+                self.2,
+            )))
+        } else {
+            Ok(if_entry::PageTableEntry::Page(
+                entry.address(),
+                entry.flags(),
+            ))
+        }
+    }
+
+    fn write_page(
+        &self,
+        paddr: PhysAddr,
+        page_size: Self::PageSize,
+        mut write: impl FnMut(usize, &mut [u8]),
+    ) {
+        // Whole page is reachable through the direct mapping
+        let page = unsafe {
+            core::slice::from_raw_parts_mut(super::phys2virt(paddr).as_mut_ptr(), page_size.into())
+        };
+        write(0, page);
+    }
+}
+
+impl AddressSpaceTrait<PageSize> for AddressSpace {
+    fn map_alloc(
+        &self,
+        vaddr: VirtAddr,
+        size: usize,
+        flags: if_entry::MappingFlags,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> MappingResult<VirtAddr> {
+        <Self as NestedPageTable>::map_alloc(self, vaddr, size, flags, alloc)
+    }
+
+    fn unmap_free(
+        &self,
+        vaddr: VirtAddr,
+        size: usize,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> MappingResult<()> {
+        <Self as NestedPageTable>::unmap_free(self, vaddr, size, alloc)
+    }
+
+    fn reserve(&self, area: Vma) -> Result<(), VmaError> {
+        AREAS.write().entry(self.0 .0).or_default().insert(area)
+    }
+
+    fn release(
+        &self,
+        vaddr: VirtAddr,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> Result<(), VmaError> {
+        let area = AREAS
+            .write()
+            .get_mut(&self.0 .0)
+            .ok_or(VmaError::NotFound(vaddr))?
+            .remove(vaddr)?;
+        <Self as NestedPageTable>::unmap_free(self, area.start, area.size, alloc)?;
+        Ok(())
+    }
+
+    fn fork(&self, alloc: &impl PageAllocatorTrait<PageSize>) -> MappingResult<Self> {
+        let top_level = self.top_level();
+        let child = Self::new(alloc)
+            .ok_or(MappingError::PageAllocationFailed)?
+            .top_level();
+
+        let user_start = VirtAddr::from_usize(super::USER_START);
+        self.fork_into(
+            &child,
+            user_start,
+            super::USER_END - super::USER_START,
+            alloc,
+        )?;
+
+        // Kernel half is shared between all address spaces. All of it's
+        // top level entries are allocated with the kernel address space
+        let step = top_level.region_size();
+        for vaddr in (super::KERNEL_HALF_START..super::KERNEL_HALF_END).step_by(step) {
+            let vaddr = VirtAddr::from_usize(vaddr);
+            let entry = top_level.get_entry(vaddr)?;
+            if entry.mapped() {
+                child.set_entry(vaddr, entry)?;
+            }
+        }
+
+        let mut areas = AREAS.write();
+        if let Some(parent_areas) = areas.get(&top_level.0).cloned() {
+            areas.insert(child.0, parent_areas);
+        }
+        Ok(Self(child))
+    }
+}
@@ -0,0 +1,369 @@
+use crate::memory::boot_memory_map::{BootMemoryMap, BootMemoryMapError, RegionKind};
+use crate::memory::MappingFlags;
+use crate::sync::{mutex, Mutex};
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+
+/// Copying to and from user memory
+mod user;
+
+mod page_size;
+pub use page_size::PageSize;
+
+/// Page table entry
+mod page_table_entry;
+use page_table_entry::PTEntry;
+
+/// Address space implementation
+mod address_space;
+pub use address_space::AddressSpace;
+
+/// Use standard zone-based page allocator
+pub type PageAllocator = crate::memory::page_allocator::ZonedBuddy<0x1000>;
+
+/// Size of the memory file, that stands in for physical memory
+const PHYSICAL_MEMORY_SIZE: usize = 0x1000_0000;
+
+/// Number of bits each table takes off the vitual address
+const PAGE_LEVEL_BITS: usize = 9;
+
+/// Number of bits of the virtual address, that an entry of the top level page table covers
+const TOP_LEVEL_BITS: usize = 39;
+
+/// Number of page table entries in a page table
+const PAGE_TABLE_ENTRIES: usize = 1 << PAGE_LEVEL_BITS;
+
+/// Emulated virtual memory is a window of the host address space, reserved
+/// at a fixed address, far from anything the host maps by itself.
+/// The lower half of the window belongs to user space
+const USER_START: usize = 0x2000_0000_0000;
+const USER_END: usize = 0x2800_0000_0000;
+
+/// The upper half of the window is the kernel half, shared between all address spaces
+const KERNEL_HALF_START: usize = USER_END;
+const KERNEL_HALF_END: usize = 0x3000_0000_0000;
+
+/// Virtual memory range reserved for the kernel heap, at the end of the
+/// kernel half. The rest of the kernel half is used by vmalloc
+pub(super) const KERNEL_HEAP_START: usize = 0x2fff_f000_0000;
+pub(super) const KERNEL_HEAP_SIZE: usize = 0x1000_0000;
+
+/// Page tables of the kernel address space: the top level and a table for each top level
+/// entry of the kernel half. They stand in for the page tables of a kernel image
+const KERNEL_PAGE_TABLES_START: usize = 0x1000;
+const KERNEL_PAGE_TABLES: usize = 1 + ((KERNEL_HALF_END - KERNEL_HALF_START) >> TOP_LEVEL_BITS);
+
+/// Page table type
+type PageTable = [PTEntry; PAGE_TABLE_ENTRIES];
+
+/// Physical memory is a memory file, mapped somewhere by the host. Emulated
+/// pages are mappings of the file, page tables are accessed through `base`
+struct PhysicalMemory {
+    fd: libc::c_int,
+    base: usize,
+}
+
+static PHYSICAL_MEMORY: spin::Once<PhysicalMemory> = spin::Once::new();
+
+static PAGE_ALLOCATOR: PageAllocator = PageAllocator::new();
+
+/// Physical memory map, there is only the memory file in it
+static BOOT_MEMORY_MAP: Mutex<BootMemoryMap> = mutex(BootMemoryMap::new());
+
+/// Convert a physical address to virtual in the host mapping of the memory file
+pub(super) fn phys2virt(paddr: PhysAddr) -> VirtAddr {
+    debug_assert!(paddr.as_usize() < PHYSICAL_MEMORY_SIZE);
+    let memory = PHYSICAL_MEMORY
+        .get()
+        .expect("Physical memory is not set up");
+    VirtAddr::from_usize(memory.base + paddr.as_usize())
+}
+
+/// Convert a virtual address in the host mapping of the memory file to physical
+fn virt2phys(vaddr: VirtAddr) -> PhysAddr {
+    let memory = PHYSICAL_MEMORY
+        .get()
+        .expect("Physical memory is not set up");
+    debug_assert!(vaddr.as_usize() >= memory.base);
+    PhysAddr::from_usize(vaddr.as_usize() - memory.base)
+}
+
+pub struct Memory;
+impl crate::arch::MemoryTrait for Memory {
+    type PageSize = PageSize;
+    type PageAllocator = PageAllocator;
+    type AddressSpace = AddressSpace;
+
+    fn page_allocator() -> &'static Self::PageAllocator {
+        &PAGE_ALLOCATOR
+    }
+
+    fn kernel_address_space() -> Self::AddressSpace {
+        AddressSpace::from_paddr(PhysAddr::from_usize(KERNEL_PAGE_TABLES_START))
+    }
+
+    fn vmalloc_range() -> (VirtAddr, VirtAddr) {
+        (
+            VirtAddr::from_usize(KERNEL_HALF_START),
+            VirtAddr::from_usize(KERNEL_HEAP_START),
+        )
+    }
+
+    fn alloc_kernel_page() -> Option<VirtAddr> {
+        PAGE_ALLOCATOR.alloc(PageSize::Size4K.into()).map(phys2virt)
+    }
+
+    fn free_kernel_page(page: VirtAddr) {
+        PAGE_ALLOCATOR.free(virt2phys(page), PageSize::Size4K.into());
+    }
+
+    fn heap_stats() -> crate::memory::HeapStats {
+        super::allocator::stats()
+    }
+
+    fn memory_stats() -> crate::memory::MemoryStats {
+        let page_tables = crate::memory::frame::frames().map_or(0, |frames| {
+            frames.count(crate::memory::FrameFlags::PAGE_TABLE) * usize::from(PageSize::Size4K)
+        });
+        let map = BOOT_MEMORY_MAP.lock();
+        crate::memory::MemoryStats {
+            zones: PAGE_ALLOCATOR.zone_stats(),
+            page_tables,
+            heap: Self::heap_stats(),
+            slab: crate::memory::slab::stats(),
+            reserved: RegionKind::ALL
+                .into_iter()
+                .filter(|&kind| kind != RegionKind::Free)
+                .map(|kind| (kind, map.total(kind)))
+                .filter(|&(_, size)| size > 0)
+                .collect(),
+        }
+    }
+
+    fn boot_memory_map() -> BootMemoryMap {
+        BOOT_MEMORY_MAP.lock().clone()
+    }
+
+    fn release_acpi_memory() {
+        // There is no firmware
+    }
+
+    fn user_range() -> (VirtAddr, VirtAddr) {
+        (
+            VirtAddr::from_usize(USER_START),
+            VirtAddr::from_usize(USER_END),
+        )
+    }
+
+    unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+        unsafe { user::copy_user(dst, src, len) }
+    }
+}
+
+/// Setup paging: create physical memory and the kernel address space
+pub(super) fn setup_paging() {
+    PHYSICAL_MEMORY.call_once(create_physical_memory);
+    reserve_window();
+
+    let mut map = BOOT_MEMORY_MAP.lock();
+    *map = boot_memory_map().expect("Failed to build boot memory map");
+    setup_frame_database(&mut map).expect("Failed to set up the frame database");
+    crate::println!("Boot memory map:\n{}", *map);
+
+    // Kernel heap grows while zones are added, so the kernel address space comes first
+    AddressSpace::new_kernel(PhysAddr::from_usize(KERNEL_PAGE_TABLES_START))
+        .expect("Failed to create the kernel address space")
+        .activate();
+
+    // Add zones to the page allocator
+    for region in map.regions_of(RegionKind::Free) {
+        add_zone(region.start, region.end);
+    }
+}
+
+/// Create the memory file and map all of it into the host
+fn create_physical_memory() -> PhysicalMemory {
+    unsafe {
+        let fd = libc::memfd_create(c"satan-physical-memory".as_ptr(), libc::MFD_CLOEXEC);
+        if fd < 0 || libc::ftruncate(fd, PHYSICAL_MEMORY_SIZE as _) != 0 {
+            panic!("Failed to create the physical memory file");
+        }
+        let base = libc::mmap(
+            core::ptr::null_mut(),
+            PHYSICAL_MEMORY_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        if base == libc::MAP_FAILED {
+            panic!("Failed to map the physical memory file");
+        }
+        crate::println!(
+            "Physical memory: {} at {:p}",
+            crate::memory::FormatSize(PHYSICAL_MEMORY_SIZE as _),
+            base
+        );
+        PhysicalMemory {
+            fd,
+            base: base as usize,
+        }
+    }
+}
+
+/// Reserve the window of emulated virtual memory, so that the host never puts
+/// anything there. Nothing is accessible until it's mapped by a page table
+fn reserve_window() {
+    let start = USER_START as *mut libc::c_void;
+    let window = unsafe {
+        libc::mmap(
+            start,
+            KERNEL_HALF_END - USER_START,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE
+                | libc::MAP_ANONYMOUS
+                | libc::MAP_NORESERVE
+                | libc::MAP_FIXED_NOREPLACE,
+            -1,
+            0,
+        )
+    };
+    if window != start {
+        panic!("Failed to reserve virtual memory at {:p}", start);
+    }
+}
+
+/// Mirror a page of an emulated address space in the host: map the
+/// memory file at paddr with the protection from flags, or make
+/// the range inaccessible, if the page is not present
+pub(super) fn host_map(vaddr: VirtAddr, size: usize, paddr: PhysAddr, flags: MappingFlags) {
+    // Only the window is backed by the host, the rest exists only in page tables
+    if vaddr.as_usize() < USER_START || vaddr.as_usize() + size > KERNEL_HALF_END {
+        return;
+    }
+
+    let memory = PHYSICAL_MEMORY
+        .get()
+        .expect("Physical memory is not set up");
+    let result = unsafe {
+        if flags.contains(MappingFlags::PRESENT) {
+            let mut prot = libc::PROT_READ;
+            if flags.contains(MappingFlags::WRITE) {
+                prot |= libc::PROT_WRITE;
+            }
+            if flags.contains(MappingFlags::EXECUTE) {
+                prot |= libc::PROT_EXEC;
+            }
+            libc::mmap(
+                vaddr.as_mut_ptr().cast(),
+                size,
+                prot,
+                libc::MAP_SHARED | libc::MAP_FIXED,
+                memory.fd,
+                paddr.as_usize() as _,
+            )
+        } else {
+            libc::mmap(
+                vaddr.as_mut_ptr().cast(),
+                size,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        }
+    };
+    if result == libc::MAP_FAILED {
+        panic!("Failed to mirror a page at {:#x}", vaddr);
+    }
+}
+
+/// Build the memory map. All of the memory file is free, except for the kernel page
+/// tables and the first page, so that a null physical address is never handed out
+fn boot_memory_map() -> Result<BootMemoryMap, BootMemoryMapError> {
+    let mut map = BootMemoryMap::new();
+    map.add(
+        PhysAddr::from_usize(0),
+        PhysAddr::from_usize(PHYSICAL_MEMORY_SIZE),
+        RegionKind::Free,
+    )?;
+    map.add(
+        PhysAddr::from_usize(0),
+        PhysAddr::from_usize(0x1000),
+        RegionKind::Reserved,
+    )?;
+    map.add(
+        PhysAddr::from_usize(KERNEL_PAGE_TABLES_START),
+        PhysAddr::from_usize(KERNEL_PAGE_TABLES_START + KERNEL_PAGE_TABLES * 0x1000),
+        RegionKind::Kernel,
+    )?;
+    map.sanitize()?;
+    Ok(map)
+}
+
+/// Allocate the page frame database from free memory in the map. It is
+/// accessed through the host mapping of the memory file
+fn setup_frame_database(map: &mut BootMemoryMap) -> Result<(), BootMemoryMapError> {
+    use crate::memory::FrameDatabase;
+
+    let size = FrameDatabase::size(map.memory_start(), map.memory_end()).align_up_4k();
+    let start = map
+        .regions_of(RegionKind::Free)
+        .map(|region| (region.start.align_up_4k(), region.end))
+        .find(|&(start, end)| end > start && end - start >= size)
+        .map(|(start, _)| start)
+        .expect("Not enough memory for the frame database");
+    map.add(start, start + size, RegionKind::FrameDatabase)?;
+    map.sanitize()?;
+
+    let frames = unsafe { crate::memory::frame::init(phys2virt(start), map) };
+    crate::println!(
+        "Frame database: {} frames at {:#x}",
+        frames.frames().len(),
+        start
+    );
+    Ok(())
+}
+
+/// Add a free physical memory range to the page allocator
+fn add_zone(start: PhysAddr, end: PhysAddr) {
+    let start = start.align_up_4k();
+    let end = end.align_down_4k();
+    if end <= start {
+        return;
+    }
+
+    if PAGE_ALLOCATOR
+        .add_zone(start.as_usize(), end - start)
+        .is_err()
+    {
+        crate::println!("Failed to add memory zone {:#x} to {:#x}", start, end);
+    }
+}
+
+/// Try to resolve a page fault: copy a copy-on-write
+/// page or map a page of a memory area
+pub(super) fn handle_page_fault(
+    vaddr: VirtAddr,
+    access: MappingFlags,
+) -> Result<(), crate::memory::FaultError> {
+    use crate::memory::address_space::nested_page_table::NestedPageTable as _;
+    use crate::memory::FaultError;
+
+    if !(USER_START..KERNEL_HALF_END).contains(&vaddr.as_usize()) {
+        return Err(FaultError::NoArea(vaddr));
+    }
+
+    let address_space = AddressSpace::current();
+    if !address_space
+        .page_flags(vaddr)?
+        .contains(MappingFlags::PRESENT)
+    {
+        address_space.handle_fault(vaddr, access, &PAGE_ALLOCATOR)
+    } else if access.contains(MappingFlags::WRITE)
+        && address_space.resolve_cow(vaddr, &PAGE_ALLOCATOR)?
+    {
+        Ok(())
+    } else {
+        Err(FaultError::AccessViolation(vaddr, access))
+    }
+}
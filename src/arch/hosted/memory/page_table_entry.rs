@@ -0,0 +1,50 @@
+use crate::memory::MappingFlags;
+use memory_addr::PhysAddr;
+
+/// Entry points to the next level page table. Above all [`MappingFlags`]
+const TABLE: usize = 1 << 11;
+/// Bits of a page table entry, that hold flags
+const FLAGS_MASK: usize = 0xfff;
+
+/// Page table entry. There is no hardware to read it, so it is just a
+/// page aligned physical address with [`MappingFlags`] in the low bits
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub(super) struct PTEntry(usize);
+
+impl core::fmt::Debug for PTEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PTEntry({:#x})", self.0)
+    }
+}
+
+impl PTEntry {
+    pub(super) const NULL: Self = Self(0);
+
+    /// Create a new entry associated with a page. Page size
+    /// is defined only by the level of the page table
+    pub(super) fn new_page(addr: PhysAddr, flags: MappingFlags) -> Self {
+        let flags = flags - MappingFlags::WRITE_EXECUTE;
+        Self(addr.as_usize() | flags.bits())
+    }
+
+    /// Create a new entry associated with a page table
+    pub(super) fn new_page_table(addr: PhysAddr) -> Self {
+        Self(addr.as_usize() | TABLE | MappingFlags::PRESENT.bits())
+    }
+
+    /// Get flags of this page table entry
+    pub(super) fn flags(&self) -> MappingFlags {
+        MappingFlags::from_bits_truncate(self.0 & FLAGS_MASK & !TABLE)
+    }
+
+    /// Whether this entry points to the next level page table
+    pub(super) fn is_page_table(&self) -> bool {
+        self.0 & TABLE != 0
+    }
+
+    /// Get the address this page table entry holds
+    pub(super) fn address(&self) -> PhysAddr {
+        PhysAddr::from_usize(self.0 & !FLAGS_MASK)
+    }
+}
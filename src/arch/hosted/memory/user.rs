@@ -0,0 +1,52 @@
+use crate::memory::MappingFlags;
+use memory_addr::{MemoryAddr, VirtAddr};
+
+/// Make a page of user memory accessible, like the page fault handler would.
+/// A copy can't be resumed after a signal, so faults are avoided upfront by
+/// checking the page table. Memory outside of the user half is kernel's
+fn prepare_user_page(vaddr: VirtAddr, access: MappingFlags) -> bool {
+    if !(super::USER_START..super::USER_END).contains(&vaddr.as_usize()) {
+        return true;
+    }
+
+    match super::AddressSpace::current().page_flags(vaddr) {
+        Ok(flags)
+            if flags.contains(MappingFlags::PRESENT)
+                && (flags.contains(MappingFlags::WRITE)
+                    || !access.contains(MappingFlags::WRITE)) =>
+        {
+            true
+        }
+        Ok(_) => super::handle_page_fault(vaddr, access).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Copy len bytes from src to dst, a page at a time, stopping at the
+/// first page of user memory that can't be accessed. Returns the number
+/// of bytes left, if the copy faulted
+///
+/// # Safety
+/// Kernel side of the copy must be valid for len bytes
+pub(super) unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    const PAGE_SIZE: usize = 0x1000;
+
+    let mut copied = 0;
+    while copied < len {
+        let src_addr = VirtAddr::from_ptr_of(src) + copied;
+        let dst_addr = VirtAddr::from_mut_ptr_of(dst) + copied;
+        let chunk = (len - copied)
+            .min(PAGE_SIZE - src_addr.align_offset_4k())
+            .min(PAGE_SIZE - dst_addr.align_offset_4k());
+        if !prepare_user_page(src_addr, MappingFlags::READ)
+            || !prepare_user_page(dst_addr, MappingFlags::WRITE)
+        {
+            break;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(src.add(copied), dst.add(copied), chunk);
+        }
+        copied += chunk;
+    }
+    len - copied
+}
@@ -0,0 +1,52 @@
+/// Early logging facilities
+mod early_logger;
+
+/// CPU Interface
+mod cpu;
+
+/// Signal handling, signals stand in for traps
+mod interrupts;
+
+/// Timer interrupts
+mod timer;
+
+/// Global allocator, kernel heap
+mod allocator;
+
+/// Kernel thread context switching
+mod context;
+
+/// Paging implementation
+mod memory;
+
+#[cfg(feature = "kernel-tests")]
+mod tests;
+
+/// Arch implementation
+pub struct Arch;
+impl crate::arch::ArchTrait for Arch {
+    type EarlyLogger = early_logger::EarlyLogger;
+    type Cpu = cpu::Cpu;
+    type Memory = memory::Memory;
+    type Context = context::Context;
+    type Timer = timer::Timer;
+}
+
+/// Kernel setup function. The kernel is an ordinary Linux
+/// process, so the C runtime calls it like any other program
+#[no_mangle]
+pub extern "C" fn main(_argc: libc::c_int, _argv: *const *const libc::c_char) -> libc::c_int {
+    crate::println!("Hello, SATAN!");
+    interrupts::setup();
+    memory::setup_paging();
+    timer::setup();
+
+    #[cfg(feature = "kernel-tests")]
+    tests::run();
+
+    loop {
+        unsafe {
+            libc::pause();
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use libc::{c_int, c_void, siginfo_t};
+
+/// Timer interrupts per second
+const TICK_RATE: u64 = 100;
+
+/// Number of timer interrupts since the timer was set up
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Handle a timer interrupt, which is SIGALRM of the interval timer
+extern "C" fn tick(_signal: c_int, _info: *mut siginfo_t, _context: *mut c_void) {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    crate::thread::scheduler::tick();
+    crate::thread::scheduler::irq_return();
+}
+
+pub struct Timer;
+
+impl crate::arch::TimerTrait for Timer {
+    fn ticks() -> u64 {
+        TICKS.load(Ordering::SeqCst)
+    }
+}
+
+/// Start periodic timer interrupts with the real time interval timer of the host
+pub(super) fn setup() {
+    super::interrupts::set_handler(libc::SIGALRM, tick);
+    let interval = libc::timeval {
+        tv_sec: 0,
+        tv_usec: (1_000_000 / TICK_RATE) as _,
+    };
+    let timer = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { libc::setitimer(libc::ITIMER_REAL, &timer, core::ptr::null_mut()) } != 0 {
+        panic!("Failed to set up the interval timer");
+    }
+    crate::println!("Timer is setup, {} Hz", TICK_RATE);
+}
@@ -8,8 +8,26 @@ pub mod traits {
 
     /// CPU instructions
     pub trait CpuTrait {
+        /// Maximum number of CPUs supported
+        const MAX_CPUS: usize;
+
         /// Get CPU id, a unique number identifying a CPU core
         fn cpu_id() -> usize;
+
+        /// Mask interrupts on this CPU, returns true if they were enabled
+        fn disable_interrupts() -> bool;
+
+        /// Unmask interrupts on this CPU
+        fn enable_interrupts();
+
+        /// Unmask interrupts and halt until one comes
+        fn wait_for_interrupt();
+    }
+
+    /// Periodic timer interrupts, that drive preemption
+    pub trait TimerTrait {
+        /// Number of timer interrupts since the timer was set up
+        fn ticks() -> u64;
     }
 
     /// Memory abstraction layer
@@ -19,6 +37,50 @@ pub mod traits {
         type AddressSpace: crate::memory::AddressSpaceTrait<Self::PageSize>;
         fn page_allocator() -> &'static Self::PageAllocator;
         fn kernel_address_space() -> Self::AddressSpace;
+        /// Free range of kernel virtual memory, managed by [`crate::memory::vmalloc`]
+        fn vmalloc_range() -> (memory_addr::VirtAddr, memory_addr::VirtAddr);
+        /// Allocate a page of kernel memory, that has a fixed virtual address, like one
+        /// in the direct mapping. Unlike [`crate::memory::vmalloc`], it needs no virtual
+        /// memory allocation and no guard page
+        fn alloc_kernel_page() -> Option<memory_addr::VirtAddr>;
+        /// Free a page, allocated with [`Self::alloc_kernel_page`]
+        fn free_kernel_page(page: memory_addr::VirtAddr);
+        /// Get kernel heap usage statistics
+        fn heap_stats() -> crate::memory::HeapStats;
+        /// Get memory usage of the whole system
+        fn memory_stats() -> crate::memory::MemoryStats;
+        /// Get physical memory map, as it was at boot
+        fn boot_memory_map() -> crate::memory::BootMemoryMap;
+        /// Give memory holding ACPI tables to the page allocator.
+        /// Must be called after the tables are parsed
+        fn release_acpi_memory();
+        /// Range of virtual memory, that belongs to user space
+        fn user_range() -> (memory_addr::VirtAddr, memory_addr::VirtAddr);
+        /// Copy len bytes between user and kernel memory. Faults are caught,
+        /// returns number of bytes that were not copied because of one
+        ///
+        /// # Safety
+        /// Kernel side of the copy must be valid for len bytes
+        unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    }
+
+    /// Kernel thread context switching
+    pub trait ContextTrait: Default {
+        /// Build the context of a new thread. The first switch to it calls
+        /// `entry(arg)` on the stack, that ends at `stack_top`
+        fn new(
+            stack_top: memory_addr::VirtAddr,
+            entry: extern "C" fn(usize) -> !,
+            arg: usize,
+        ) -> Self;
+
+        /// Save callee-saved registers of the running thread into `from`
+        /// and continue the thread, that was saved into `to`
+        ///
+        /// # Safety
+        /// `to` must be built by [`ContextTrait::new`] or saved by a previous
+        /// switch, and both must stay valid until the threads run again
+        unsafe fn switch_to(from: *mut Self, to: *const Self);
     }
 
     /// A trait that every architecture has to implement
@@ -29,18 +91,42 @@ pub mod traits {
         type Cpu: CpuTrait;
         /// See [MemoryTrait]
         type Memory: MemoryTrait;
+        /// See [ContextTrait]
+        type Context: ContextTrait;
+        /// See [TimerTrait]
+        type Timer: TimerTrait;
     }
 }
 
 pub use traits::*;
 
-#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[cfg(all(any(target_arch = "x86_64", target_arch = "x86"), target_os = "none"))]
 /// x86 and x86_64 architectures
 pub mod x86;
-#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[cfg(all(any(target_arch = "x86_64", target_arch = "x86"), target_os = "none"))]
 pub use x86::Arch;
 
+#[cfg(all(target_arch = "riscv64", target_os = "none"))]
+/// RISC-V 64 architecture
+pub mod riscv64;
+#[cfg(all(target_arch = "riscv64", target_os = "none"))]
+pub use riscv64::Arch;
+
+#[cfg(all(target_arch = "aarch64", target_os = "none"))]
+/// AArch64 architecture
+pub mod aarch64;
+#[cfg(all(target_arch = "aarch64", target_os = "none"))]
+pub use aarch64::Arch;
+
+#[cfg(target_os = "linux")]
+/// Hosted architecture, the kernel runs as a Linux process
+pub mod hosted;
+#[cfg(target_os = "linux")]
+pub use hosted::Arch;
+
 // Working around https://github.com/rust-lang/rust/issues/104119
 pub type EarlyLogger = <Arch as ArchTrait>::EarlyLogger;
 pub type Cpu = <Arch as ArchTrait>::Cpu;
 pub type Memory = <Arch as ArchTrait>::Memory;
+pub type Context = <Arch as ArchTrait>::Context;
+pub type Timer = <Arch as ArchTrait>::Timer;
@@ -0,0 +1,67 @@
+/// Saved state of a thread, that is not running. Callee-saved
+/// registers are stored on it's stack, only the stack pointer is kept
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Context {
+    sp: usize,
+}
+
+/// Stack frame, that [`switch_context`] leaves on the stack of a thread it switched
+/// away from: return address and callee-saved registers, 16 byte aligned. New
+/// threads start in [`thread_entry`] with entry and argument in s0 and s1
+#[repr(C)]
+struct Frame {
+    ra: usize,
+    s: [usize; 12],
+    _padding: usize,
+}
+
+impl crate::arch::ContextTrait for Context {
+    fn new(stack_top: memory_addr::VirtAddr, entry: extern "C" fn(usize) -> !, arg: usize) -> Self {
+        let sp = stack_top.as_usize() - core::mem::size_of::<Frame>();
+        let mut s = [0; 12];
+        s[0] = entry as usize;
+        s[1] = arg;
+        let frame = Frame {
+            ra: thread_entry as *const () as usize,
+            s,
+            _padding: 0,
+        };
+        unsafe {
+            (sp as *mut Frame).write(frame);
+        }
+        Self { sp }
+    }
+
+    unsafe fn switch_to(from: *mut Self, to: *const Self) {
+        unsafe { switch_context(from, to) }
+    }
+}
+
+/// Store callee-saved registers on the stack, save the stack pointer
+/// into `from`, then load the one from `to` and restore it's registers
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(from: *mut Context, to: *const Context) {
+    core::arch::naked_asm!(
+        "addi sp, sp, -{frame_size}",
+        "sd ra, 0(sp)",
+        ".irp n, 0,1,2,3,4,5,6,7,8,9,10,11",
+        "sd s\\n, (\\n+1)*8(sp)",
+        ".endr",
+        "sd sp, 0(a0)",
+        "ld sp, 0(a1)",
+        "ld ra, 0(sp)",
+        ".irp n, 0,1,2,3,4,5,6,7,8,9,10,11",
+        "ld s\\n, (\\n+1)*8(sp)",
+        ".endr",
+        "addi sp, sp, {frame_size}",
+        "ret",
+        frame_size = const core::mem::size_of::<Frame>(),
+    );
+}
+
+/// First switch to a new thread returns here. Entry never returns
+#[unsafe(naked)]
+unsafe extern "C" fn thread_entry() -> ! {
+    core::arch::naked_asm!("mv a0, s1", "jalr s0", "unimp");
+}
@@ -0,0 +1,36 @@
+use riscv::register::sstatus;
+
+pub struct Cpu;
+
+impl crate::arch::CpuTrait for Cpu {
+    const MAX_CPUS: usize = 16;
+
+    fn cpu_id() -> usize {
+        // Bootstrap puts the hart id into the thread pointer
+        let hart_id: usize;
+        unsafe {
+            core::arch::asm!("mv {}, tp", out(reg) hart_id, options(nomem, nostack));
+        }
+        hart_id
+    }
+
+    fn disable_interrupts() -> bool {
+        let enabled = sstatus::read().sie();
+        unsafe {
+            sstatus::clear_sie();
+        }
+        enabled
+    }
+
+    fn enable_interrupts() {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+
+    fn wait_for_interrupt() {
+        // wfi wakes up on a pending interrupt, even if they are masked
+        riscv::asm::wfi();
+        Self::enable_interrupts();
+    }
+}
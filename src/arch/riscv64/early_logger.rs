@@ -0,0 +1,37 @@
+/// Writes to the SBI debug console, which is the serial port on QEMU virt
+struct Writer;
+
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                super::sbi::console_putchar(b'\r');
+            }
+            super::sbi::console_putchar(byte);
+        }
+        Ok(())
+    }
+}
+
+static WRITER: crate::sync::IrqSafeMutex<Writer> = crate::sync::irq_safe_mutex(Writer);
+
+pub struct EarlyLogger;
+impl crate::arch::LoggerTrait for EarlyLogger {
+    fn _print(args: core::fmt::Arguments) {
+        use core::fmt::Write as _;
+        WRITER.lock().write_fmt(args).unwrap();
+    }
+
+    fn _panic(args: core::fmt::Arguments) -> ! {
+        use core::fmt::Write as _;
+        let mut writer = WRITER.lock();
+        // Red, like the VGA logger on x86
+        writer.write_str("\x1b[31m").unwrap();
+        writer.write_fmt(args).unwrap();
+        writer.write_str("\x1b[0m").unwrap();
+        riscv::interrupt::disable();
+        loop {
+            riscv::asm::wfi();
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use riscv::interrupt::supervisor::{Exception, Interrupt};
+use riscv::register::scause::{ExceptionNumber as _, InterruptNumber as _};
+use riscv::register::{scause, sstatus, stval, stvec};
+
+/// Registers saved by the trap entry. General purpose registers are indexed
+/// by their number, x0 slot is unused
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct InterruptStackFrame {
+    pub(super) registers: [usize; 32],
+    pub(super) sepc: usize,
+    pub(super) sstatus: usize,
+}
+
+/// Size of the saved state on the stack, keeps the stack 16 byte aligned
+const FRAME_SIZE: usize = core::mem::size_of::<InterruptStackFrame>().next_multiple_of(16);
+
+/// Index of the stack pointer in [`InterruptStackFrame::registers`]
+const SP: usize = 2;
+
+/// Previous privilege mode bit of sstatus, set if the trap came from the kernel
+const SSTATUS_SPP: usize = 1 << 8;
+
+// stvec needs a 4 byte aligned address, naked functions can be 2 byte aligned
+// with compressed instructions, so the trap entry is written in global assembly.
+// Traps only come from the kernel, so it stays on the current stack
+core::arch::global_asm!(
+    ".section .text",
+    ".align 2",
+    ".global trap_entry",
+    "trap_entry:",
+    "addi sp, sp, -{frame_size}",
+    ".irp n, 1,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+    "sd x\\n, \\n*8(sp)",
+    ".endr",
+    "addi t0, sp, {frame_size}",
+    "sd t0, {sp}*8(sp)",
+    "csrr t0, sepc",
+    "sd t0, 32*8(sp)",
+    "csrr t0, sstatus",
+    "sd t0, 33*8(sp)",
+    "mv a0, sp",
+    "call {trap_handler}",
+    "ld t0, 32*8(sp)",
+    "csrw sepc, t0",
+    "ld t0, 33*8(sp)",
+    "csrw sstatus, t0",
+    ".irp n, 1,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+    "ld x\\n, \\n*8(sp)",
+    ".endr",
+    "addi sp, sp, {frame_size}",
+    "sret",
+    frame_size = const FRAME_SIZE,
+    sp = const SP,
+    trap_handler = sym trap_handler,
+);
+
+extern "C" {
+    fn trap_entry();
+}
+
+/// Central trap handler, all interrupts and exceptions come here
+extern "C" fn trap_handler(frame: &mut InterruptStackFrame) {
+    let cause = scause::read();
+    if cause.is_interrupt() {
+        match Interrupt::from_number(cause.code()) {
+            Ok(Interrupt::SupervisorTimer) => super::timer::tick(),
+            _ => panic!("Unknown interrupt: {:#x}", cause.code()),
+        }
+        crate::thread::scheduler::irq_return();
+        return;
+    }
+
+    let exception = Exception::from_number(cause.code());
+    match exception {
+        Ok(
+            Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault,
+        ) => {
+            use crate::memory::MappingFlags;
+
+            let address = memory_addr::VirtAddr::from_usize(stval::read());
+            let mut access = match exception {
+                Ok(Exception::InstructionPageFault) => MappingFlags::EXECUTE,
+                Ok(Exception::StorePageFault) => MappingFlags::WRITE,
+                _ => MappingFlags::READ,
+            };
+            if (frame.sstatus & SSTATUS_SPP) == 0 {
+                access |= MappingFlags::USER;
+            }
+            let Err(err) = super::memory::handle_page_fault(address, access) else {
+                return;
+            };
+            // Accessing user memory is allowed to fault
+            if let Some(fixup) = super::memory::exception_fixup(frame.sepc) {
+                frame.sepc = fixup;
+                return;
+            }
+            crate::println!("{}", err);
+            panic!("Page fault at {:#x}", frame.sepc);
+        }
+        Ok(Exception::Breakpoint) => {
+            crate::println!("Test breakpoint\n{:#x?}", frame);
+            frame.sepc += instruction_size(frame.sepc);
+        }
+        Ok(exception) => panic!(
+            "{:?} at {:#x}\nTrap value: {:#x}",
+            exception,
+            frame.sepc,
+            stval::read()
+        ),
+        Err(_) => panic!("Unknown exception: {:#x}", cause.code()),
+    }
+}
+
+/// Size of the instruction at ip, it's either compressed (2 bytes) or not (4 bytes)
+fn instruction_size(ip: usize) -> usize {
+    let low_bits = unsafe { *(ip as *const u16) } & 0b11;
+    if low_bits == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Install the trap handler and enable interrupts
+pub(super) fn setup() {
+    unsafe {
+        stvec::write(stvec::Stvec::new(
+            trap_entry as unsafe extern "C" fn() as usize,
+            stvec::TrapMode::Direct,
+        ));
+        sstatus::set_sie();
+    }
+    crate::println!("Trap handler is setup");
+}
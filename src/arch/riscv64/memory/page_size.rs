@@ -0,0 +1,39 @@
+/// Page sizes possible to map
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(usize)]
+pub enum PageSize {
+    #[default]
+    Size4K = 0x1000,
+    /// Megapage
+    Size2M = 0x200000,
+    /// Gigapage
+    Size1G = 0x40000000,
+}
+
+impl PageSize {
+    /// Large page, mapped by an entry of the page table right above the last level
+    pub const LARGE: Self = Self::Size2M;
+}
+
+impl TryFrom<usize> for PageSize {
+    type Error = ();
+
+    fn try_from(size: usize) -> Result<Self, Self::Error> {
+        match size {
+            0x1000 => Ok(Self::Size4K),
+            0x200000 => Ok(Self::Size2M),
+            0x40000000 => Ok(Self::Size1G),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<PageSize> for usize {
+    fn from(value: PageSize) -> Self {
+        value as _
+    }
+}
+
+impl crate::memory::PageSizeTrait for PageSize {
+    const MIN: Self = Self::Size4K;
+}
@@ -0,0 +1,133 @@
+use crate::memory::MappingFlags;
+use memory_addr::PhysAddr;
+
+/// Offset of the physical page number in a page table entry
+const PPN_SHIFT: usize = 10;
+/// Bits of a page table entry, that hold the physical page number
+const PPN_MASK: usize = 0x003f_ffff_ffff_fc00;
+
+bitflags::bitflags! {
+    /// Sv39 page table entry flags
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[repr(transparent)]
+    pub(super) struct PTEFlags: usize {
+        /// Valid
+        const V   = 1 << 0;
+        /// Readable
+        const R   = 1 << 1;
+        /// Writable, only valid along with R
+        const W   = 1 << 2;
+        /// Executable
+        const X   = 1 << 3;
+        /// User accessible
+        const U   = 1 << 4;
+        /// Global, mapped in all address spaces
+        const G   = 1 << 5;
+        /// Accessed
+        const A   = 1 << 6;
+        /// Dirty
+        const D   = 1 << 7;
+        /// Copy-on-write (reserved for software); the page is shared read-only
+        const COW = 1 << 8;
+    }
+}
+
+impl From<MappingFlags> for PTEFlags {
+    fn from(value: MappingFlags) -> Self {
+        let mut flags = Self::empty();
+        if value.contains(MappingFlags::PRESENT) {
+            // Valid entry with none of R, W and X is a pointer to the next level,
+            // so pages are always readable, like on x86. Accessed and dirty bits are
+            // not managed by the hardware everywhere, so they are set upfront
+            flags |= Self::V | Self::R | Self::A | Self::D;
+        }
+        if value.contains(MappingFlags::WRITE) {
+            flags |= Self::W;
+        }
+        if value.contains(MappingFlags::EXECUTE) {
+            flags |= Self::X;
+        }
+        if value.contains(MappingFlags::USER) {
+            flags |= Self::U;
+        }
+        if value.contains(MappingFlags::GLOBAL) {
+            flags |= Self::G;
+        }
+        if value.contains(MappingFlags::COPY_ON_WRITE) {
+            flags |= Self::COW;
+        }
+        flags
+    }
+}
+
+impl From<PTEFlags> for MappingFlags {
+    fn from(value: PTEFlags) -> Self {
+        let mut flags = Self::empty();
+        if value.contains(PTEFlags::V) {
+            flags |= Self::PRESENT;
+        }
+        if value.contains(PTEFlags::R) {
+            flags |= Self::READ;
+        }
+        if value.contains(PTEFlags::W) {
+            flags |= Self::WRITE;
+        }
+        if value.contains(PTEFlags::X) {
+            flags |= Self::EXECUTE;
+        }
+        if value.contains(PTEFlags::U) {
+            flags |= Self::USER;
+        }
+        if value.contains(PTEFlags::G) {
+            flags |= Self::GLOBAL;
+        }
+        if value.contains(PTEFlags::COW) {
+            flags |= Self::COPY_ON_WRITE;
+        }
+        flags
+    }
+}
+
+/// Page table entry
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub(super) struct PTEntry(usize);
+
+impl core::fmt::Debug for PTEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PTEntry({:#x})", self.0)
+    }
+}
+
+impl PTEntry {
+    pub(super) const NULL: Self = Self(0);
+
+    /// Create a new entry associated with a page. Unlike x86, page size
+    /// is defined only by the level of the page table
+    pub(super) fn new_page(addr: PhysAddr, flags: PTEFlags) -> Self {
+        Self(((addr.as_usize() >> 12) << PPN_SHIFT) | flags.bits())
+    }
+
+    /// Create a new entry associated with a page table. Entries without
+    /// R, W and X point to the next level, access rights are
+    /// controlled by the entries of the page table itself
+    pub(super) fn new_page_table(addr: PhysAddr) -> Self {
+        Self(((addr.as_usize() >> 12) << PPN_SHIFT) | PTEFlags::V.bits())
+    }
+
+    /// Get flags of this page table entry
+    pub(super) fn flags(&self) -> PTEFlags {
+        PTEFlags::from_bits_truncate(self.0)
+    }
+
+    /// Whether this entry points to the next level page table
+    pub(super) fn is_page_table(&self) -> bool {
+        let flags = self.flags();
+        flags.contains(PTEFlags::V) && !flags.intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X)
+    }
+
+    /// Get the address this page table entry holds
+    pub(super) fn address(&self) -> PhysAddr {
+        PhysAddr::from_usize(((self.0 & PPN_MASK) >> PPN_SHIFT) << 12)
+    }
+}
@@ -0,0 +1,65 @@
+/// Entry of the exception fixup table. If an instruction at `instruction`
+/// faults, execution continues at `fixup` instead of panicking
+#[repr(C)]
+struct ExTableEntry {
+    instruction: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    #[link_name = "ex_table_start"]
+    static EX_TABLE_START: ExTableEntry;
+    #[link_name = "ex_table_end"]
+    static EX_TABLE_END: ExTableEntry;
+}
+
+/// Find where to continue after a fault at ip, if it's allowed to fault
+pub(in crate::arch::riscv64) fn exception_fixup(ip: usize) -> Option<usize> {
+    let table = unsafe {
+        let start = &raw const EX_TABLE_START;
+        let end = &raw const EX_TABLE_END;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table
+        .iter()
+        .find(|entry| entry.instruction == ip)
+        .map(|entry| entry.fixup)
+}
+
+/// Copy len bytes from src to dst, allowing access to user memory.
+/// Returns the number of bytes left, if the copy faulted
+///
+/// # Safety
+/// Kernel side of the copy must be valid for len bytes
+pub(super) unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    /// Supervisor may access user memory while this sstatus bit is set
+    const SSTATUS_SUM: usize = 1 << 18;
+
+    let left: usize;
+    unsafe {
+        // Both the load and the store can fault, the byte isn't counted then
+        core::arch::asm!(
+            "csrs sstatus, {sum}",
+            "0: beqz {len}, 3f",
+            "1: lbu {byte}, 0({src})",
+            "2: sb {byte}, 0({dst})",
+            "addi {src}, {src}, 1",
+            "addi {dst}, {dst}, 1",
+            "addi {len}, {len}, -1",
+            "j 0b",
+            "3: csrc sstatus, {sum}",
+            ".pushsection .ex_table, \"a\"",
+            ".balign 8",
+            ".dword 1b, 3b",
+            ".dword 2b, 3b",
+            ".popsection",
+            sum = in(reg) SSTATUS_SUM,
+            byte = out(reg) _,
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            len = inout(reg) len => left,
+            options(nostack),
+        );
+    }
+    left
+}
@@ -0,0 +1,67 @@
+core::arch::global_asm!(
+    ".set KERNEL_OFFSET, {offset}",
+    include_str!("boot.S"),
+    offset = const memory::KERNEL_OFFSET,
+);
+
+/// Early logging facilities
+mod early_logger;
+
+/// CPU Interface
+mod cpu;
+
+/// Supervisor Binary Interface, calls into the firmware
+mod sbi;
+
+/// Trap handling
+mod interrupts;
+
+/// Timer interrupts
+mod timer;
+
+/// Global allocator, kernel heap
+mod allocator;
+
+/// Kernel thread context switching
+mod context;
+
+/// Paging implementation
+mod memory;
+
+#[cfg(feature = "kernel-tests")]
+mod tests;
+
+/// Arch implementation
+pub struct Arch;
+impl crate::arch::ArchTrait for Arch {
+    type EarlyLogger = early_logger::EarlyLogger;
+    type Cpu = cpu::Cpu;
+    type Memory = memory::Memory;
+    type Context = context::Context;
+    type Timer = timer::Timer;
+}
+
+/// Kernel setup function. First thing that is called after assembly
+/// bootstrap enables paging. Firmware passes the hart id and the device tree
+#[no_mangle]
+pub extern "C" fn ksetup(_hart_id: usize, device_tree_addr: usize) -> ! {
+    crate::println!("Hello, SATAN!");
+    interrupts::setup();
+
+    let device_tree_addr = memory_addr::PhysAddr::from_usize(device_tree_addr);
+    let device_tree_ptr = memory::phys2virt(device_tree_addr);
+    let device_tree = match unsafe { fdt::Fdt::from_ptr(device_tree_ptr.as_ptr()) } {
+        Ok(device_tree) => device_tree,
+        Err(err) => panic!("Failed to parse the device tree: {:?}", err),
+    };
+
+    memory::setup_paging(&device_tree, device_tree_addr);
+    timer::setup(&device_tree);
+
+    #[cfg(feature = "kernel-tests")]
+    tests::run();
+
+    loop {
+        riscv::asm::wfi();
+    }
+}
@@ -0,0 +1,40 @@
+/// Legacy console putchar extension. Deprecated, but it's the only
+/// console extension that every SBI implementation has
+const LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+/// Timer extension, "TIME"
+const TIMER: usize = 0x54494D45;
+
+/// Make an SBI call. Returns the error code and the value
+///
+/// # Safety
+/// Arguments must be valid for the function of the extension
+unsafe fn call(extension: usize, function: usize, args: [usize; 3]) -> (isize, usize) {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a6") function,
+            in("a7") extension,
+            options(nostack),
+        );
+    }
+    (error, value)
+}
+
+/// Write a byte to the debug console
+pub(super) fn console_putchar(byte: u8) {
+    unsafe {
+        call(LEGACY_CONSOLE_PUTCHAR, 0, [byte as usize, 0, 0]);
+    }
+}
+
+/// Program the timer of this hart to fire at time (in ticks of the time CSR)
+pub(super) fn set_timer(time: u64) {
+    unsafe {
+        call(TIMER, 0, [time as usize, 0, 0]);
+    }
+}
@@ -0,0 +1,14 @@
+pub(super) fn run() -> ! {
+    test_breakpoint();
+    crate::memory::tests::run();
+    crate::thread::tests::run();
+    crate::sync::tests::run();
+    crate::memory::tests::test_paging();
+    panic!("Testing finished");
+}
+
+fn test_breakpoint() {
+    unsafe {
+        core::arch::asm!("ebreak");
+    }
+}
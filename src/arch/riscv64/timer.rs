@@ -0,0 +1,46 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Timer interrupts per second
+const TICK_RATE: u64 = 100;
+
+/// Ticks of the time CSR between timer interrupts
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer interrupts since the timer was set up
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Program the next timer interrupt
+fn schedule() {
+    let now = riscv::register::time::read64();
+    super::sbi::set_timer(now + INTERVAL.load(Ordering::SeqCst));
+}
+
+/// Handle a timer interrupt. Setting the timer clears the pending interrupt
+pub(super) fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    schedule();
+    crate::thread::scheduler::tick();
+}
+
+pub struct Timer;
+
+impl crate::arch::TimerTrait for Timer {
+    fn ticks() -> u64 {
+        TICKS.load(Ordering::SeqCst)
+    }
+}
+
+/// Start periodic timer interrupts. Frequency of the time CSR comes from the device tree
+pub(super) fn setup(device_tree: &fdt::Fdt) {
+    let frequency = device_tree
+        .cpus()
+        .next()
+        .expect("No CPUs in the device tree")
+        .timebase_frequency() as u64;
+    INTERVAL.store(frequency / TICK_RATE, Ordering::SeqCst);
+    schedule();
+    unsafe {
+        riscv::register::sie::set_stimer();
+    }
+    crate::println!("Timer is setup, {} Hz", TICK_RATE);
+}
@@ -0,0 +1,192 @@
+/// Saved state of a thread, that is not running. Callee-saved
+/// registers are pushed on it's stack, only the stack pointer is kept
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Context {
+    sp: usize,
+    /// Top of the stack, that interrupts from user mode use. The boot thread
+    /// has none, it never enters user mode, so a stale stack of another
+    /// thread is never left in the TSS, while it runs
+    kernel_stack: usize,
+}
+
+/// Stack frame, that [`switch_context`] leaves on the stack of a thread it
+/// switched away from: callee-saved registers and the return address.
+/// New threads start in [`thread_entry`] with entry and argument in registers
+#[cfg(target_arch = "x86")]
+#[repr(C)]
+struct Frame {
+    edi: usize,
+    /// Argument of a new thread
+    esi: usize,
+    /// Entry point of a new thread
+    ebx: usize,
+    ebp: usize,
+    ret: usize,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+struct Frame {
+    r15: usize,
+    r14: usize,
+    /// Argument of a new thread
+    r13: usize,
+    /// Entry point of a new thread
+    r12: usize,
+    rbx: usize,
+    rbp: usize,
+    ret: usize,
+}
+
+/// Space left between the frame of a new thread and the top of it's stack, so
+/// that the stack is 16 byte aligned, when [`thread_entry`] calls the entry
+#[cfg(target_arch = "x86")]
+const FRAME_OFFSET: usize = 12;
+#[cfg(target_arch = "x86_64")]
+const FRAME_OFFSET: usize = 16;
+
+impl crate::arch::ContextTrait for Context {
+    fn new(stack_top: memory_addr::VirtAddr, entry: extern "C" fn(usize) -> !, arg: usize) -> Self {
+        let sp = stack_top.as_usize() - FRAME_OFFSET - core::mem::size_of::<Frame>();
+        #[cfg(target_arch = "x86")]
+        let frame = Frame {
+            edi: 0,
+            esi: arg,
+            ebx: entry as usize,
+            ebp: 0,
+            ret: thread_entry as *const () as usize,
+        };
+        #[cfg(target_arch = "x86_64")]
+        let frame = Frame {
+            r15: 0,
+            r14: 0,
+            r13: arg,
+            r12: entry as usize,
+            rbx: 0,
+            rbp: 0,
+            ret: thread_entry as *const () as usize,
+        };
+        unsafe {
+            (sp as *mut Frame).write(frame);
+        }
+        Self {
+            sp,
+            kernel_stack: stack_top.as_usize(),
+        }
+    }
+
+    unsafe fn switch_to(from: *mut Self, to: *const Self) {
+        super::gdt::set_kernel_stack(unsafe { (*to).kernel_stack });
+        unsafe { switch_context(from, to) }
+    }
+}
+
+/// Push callee-saved registers, save the stack pointer into `from`,
+/// then load the one from `to` and pop it's registers
+#[cfg(target_arch = "x86")]
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(from: *mut Context, to: *const Context) {
+    core::arch::naked_asm!(
+        "mov 4(%esp), %eax",
+        "mov 8(%esp), %edx",
+        "push %ebp",
+        "push %ebx",
+        "push %esi",
+        "push %edi",
+        "mov %esp, (%eax)",
+        "mov (%edx), %esp",
+        "pop %edi",
+        "pop %esi",
+        "pop %ebx",
+        "pop %ebp",
+        "ret",
+        options(att_syntax),
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(from: *mut Context, to: *const Context) {
+    core::arch::naked_asm!(
+        "push %rbp",
+        "push %rbx",
+        "push %r12",
+        "push %r13",
+        "push %r14",
+        "push %r15",
+        "mov %rsp, (%rdi)",
+        "mov (%rsi), %rsp",
+        "pop %r15",
+        "pop %r14",
+        "pop %r13",
+        "pop %r12",
+        "pop %rbx",
+        "pop %rbp",
+        "ret",
+        options(att_syntax),
+    );
+}
+
+/// First switch to a new thread returns here. Entry never returns
+#[cfg(target_arch = "x86")]
+#[unsafe(naked)]
+unsafe extern "C" fn thread_entry() -> ! {
+    core::arch::naked_asm!("push %esi", "call *%ebx", "ud2", options(att_syntax));
+}
+
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+unsafe extern "C" fn thread_entry() -> ! {
+    core::arch::naked_asm!("mov %r13, %rdi", "call *%r12", "ud2", options(att_syntax));
+}
+
+/// Leave the kernel and continue the running thread in user mode at
+/// `entry` with `stack`, interrupts enabled. Interrupts from user mode
+/// run on the kernel stack of the thread, whatever was on it is abandoned
+pub fn enter_user(entry: memory_addr::VirtAddr, stack: memory_addr::VirtAddr) -> ! {
+    debug_assert_eq!(
+        crate::thread::scheduler::preempt_count(),
+        0,
+        "Entering user mode while holding a spinlock"
+    );
+    // Data segments are not reloaded by iret, kernel ones would be cleared
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        core::arch::asm!(
+            "mov {data:e}, %ds",
+            "mov {data:e}, %es",
+            "mov {data:e}, %fs",
+            "mov {data:e}, %gs",
+            "push {data}",
+            "push {stack}",
+            "push ${flags}",
+            "push ${code}",
+            "push {entry}",
+            "iret",
+            data = in(reg) super::gdt::USER_DATA.bits() as usize,
+            stack = in(reg) stack.as_usize(),
+            flags = const super::cpu::FLAGS_IF,
+            code = const super::gdt::USER_CODE.bits(),
+            entry = in(reg) entry.as_usize(),
+            options(att_syntax, noreturn),
+        );
+    }
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::asm!(
+            "push {data}",
+            "push {stack}",
+            "push ${flags}",
+            "push ${code}",
+            "push {entry}",
+            "iretq",
+            data = in(reg) super::gdt::USER_DATA.bits() as usize,
+            stack = in(reg) stack.as_usize(),
+            flags = const super::cpu::FLAGS_IF,
+            code = const super::gdt::USER_CODE.bits(),
+            entry = in(reg) entry.as_usize(),
+            options(att_syntax, noreturn),
+        );
+    }
+}
@@ -1,8 +1,35 @@
 pub struct Cpu;
 
+/// Interrupt enable flag of EFLAGS
+pub(super) const FLAGS_IF: usize = 1 << 9;
+
 impl crate::arch::CpuTrait for Cpu {
+    const MAX_CPUS: usize = 16;
+
     fn cpu_id() -> usize {
         // TODO: Proper CPU id
         0
     }
+
+    fn disable_interrupts() -> bool {
+        let flags: usize;
+        unsafe {
+            core::arch::asm!("pushf", "pop {}", "cli", out(reg) flags, options(att_syntax));
+        }
+        flags & FLAGS_IF != 0
+    }
+
+    fn enable_interrupts() {
+        unsafe {
+            x86::irq::enable();
+        }
+    }
+
+    fn wait_for_interrupt() {
+        // Interrupts are only recognized after the instruction following sti,
+        // so one can't slip in between and leave the CPU halted
+        unsafe {
+            core::arch::asm!("sti", "hlt", options(att_syntax, nomem, nostack));
+        }
+    }
 }
@@ -1,6 +1,11 @@
 const VGA_WIDTH: usize = 80;
 const VGA_HEIGHT: usize = 25;
-const VGA_BUFFER: usize = 0xb8000;
+#[cfg(target_arch = "x86")]
+/// VGA text buffer, mapped into the higher half by the bootstrap
+const VGA_BUFFER: usize = 0xc00b8000;
+#[cfg(target_arch = "x86_64")]
+/// VGA text buffer, mapped into the higher half by the bootstrap
+const VGA_BUFFER: usize = 0xffff_ffff_800b_8000;
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -98,8 +103,8 @@ impl core::fmt::Write for Writer {
 
 unsafe impl Send for Writer {}
 
-static WRITER: spin::Mutex<Writer> = {
-    spin::Mutex::new(Writer {
+static WRITER: crate::sync::IrqSafeMutex<Writer> = {
+    crate::sync::irq_safe_mutex(Writer {
         col: 0,
         color: 0x0f,
         buffer: VGA_BUFFER as _,
@@ -0,0 +1,123 @@
+use x86::segmentation::{
+    BuildDescriptor, CodeSegmentType, DataSegmentType, Descriptor, DescriptorBuilder,
+    GateDescriptorBuilder, SegmentDescriptorBuilder, SegmentSelector,
+};
+use x86::Ring;
+
+#[cfg(target_arch = "x86_64")]
+use x86::bits64::segmentation::Descriptor64;
+
+#[cfg(target_arch = "x86")]
+type TaskStateSegment = x86::bits32::task::TaskStateSegment;
+#[cfg(target_arch = "x86_64")]
+type TaskStateSegment = x86::bits64::task::TaskStateSegment;
+
+/// Kernel code and data keep the selectors, that the bootstrap GDT used
+pub(super) const KERNEL_CODE: SegmentSelector = SegmentSelector::new(1, Ring::Ring0);
+pub(super) const KERNEL_DATA: SegmentSelector = SegmentSelector::new(2, Ring::Ring0);
+pub(super) const USER_CODE: SegmentSelector = SegmentSelector::new(3, Ring::Ring3);
+pub(super) const USER_DATA: SegmentSelector = SegmentSelector::new(4, Ring::Ring3);
+const TSS: SegmentSelector = SegmentSelector::new(5, Ring::Ring0);
+
+/// TSS descriptor takes two entries on x86_64
+#[cfg(target_arch = "x86")]
+const ENTRIES: usize = 6;
+#[cfg(target_arch = "x86_64")]
+const ENTRIES: usize = 7;
+
+// TODO: GDT and TSS per CPU
+static mut GDT: [Descriptor; ENTRIES] = [Descriptor::NULL; ENTRIES];
+static mut GDTR: Option<x86::dtables::DescriptorTablePointer<Descriptor>> = None;
+/// Only the kernel stack is used, hardware task switching is not
+static mut TASK: TaskStateSegment = TaskStateSegment::new();
+
+/// Flat code or data segment, that spans the whole address space
+fn segment(code: bool, ring: Ring) -> Descriptor {
+    let builder = if code {
+        DescriptorBuilder::code_descriptor(0, 0xfffff, CodeSegmentType::ExecuteRead)
+    } else {
+        DescriptorBuilder::data_descriptor(0, 0xfffff, DataSegmentType::ReadWrite)
+    };
+    let builder = builder.present().dpl(ring).limit_granularity_4kb();
+    // Long mode code segments must not set the default operand size
+    #[cfg(target_arch = "x86_64")]
+    let builder = if code { builder.l() } else { builder.db() };
+    #[cfg(target_arch = "x86")]
+    let builder = builder.db();
+    builder.finish()
+}
+
+/// Replace the bootstrap GDT with one, that has user segments
+/// and a TSS, so that interrupts can come from user mode
+pub(super) fn setup() {
+    unsafe {
+        let base = &raw const TASK as u64;
+        let limit = core::mem::size_of::<TaskStateSegment>() as u64 - 1;
+        // I/O bitmap is past the limit, so user mode can't access any ports
+        #[cfg(target_arch = "x86")]
+        {
+            TASK.ss0 = KERNEL_DATA.bits();
+            TASK.iobp_offset = limit as u16 + 1;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            TASK.iomap_base = limit as u16 + 1;
+        }
+
+        GDT[KERNEL_CODE.index() as usize] = segment(true, Ring::Ring0);
+        GDT[KERNEL_DATA.index() as usize] = segment(false, Ring::Ring0);
+        GDT[USER_CODE.index() as usize] = segment(true, Ring::Ring3);
+        GDT[USER_DATA.index() as usize] = segment(false, Ring::Ring3);
+        #[cfg(target_arch = "x86")]
+        {
+            GDT[TSS.index() as usize] =
+                <DescriptorBuilder as GateDescriptorBuilder<u32>>::tss_descriptor(
+                    base, limit, true,
+                )
+                .present()
+                .finish();
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            let tss: Descriptor64 =
+                <DescriptorBuilder as GateDescriptorBuilder<u64>>::tss_descriptor(
+                    base, limit, true,
+                )
+                .present()
+                .finish();
+            let [low, high] = core::mem::transmute::<Descriptor64, [Descriptor; 2]>(tss);
+            GDT[TSS.index() as usize] = low;
+            GDT[TSS.index() as usize + 1] = high;
+        }
+
+        #[allow(static_mut_refs)]
+        let gdtr = GDTR.insert(x86::dtables::DescriptorTablePointer::new_from_slice(&GDT));
+        x86::dtables::lgdt(gdtr);
+        x86::segmentation::load_ss(KERNEL_DATA);
+        x86::segmentation::load_ds(KERNEL_DATA);
+        x86::segmentation::load_es(KERNEL_DATA);
+        x86::segmentation::load_fs(KERNEL_DATA);
+        x86::segmentation::load_gs(KERNEL_DATA);
+        #[cfg(target_arch = "x86")]
+        x86::bits32::segmentation::load_cs(KERNEL_CODE);
+        #[cfg(target_arch = "x86_64")]
+        x86::bits64::segmentation::load_cs(KERNEL_CODE);
+        x86::task::load_tr(TSS);
+    }
+    crate::println!("GDT is setup");
+}
+
+/// Set the stack, that the CPU switches to, when an interrupt comes
+/// from user mode. Each thread has it's own, so it's updated on every switch
+pub(super) fn set_kernel_stack(top: usize) {
+    unsafe {
+        #[cfg(target_arch = "x86")]
+        {
+            TASK.esp0 = top as u32;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            TASK.rsp[0] = top as u64;
+        }
+    }
+}
@@ -2,13 +2,25 @@ mod state;
 use state::*;
 
 /// Central interrupt handler, all interrupts come here specifying an interrupt number
-extern "fastcall" fn interrupt_handler(interrupt: usize, frame: &mut InterruptStackFrame) {
+extern "C" fn interrupt_handler(interrupt: usize, frame: &mut InterruptStackFrame) {
     if interrupt == 0x20 {
         // Timer
+        super::timer::tick();
+        crate::thread::scheduler::irq_return();
         return;
     }
     if interrupt == 0x0E {
         // Page fault
+        let address = memory_addr::VirtAddr::from_usize(unsafe { x86::controlregs::cr2() });
+        let Err(err) = super::memory::handle_page_fault(address, frame.error_code) else {
+            return;
+        };
+        // Accessing user memory is allowed to fault
+        if let Some(fixup) = super::memory::exception_fixup(frame.iret.ip) {
+            frame.iret.ip = fixup;
+            return;
+        }
+        crate::println!("{}", err);
         crate::println!("Page fault!\nError code:\n{:#032b}", frame.error_code);
         crate::println!("                ^        ^^IRUWP");
         crate::println!("               SGX      SSPK    ");
@@ -18,10 +30,15 @@ extern "fastcall" fn interrupt_handler(interrupt: usize, frame: &mut InterruptSt
         // Keyboard
         let scancode = unsafe { x86::io::inb(0x60) };
         crate::println!("Keyboard: {}", scancode);
+        crate::thread::scheduler::irq_return();
         return;
     }
     if interrupt == 0x80 {
         // Syscall
+        if frame.iret.cs & 0b11 == 3 && frame.registers.ax == 0 {
+            // Exit, user mode has nothing to return to
+            crate::thread::exit();
+        }
         crate::println!("Test syscall\n{:#?}", frame);
         return;
     }
@@ -62,13 +79,15 @@ macro_rules! int {
     };
     ($name: ident($no: literal, m)) => {
         wrap_interrupt!($name, $no, wrap_interrupt!(no error code), concat!(
-            "out $0x20, $0x20\n"
+            "mov $0x20, %al\n",
+            "out %al, $0x20\n",
         ));
     };
     ($name: ident($no: literal, s)) => {
         wrap_interrupt!($name, $no, wrap_interrupt!(no error code), concat!(
-            "out $0x20, $0x20\n",
-            "out $0xa0, $0x20\n",
+            "mov $0x20, %al\n",
+            "out %al, $0x20\n",
+            "out %al, $0xa0\n",
         ));
     };
 }
@@ -104,14 +123,20 @@ pub(super) fn setup() {
     #[cfg(target_arch = "x86_64")]
     type Address = u64;
 
-    fn make_desc(offset: Address) -> Descriptor {
-        use x86::segmentation::{
-            BuildDescriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentSelector,
-        };
-        DescriptorBuilder::interrupt_descriptor(SegmentSelector::new(1, x86::Ring::Ring0), offset)
+    fn make_gate(offset: Address, dpl: x86::Ring) -> Descriptor {
+        use x86::segmentation::{BuildDescriptor, DescriptorBuilder, GateDescriptorBuilder};
+        DescriptorBuilder::interrupt_descriptor(super::gdt::KERNEL_CODE, offset)
             .present()
+            .dpl(dpl)
             .finish()
     }
+    fn make_desc(offset: Address) -> Descriptor {
+        make_gate(offset, x86::Ring::Ring0)
+    }
+    /// User mode can only raise interrupts with `int`, that have this
+    fn make_syscall_desc(offset: Address) -> Descriptor {
+        make_gate(offset, x86::Ring::Ring3)
+    }
 
     unsafe {
         IDT[0x00] = make_desc(int_0x00 as _);
@@ -242,7 +267,7 @@ pub(super) fn setup() {
         IDT[0x7d] = make_desc(int_0x7d as _);
         IDT[0x7e] = make_desc(int_0x7e as _);
         IDT[0x7f] = make_desc(int_0x7f as _);
-        IDT[0x80] = make_desc(int_0x80 as _);
+        IDT[0x80] = make_syscall_desc(int_0x80 as _);
         IDT[0x81] = make_desc(int_0x81 as _);
         IDT[0x82] = make_desc(int_0x82 as _);
         IDT[0x83] = make_desc(int_0x83 as _);
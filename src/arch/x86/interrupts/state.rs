@@ -33,6 +33,12 @@ pub(super) struct Registers {
     pub(super) r9: usize,
     #[cfg(target_arch = "x86_64")]
     pub(super) r8: usize,
+    /// Data segments of user mode, that are replaced by the kernel ones.
+    /// Long mode ignores them, so only 32 bit mode saves them
+    #[cfg(target_arch = "x86")]
+    pub(super) es: usize,
+    #[cfg(target_arch = "x86")]
+    pub(super) ds: usize,
     pub(super) bp: usize,
     pub(super) di: usize,
     pub(super) si: usize,
@@ -51,6 +57,7 @@ pub struct InterruptStackFrame {
     pub(super) iret: IRetFrame,
 }
 
+#[cfg(target_arch = "x86")]
 #[macro_export]
 macro_rules! wrap_interrupt {
     (error code) => {
@@ -67,7 +74,7 @@ macro_rules! wrap_interrupt {
             "xor %eax, %eax\n",
         )
     };
-    ($name: ident, $interrupt: literal, $error_code: expr, $postfix: expr) => {
+    ($name: ident, $interrupt: literal, $error_code: expr, $eoi: expr) => {
         #[unsafe(naked)]
         pub unsafe extern "C" fn $name() {
             core::arch::naked_asm!(
@@ -79,11 +86,23 @@ macro_rules! wrap_interrupt {
                     "push %esi\n",
                     "push %edi\n",
                     "push %ebp\n",
+                    "push %ds\n",
+                    "push %es\n",
+                    // Interrupt might come from user mode, with it's data segments loaded
+                    "mov ${kernel_data}, %ebx\n",
+                    "mov %ebx, %ds\n",
+                    "mov %ebx, %es\n",
                     "push %eax\n",
+                    // Acknowledge before the handler, it might switch to another thread
+                    $eoi,
                     "mov %esp, %edx\n",
-                    "mov $", $interrupt, ", %ecx\n",
+                    "push %edx\n",
+                    "push $", $interrupt, "\n",
                     "call {interrupt_handler}\n",
+                    "add $8, %esp\n",
                     "pop %eax\n",
+                    "pop %es\n",
+                    "pop %ds\n",
                     "pop %ebp\n",
                     "pop %edi\n",
                     "pop %esi\n",
@@ -94,6 +113,79 @@ macro_rules! wrap_interrupt {
                     "iret\n",
                 ),
                 interrupt_handler = sym interrupt_handler,
+                kernel_data = const $crate::arch::x86::gdt::KERNEL_DATA.bits(),
+                options(att_syntax),
+            );
+        }
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+#[macro_export]
+macro_rules! wrap_interrupt {
+    (error code) => {
+        concat!(
+            // Move rax into code's place, put code in last instead (to be
+            // compatible with InterruptStack)
+            "xchg (%rsp), %rax\n",
+        )
+    };
+    (no error code) => {
+        concat!(
+            // Clear rax so exit code is zero
+            "push %rax\n",
+            "xor %eax, %eax\n",
+        )
+    };
+    ($name: ident, $interrupt: literal, $error_code: expr, $eoi: expr) => {
+        #[unsafe(naked)]
+        pub unsafe extern "C" fn $name() {
+            core::arch::naked_asm!(
+                concat!(
+                    $error_code,
+                    "push %rbx\n",
+                    "push %rcx\n",
+                    "push %rdx\n",
+                    "push %rsi\n",
+                    "push %rdi\n",
+                    "push %rbp\n",
+                    "push %r8\n",
+                    "push %r9\n",
+                    "push %r10\n",
+                    "push %r11\n",
+                    "push %r12\n",
+                    "push %r13\n",
+                    "push %r14\n",
+                    "push %r15\n",
+                    "push %rax\n",
+                    // Acknowledge before the handler, it might switch to another thread
+                    $eoi,
+                    "mov %rsp, %rsi\n",
+                    "mov $", $interrupt, ", %edi\n",
+                    // CPU aligns the stack to 16 bytes before pushing the interrupt
+                    // frame, 21 qwords were pushed since then
+                    "sub $8, %rsp\n",
+                    "call {interrupt_handler}\n",
+                    "add $8, %rsp\n",
+                    "pop %rax\n",
+                    "pop %r15\n",
+                    "pop %r14\n",
+                    "pop %r13\n",
+                    "pop %r12\n",
+                    "pop %r11\n",
+                    "pop %r10\n",
+                    "pop %r9\n",
+                    "pop %r8\n",
+                    "pop %rbp\n",
+                    "pop %rdi\n",
+                    "pop %rsi\n",
+                    "pop %rdx\n",
+                    "pop %rcx\n",
+                    "pop %rbx\n",
+                    "pop %rax\n",
+                    "iretq\n",
+                ),
+                interrupt_handler = sym interrupt_handler,
                 options(att_syntax),
             );
         }
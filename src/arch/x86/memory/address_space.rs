@@ -9,6 +9,7 @@ mod entry {
 
 use crate::memory::address_space::nested_page_table::{NestedPageTable, NestedPageTableLevel};
 use crate::memory::address_space::AddressSpaceTrait;
+use crate::memory::{FaultError, FrameFlags, Vma, VmaError};
 use crate::memory::{MappingError, MappingResult, PageAllocatorTrait};
 
 /// Interface page table entry types
@@ -17,6 +18,11 @@ mod if_entry {
     pub(super) use crate::memory::MappingFlags;
 }
 
+/// Memory areas of every address space, by address of the top level page table
+static AREAS: crate::sync::IrqSafeRwLock<
+    alloc::collections::BTreeMap<PhysAddr, crate::memory::address_space::vma::VmaSet>,
+> = crate::sync::irq_safe_rw_lock(alloc::collections::BTreeMap::new());
+
 /// Address space struct
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct AddressSpace(PageTableLevel);
@@ -26,23 +32,158 @@ pub struct PageTableLevel(PhysAddr, usize);
 
 impl AddressSpace {
     pub(super) fn from_paddr(addr: PhysAddr) -> Self {
-        #[cfg(target_arch = "x86")]
-        let top_level_bits = 22;
-        #[cfg(target_arch = "x86_64")]
-        let top_level_bits = 39;
-        Self(PageTableLevel(addr, top_level_bits))
+        Self(PageTableLevel(addr, super::TOP_LEVEL_BITS))
+    }
+
+    /// Get the address space that is currently active on this CPU
+    pub fn current() -> Self {
+        let cr3 = unsafe { x86::controlregs::cr3() } as usize;
+        Self::from_paddr(PhysAddr::from_usize(cr3).align_down_4k())
+    }
+
+    /// Switch to this address space
+    pub fn activate(&self) {
+        unsafe {
+            x86::controlregs::cr3_write(self.0 .0.as_usize() as _);
+        }
+    }
+
+    /// Handle a fault on a page that is not present by
+    /// mapping it, if it belongs to a memory area
+    pub fn handle_fault(
+        &self,
+        vaddr: VirtAddr,
+        access: if_entry::MappingFlags,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> Result<(), FaultError> {
+        // Populating allocates memory and takes the page table lock, so
+        // it's done on a copy of the area, without holding the areas lock
+        let area = AREAS
+            .read()
+            .get(&self.0 .0)
+            .and_then(|areas| areas.find(vaddr))
+            .cloned()
+            .ok_or(FaultError::NoArea(vaddr))?;
+        if !area.allows(access) {
+            return Err(FaultError::AccessViolation(vaddr, access));
+        }
+        self.populate(&area, vaddr, alloc)?;
+        Ok(())
     }
 }
 
 impl PageTableLevel {
-    /// Map the page table level to tmp page
-    /// and get the page table entry associated with this address
-    fn lock_entry(&self, vaddr: VirtAddr) -> crate::sync::MappedLockGuard<entry::PTEntry> {
-        let page_table = tmp_page::map::<super::PageTable>(self.0);
+    /// Allocate and clear a new page table
+    fn new(bits: usize, alloc: &impl PageAllocatorTrait<PageSize>) -> Option<Self> {
+        let addr = alloc.alloc(PageSize::Size4K)?;
+        if let Some(frame) = crate::memory::frame::frames().and_then(|frames| frames.get(addr)) {
+            frame.insert_flags(FrameFlags::PAGE_TABLE);
+        }
+        let mut page_table = tmp_page::map::<super::PageTable>(addr);
+
+        // Clear the page table
+        for index in 0..super::PAGE_TABLE_ENTRIES {
+            page_table[index] = entry::PTEntry::NULL;
+        }
+
+        Some(PageTableLevel(addr, bits))
+    }
+
+    /// Map the page table level and get the page table entry associated with this address.
+    /// Page tables of the active address space are accessed through the recursive mapping
+    fn map_entry(&self, vaddr: VirtAddr) -> MappedEntry {
+        let page_table = match super::recursive::page_table(self.0, self.1, vaddr) {
+            Some(page_table) => MappedPageTable::Recursive(page_table),
+            None => MappedPageTable::Tmp(tmp_page::map(self.0)),
+        };
 
         let mask = super::PAGE_TABLE_ENTRIES - 1;
         let index = (vaddr.as_usize() >> self.1) & mask;
-        crate::sync::MappedLockGuard::map(page_table, |page_table| &mut page_table[index])
+        MappedEntry { page_table, index }
+    }
+
+    /// With PAE, page directories are never freed or replaced: an address space always
+    /// has all 4 of them, so that they can be mapped recursively
+    fn is_pdpt(&self) -> bool {
+        cfg!(feature = "pae") && self.1 == super::TOP_LEVEL_BITS
+    }
+
+    /// Whether this is the kernel table, and vaddr is in the kernel half
+    fn is_kernel_entry(&self, vaddr: VirtAddr) -> bool {
+        self.1 == super::recursive::KERNEL_TABLE_BITS
+            && vaddr.as_usize() >= super::KERNEL_HALF_START
+    }
+}
+
+/// Page table, that manages the kernel half of the address space
+/// with the top level page table `top_level`
+fn kernel_table(top_level: &PageTableLevel) -> MappingResult<PageTableLevel> {
+    // With PAE, kernel half is managed by the last page directory
+    #[cfg(not(feature = "pae"))]
+    return Ok(top_level.clone());
+    #[cfg(feature = "pae")]
+    {
+        let vaddr = VirtAddr::from_usize(super::KERNEL_HALF_START);
+        let if_entry::PageTableEntry::Level(kernel_table) = top_level.get_entry(vaddr)? else {
+            panic!("Kernel page directory is not present");
+        };
+        Ok(kernel_table)
+    }
+}
+
+/// Allocate every page table, that the kernel table points to in the kernel half.
+/// Address spaces share the kernel half by copying these entries, when they are
+/// made, so the entries must never change afterwards. Costs a page table
+/// for every entry: 1 MiB on x86 and x86_64, 2 MiB with PAE
+pub(super) fn preallocate_kernel_tables(
+    alloc: &impl PageAllocatorTrait<PageSize>,
+) -> MappingResult<()> {
+    let kernel_table = kernel_table(&AddressSpace::current().0)?;
+    let step = kernel_table.region_size();
+    for vaddr in (super::KERNEL_HALF_START..=usize::MAX).step_by(step) {
+        let vaddr = VirtAddr::from_usize(vaddr);
+        if super::recursive::is_recursive(vaddr.as_usize())
+            || kernel_table.get_entry(vaddr)?.mapped()
+        {
+            continue;
+        }
+        let table = kernel_table
+            .new_sublevel(alloc)
+            .ok_or(MappingError::PageAllocationFailed)?;
+        kernel_table.set_entry(vaddr, if_entry::PageTableEntry::Level(table))?;
+    }
+    Ok(())
+}
+
+/// Page table, mapped to be accessed by the kernel
+enum MappedPageTable {
+    Recursive(*mut super::PageTable),
+    Tmp(tmp_page::TmpPage<super::PageTable>),
+}
+
+/// Page table entry in a mapped page table
+struct MappedEntry {
+    page_table: MappedPageTable,
+    index: usize,
+}
+
+impl core::ops::Deref for MappedEntry {
+    type Target = entry::PTEntry;
+
+    fn deref(&self) -> &Self::Target {
+        match &self.page_table {
+            MappedPageTable::Recursive(page_table) => unsafe { &(**page_table)[self.index] },
+            MappedPageTable::Tmp(page_table) => &page_table[self.index],
+        }
+    }
+}
+
+impl core::ops::DerefMut for MappedEntry {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match &mut self.page_table {
+            MappedPageTable::Recursive(page_table) => unsafe { &mut (**page_table)[self.index] },
+            MappedPageTable::Tmp(page_table) => &mut page_table[self.index],
+        }
     }
 }
 
@@ -63,15 +204,11 @@ impl NestedPageTableLevel for PageTableLevel {
     }
 
     fn new_sublevel(&self, alloc: &impl PageAllocatorTrait<Self::PageSize>) -> Option<Self> {
-        let addr = alloc.alloc(PageSize::Size4K)?;
-        let mut page_table = tmp_page::map::<super::PageTable>(addr);
-
-        // Clear the page table
-        for index in 0..super::PAGE_TABLE_ENTRIES {
-            page_table[index] = entry::PTEntry::NULL;
-        }
+        PageTableLevel::new(self.1 - super::PAGE_LEVEL_BITS, alloc)
+    }
 
-        Some(PageTableLevel(addr, self.1 - super::PAGE_LEVEL_BITS))
+    fn keeps_sublevel(&self, vaddr: VirtAddr) -> bool {
+        self.is_kernel_entry(vaddr)
     }
 
     fn free_sublevel(
@@ -79,6 +216,14 @@ impl NestedPageTableLevel for PageTableLevel {
         sublevel: Self,
         alloc: &impl PageAllocatorTrait<Self::PageSize>,
     ) -> MappingResult<()> {
+        if self.is_pdpt() {
+            return Ok(());
+        }
+        if let Some(frame) =
+            crate::memory::frame::frames().and_then(|frames| frames.get(sublevel.0))
+        {
+            frame.remove_flags(FrameFlags::PAGE_TABLE);
+        }
         alloc.free(sublevel.0, PageSize::Size4K);
         Ok(())
     }
@@ -92,27 +237,51 @@ impl NestedPageTableLevel for PageTableLevel {
             debug_assert!(vaddr.is_aligned(1usize << self.1));
         }
 
-        let mut entry = self.lock_entry(vaddr);
-        if self.1 > 12 && entry.flags().contains(entry::PTEFlags::PS) {
+        if self.is_pdpt() && !new_entry.mapped() {
+            return Ok(());
+        }
+
+        let mut entry = self.map_entry(vaddr);
+        if self.1 > 12
+            && matches!(new_entry, if_entry::PageTableEntry::Level(_))
+            && entry
+                .flags()
+                .contains(entry::PTEFlags::P | entry::PTEFlags::PS)
+        {
             return Err(MappingError::MappingOver(entry.address()));
         }
 
         *entry = match new_entry {
+            #[cfg(feature = "pae")]
+            if_entry::PageTableEntry::Level(level) if self.is_pdpt() => {
+                entry::PTEntry::new_pdpt_entry(level.0)
+            }
             if_entry::PageTableEntry::Level(level) => entry::PTEntry::new_page_table(level.0),
             if_entry::PageTableEntry::Page(paddr, flags) => {
                 entry::PTEntry::new_page(paddr, self.page_size().unwrap(), flags.into())
             }
         };
 
+        // Page directory pointers are only loaded along with CR3
+        if self.is_pdpt() && AddressSpace::current().0 == *self {
+            unsafe {
+                x86::controlregs::cr3_write(self.0.as_usize() as _);
+            }
+        }
+
         // TODO: Check if this page table is currently active
         unsafe {
             x86::tlb::flush(vaddr.as_usize());
         }
+        if self.1 > 12 {
+            // Sublevel is mapped through the recursive mapping
+            super::recursive::flush(vaddr, self.1);
+        }
         Ok(())
     }
 
     fn get_entry(&self, vaddr: VirtAddr) -> MappingResult<if_entry::PageTableEntry<Self>> {
-        let entry = self.lock_entry(vaddr);
+        let entry = self.map_entry(vaddr);
         if entry.flags().contains(entry::PTEFlags::P)
             && self.1 > 12
             && !entry.flags().contains(entry::PTEFlags::PS)
@@ -128,6 +297,19 @@ impl NestedPageTableLevel for PageTableLevel {
             ))
         }
     }
+
+    fn write_page(
+        &self,
+        paddr: PhysAddr,
+        page_size: Self::PageSize,
+        mut write: impl FnMut(usize, &mut [u8]),
+    ) {
+        // TMP page is only 4K, so write large pages in parts
+        for offset in (0..page_size.into()).step_by(PageSize::Size4K.into()) {
+            let mut page = tmp_page::map::<[u8; memory_addr::PAGE_SIZE_4K]>(paddr + offset);
+            write(offset, page.as_mut_slice());
+        }
+    }
 }
 
 impl AddressSpaceTrait<PageSize> for AddressSpace {
@@ -149,4 +331,69 @@ impl AddressSpaceTrait<PageSize> for AddressSpace {
     ) -> MappingResult<()> {
         <Self as NestedPageTable>::unmap_free(self, vaddr, size, alloc)
     }
+
+    fn reserve(&self, area: Vma) -> Result<(), VmaError> {
+        AREAS.write().entry(self.0 .0).or_default().insert(area)
+    }
+
+    fn release(
+        &self,
+        vaddr: VirtAddr,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> Result<(), VmaError> {
+        let area = AREAS
+            .write()
+            .get_mut(&self.0 .0)
+            .ok_or(VmaError::NotFound(vaddr))?
+            .remove(vaddr)?;
+        <Self as NestedPageTable>::unmap_free(self, area.start, area.size, alloc)?;
+        Ok(())
+    }
+
+    fn fork(&self, alloc: &impl PageAllocatorTrait<PageSize>) -> MappingResult<Self> {
+        let top_level = self.top_level();
+        let child =
+            PageTableLevel::new(top_level.1, alloc).ok_or(MappingError::PageAllocationFailed)?;
+
+        self.fork_into(&child, VirtAddr::from_usize(0), super::USER_END, alloc)?;
+
+        let kernel_table = kernel_table(&top_level)?;
+        #[cfg(not(feature = "pae"))]
+        let child_kernel_table = child.clone();
+        #[cfg(feature = "pae")]
+        let child_kernel_table = {
+            let child_kernel_table = child
+                .new_sublevel(alloc)
+                .ok_or(MappingError::PageAllocationFailed)?;
+            child.set_entry(
+                VirtAddr::from_usize(super::KERNEL_HALF_START),
+                if_entry::PageTableEntry::Level(child_kernel_table.clone()),
+            )?;
+            child_kernel_table
+        };
+
+        // Kernel half is shared between all address spaces. Every page table
+        // of it was allocated at boot, so the entries stay the same forever
+        let step = kernel_table.region_size();
+        for vaddr in (super::KERNEL_HALF_START..=usize::MAX).step_by(step) {
+            if super::recursive::is_recursive(vaddr) {
+                continue;
+            }
+            let vaddr = VirtAddr::from_usize(vaddr);
+            let entry = kernel_table.get_entry(vaddr)?;
+            if entry.mapped() {
+                child_kernel_table.set_entry(vaddr, entry)?;
+            }
+        }
+        super::recursive::install(
+            &mut tmp_page::map(child_kernel_table.0),
+            super::recursive::directories(child.0),
+        );
+
+        let mut areas = AREAS.write();
+        if let Some(parent_areas) = areas.get(&top_level.0).cloned() {
+            areas.insert(child.0, parent_areas);
+        }
+        Ok(Self(child))
+    }
 }
@@ -1,9 +1,19 @@
-use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+use crate::memory::boot_memory_map::{BootMemoryMap, BootMemoryMapError, RegionKind};
+use crate::sync::{mutex, Mutex};
+use memory_addr::{pa, MemoryAddr, PhysAddr, VirtAddr};
 
-/// Temproary page, space for it is allocated after the kernel in the kernel address space.
-/// Used to map page tables and manipulate their entries
+/// Per-CPU temproary pages, mapped at the top of the kernel address space.
+/// Used to access physical memory, that is not mapped anywhere
 mod tmp_page;
 
+/// Recursive mapping of the top level page table. Used to access
+/// page tables of the active address space without any locks
+mod recursive;
+
+/// SMEP/SMAP and copying to and from user memory
+mod user;
+pub(super) use user::exception_fixup;
+
 mod page_size;
 pub use page_size::PageSize;
 
@@ -21,38 +31,111 @@ pub type PageAllocator = crate::memory::page_allocator::ZonedBuddy<0x1000>;
 extern "C" {
     #[link_name = "kernel_top_level_page_table"]
     static mut KERNEL_TOP_LEVEL_PAGE_TABLE: PageTable;
+    #[cfg(feature = "pae")]
+    #[link_name = "kernel_page_directories"]
+    static mut KERNEL_PAGE_DIRECTORIES: [PageTable; 4];
+    /// Page directory, that maps the last 2 GiB of the address space
+    #[cfg(target_arch = "x86_64")]
+    #[link_name = "kernel_page_directory"]
+    static mut KERNEL_PAGE_DIRECTORY: PageTable;
 }
 
 linker_symbol! {
     kernel_offset(KERNEL_OFFSET_SYMBOL) => "KERNEL_OFFSET";
+    kernel_start(KERNEL_START) => "kernel_start";
+    rodata_start(RODATA_START) => "rodata_start";
+    data_start(DATA_START) => "data_start";
     kernel_end(KERNEL_END) => "kernel_end";
     kernel_reserved_end(KERNEL_RESERVED_END) => "kernel_reserved_end";
 }
 
-/// Convert a physical address in the kernel address space to virtual by adding the offset
-fn kernel_phys2virt(paddr: PhysAddr) -> VirtAddr {
-    VirtAddr::from_usize(paddr.as_usize() + kernel_offset().as_usize())
-}
-
 /// Convert a virtual address in the kernel address space to physical by subtracting the offset
 fn kernel_virt2phys(vaddr: VirtAddr) -> PhysAddr {
     PhysAddr::from_usize(vaddr.as_usize() - kernel_offset().as_usize())
 }
 
-#[cfg(target_arch = "x86")]
+#[cfg(all(target_arch = "x86", not(feature = "pae")))]
 /// Number of bits each table takes off the vitual address
 const PAGE_LEVEL_BITS: usize = 10;
-#[cfg(target_arch = "x86_64")]
+#[cfg(any(target_arch = "x86_64", feature = "pae"))]
 /// Number of bits each table takes off the vitual address
 const PAGE_LEVEL_BITS: usize = 9;
 
+#[cfg(all(target_arch = "x86", not(feature = "pae")))]
+/// Number of bits of the virtual address, that an entry of the top level page table covers
+const TOP_LEVEL_BITS: usize = 22;
+#[cfg(all(target_arch = "x86", feature = "pae"))]
+/// Number of bits of the virtual address, that an entry of the top level page table covers.
+/// With PAE, top level is a page directory pointer table with just 4 entries
+const TOP_LEVEL_BITS: usize = 30;
+#[cfg(target_arch = "x86_64")]
+/// Number of bits of the virtual address, that an entry of the top level page table covers
+const TOP_LEVEL_BITS: usize = 39;
+
 /// Number of page table entries in a page table
 const PAGE_TABLE_ENTRIES: usize = 1 << PAGE_LEVEL_BITS;
 
+#[cfg(target_arch = "x86")]
+/// End of the user half of the address space
+const USER_END: usize = 0xc0000000;
+#[cfg(target_arch = "x86_64")]
+/// End of the user half of the address space, lower canonical addresses
+const USER_END: usize = 0x0000_8000_0000_0000;
+
+#[cfg(target_arch = "x86")]
+/// Start of the kernel half of the address space, shared between all address spaces
+const KERNEL_HALF_START: usize = USER_END;
+#[cfg(target_arch = "x86_64")]
+/// Start of the kernel half of the address space, shared between all address spaces
+const KERNEL_HALF_START: usize = 0xffff_8000_0000_0000;
+
+#[cfg(target_arch = "x86")]
+/// Virtual memory range reserved for the kernel heap. Free kernel
+/// memory between the kernel and the heap is used by vmalloc
+pub(super) const KERNEL_HEAP_START: usize = 0xd0000000;
+#[cfg(target_arch = "x86_64")]
+/// Virtual memory range reserved for the kernel heap. Free kernel
+/// memory between the kernel and the heap is used by vmalloc.
+/// Everything is in the last 2 GiB, so that it's shared through one top level entry
+pub(super) const KERNEL_HEAP_START: usize = 0xffff_ffff_a000_0000;
+pub(super) const KERNEL_HEAP_SIZE: usize = 0x10000000;
+#[cfg(target_arch = "x86")]
+/// Virtual memory range, where kernel pages of low physical memory are mapped
+/// on demand, at a fixed offset from their physical address, right after the heap
+const LOW_MEMORY_MAP_START: usize = KERNEL_HEAP_START + KERNEL_HEAP_SIZE;
+#[cfg(target_arch = "x86_64")]
+/// Virtual memory range, where kernel pages of low physical memory are mapped
+/// on demand, at a fixed offset from their physical address, after the temporary pages
+const LOW_MEMORY_MAP_START: usize = 0xffff_ffff_c000_0000;
+const LOW_MEMORY_MAP_SIZE: usize = 0x10000000;
+/// Virtual memory range for the page frame database, right below the heap.
+/// It is mapped with large pages in the page tables, that the bootstrap has set up
+const FRAME_DATABASE_START: usize = KERNEL_HEAP_START - FRAME_DATABASE_SIZE;
+#[cfg(target_arch = "x86")]
+/// Enough for descriptors of 4 GiB of memory
+const FRAME_DATABASE_SIZE: usize = 0x1000000;
+#[cfg(target_arch = "x86_64")]
+/// Enough for descriptors of 64 GiB of memory
+const FRAME_DATABASE_SIZE: usize = 0x10000000;
+
 /// Page table type
 type PageTable = [PTEntry; PAGE_TABLE_ENTRIES];
 
-static PAGE_ALLOCATOR: PageAllocator = PageAllocator::new();
+/// End of low memory, that the bootstrap identity maps
+const IDENTITY_MAP_END: usize = 0x800000;
+
+#[cfg(target_arch = "x86")]
+/// Physical memory that doesn't fit into the kernel half of the address space
+/// along with the heap and vmalloc ranges. Same limit as linux has
+const HIGH_MEMORY_START: usize = 0x38000000;
+#[cfg(target_arch = "x86_64")]
+/// All physical memory is within the kernel's reach
+const HIGH_MEMORY_START: usize = usize::MAX;
+
+static PAGE_ALLOCATOR: PageAllocator = PageAllocator::new().with_high_memory(HIGH_MEMORY_START);
+
+/// Physical memory map, built from the map provided by the bootloader
+static BOOT_MEMORY_MAP: Mutex<BootMemoryMap> = mutex(BootMemoryMap::new());
 
 pub struct Memory;
 impl crate::arch::MemoryTrait for Memory {
@@ -69,33 +152,352 @@ impl crate::arch::MemoryTrait for Memory {
             VirtAddr::from_usize(&raw const KERNEL_TOP_LEVEL_PAGE_TABLE as _);
         AddressSpace::from_paddr(kernel_virt2phys(kernel_address_space))
     }
+
+    fn vmalloc_range() -> (VirtAddr, VirtAddr) {
+        (
+            kernel_reserved_end().align_up_4k(),
+            VirtAddr::from_usize(FRAME_DATABASE_START),
+        )
+    }
+
+    fn alloc_kernel_page() -> Option<VirtAddr> {
+        use crate::memory::address_space::nested_page_table::NestedPageTable as _;
+        use crate::memory::{AllocConstraints, MappingFlags};
+
+        let constraints = AllocConstraints::new().with_max_addr(LOW_MEMORY_MAP_SIZE);
+        let paddr = PAGE_ALLOCATOR.alloc_block(PageSize::Size4K.into(), &constraints)?;
+        let vaddr = VirtAddr::from_usize(LOW_MEMORY_MAP_START + paddr.as_usize());
+        Self::kernel_address_space()
+            .map(
+                vaddr,
+                paddr,
+                MappingFlags::PRESENT | MappingFlags::READ | MappingFlags::WRITE,
+                &PAGE_ALLOCATOR,
+            )
+            .inspect_err(|_| PAGE_ALLOCATOR.free(paddr, PageSize::Size4K.into()))
+            .ok()?;
+        Some(vaddr)
+    }
+
+    fn free_kernel_page(page: VirtAddr) {
+        use crate::memory::AddressSpaceTrait as _;
+
+        Self::kernel_address_space()
+            .unmap_free(page, PageSize::Size4K.into(), &PAGE_ALLOCATOR)
+            .expect("Failed to free a kernel page");
+    }
+
+    fn heap_stats() -> crate::memory::HeapStats {
+        super::allocator::stats()
+    }
+
+    fn memory_stats() -> crate::memory::MemoryStats {
+        let page_tables = crate::memory::frame::frames().map_or(0, |frames| {
+            frames.count(crate::memory::FrameFlags::PAGE_TABLE) * usize::from(PageSize::Size4K)
+        });
+        let map = BOOT_MEMORY_MAP.lock();
+        crate::memory::MemoryStats {
+            zones: PAGE_ALLOCATOR.zone_stats(),
+            page_tables,
+            heap: Self::heap_stats(),
+            slab: crate::memory::slab::stats(),
+            reserved: RegionKind::ALL
+                .into_iter()
+                .filter(|&kind| kind != RegionKind::Free)
+                .map(|kind| (kind, map.total(kind)))
+                .filter(|&(_, size)| size > 0)
+                .collect(),
+        }
+    }
+
+    fn boot_memory_map() -> BootMemoryMap {
+        BOOT_MEMORY_MAP.lock().clone()
+    }
+
+    fn release_acpi_memory() {
+        let mut map = BOOT_MEMORY_MAP.lock();
+        for region in map.regions_of(RegionKind::AcpiReclaimable) {
+            if let Some(frames) = crate::memory::frame::frames() {
+                frames.unreserve(region.start, region.size());
+            }
+            add_zone(region.start, region.end);
+        }
+        map.convert(RegionKind::AcpiReclaimable, RegionKind::Free);
+    }
+
+    fn user_range() -> (VirtAddr, VirtAddr) {
+        (VirtAddr::from_usize(0), VirtAddr::from_usize(USER_END))
+    }
+
+    unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+        unsafe { user::copy_user(dst, src, len) }
+    }
 }
 
 /// Setup paging
 pub(super) fn setup_paging(boot_info: &multiboot2::BootInformation) {
+    #[cfg(any(target_arch = "x86_64", feature = "pae"))]
+    page_table_entry::enable_nx();
+
+    // Page tables are accessed through these, so set them up first
+    unsafe {
+        let top_level = &raw mut KERNEL_TOP_LEVEL_PAGE_TABLE;
+        let paddr = kernel_virt2phys(VirtAddr::from_usize(top_level as _));
+        #[cfg(not(feature = "pae"))]
+        let kernel_table = &mut *top_level;
+        // Page directory pointer table has only 4 entries, so
+        // the kernel half is managed by the last page directory
+        #[cfg(feature = "pae")]
+        let kernel_table = {
+            let directories = &raw mut KERNEL_PAGE_DIRECTORIES;
+            &mut (*directories)[3]
+        };
+        #[cfg(target_arch = "x86")]
+        tmp_page::setup(kernel_table);
+        // Temporary pages are mapped by the page directory of the kernel image
+        #[cfg(target_arch = "x86_64")]
+        {
+            let directory = &raw mut KERNEL_PAGE_DIRECTORY;
+            tmp_page::setup(&mut *directory);
+        }
+        recursive::install(kernel_table, recursive::directories(paddr));
+    }
+
+    // Make read-only pages read-only for the kernel too, so that
+    // it doesn't write to copy-on-write pages
+    unsafe {
+        use x86::controlregs::{cr0, cr0_write, Cr0};
+        cr0_write(cr0() | Cr0::CR0_WRITE_PROTECT);
+    }
+    user::setup();
+
+    let mut map = BOOT_MEMORY_MAP.lock();
+    *map = boot_memory_map(boot_info).expect("Failed to build boot memory map");
+    setup_frame_database(&mut map).expect("Failed to set up the frame database");
+    crate::println!("Boot memory map:\n{}", *map);
+
     // Add zones to the page allocator
+    for region in map.regions_of(RegionKind::Free) {
+        add_zone(region.start, region.end);
+    }
+    address_space::preallocate_kernel_tables(<Memory as crate::arch::MemoryTrait>::page_allocator())
+        .expect("Failed to allocate kernel page tables");
+
+    protect_kernel().expect("Failed to protect kernel sections");
+    unmap_identity().expect("Failed to unmap the identity mapping");
+}
+
+/// Remap the kernel image with the permissions each section needs:
+/// only code is executable and only data is writable
+fn protect_kernel() -> crate::memory::MappingResult<()> {
+    use crate::memory::address_space::nested_page_table::NestedPageTable as _;
+    use crate::memory::MappingFlags;
+
+    let address_space = <Memory as crate::arch::MemoryTrait>::kernel_address_space();
+    let sections = [
+        // Low memory, that the bootstrap maps into the higher half (VGA buffer)
+        (
+            kernel_offset(),
+            kernel_start(),
+            MappingFlags::READ | MappingFlags::WRITE,
+        ),
+        (
+            kernel_start(),
+            rodata_start(),
+            MappingFlags::READ | MappingFlags::EXECUTE,
+        ),
+        (rodata_start(), data_start(), MappingFlags::READ),
+        (
+            data_start(),
+            kernel_end().align_up_4k(),
+            MappingFlags::READ | MappingFlags::WRITE,
+        ),
+    ];
+    for (start, end, flags) in sections {
+        address_space.protect(start, end - start, flags | MappingFlags::GLOBAL)?;
+    }
+    Ok(())
+}
+
+/// Unmap the identity mapping of low memory, along with the bootstrap code.
+/// Page tables, that the bootstrap used for it, are static and aren't freed
+fn unmap_identity() -> crate::memory::MappingResult<()> {
+    use crate::memory::address_space::nested_page_table::{
+        NestedPageTable as _, NestedPageTableLevel as _, PageTableEntry,
+    };
+    use crate::memory::MappingFlags;
+
+    // Find the page table, that maps the identity mapping
+    let address_space = <Memory as crate::arch::MemoryTrait>::kernel_address_space();
+    let mut level = address_space.top_level();
+    while level.region_size() > IDENTITY_MAP_END {
+        match level.get_entry(VirtAddr::from_usize(0))? {
+            PageTableEntry::Level(sublevel) => level = sublevel,
+            PageTableEntry::Page(_, _) => return Ok(()),
+        }
+    }
+
+    for vaddr in (0..IDENTITY_MAP_END).step_by(level.region_size()) {
+        let unmapped = PageTableEntry::Page(PhysAddr::from_usize(0), MappingFlags::empty());
+        level.set_entry(VirtAddr::from_usize(vaddr), unmapped)?;
+    }
+    // Only the first page of each entry was flushed
+    address_space.activate();
+    Ok(())
+}
+
+/// Build a sanitized memory map out of multiboot2 memory areas
+/// and memory used by the kernel and the bootloader
+fn boot_memory_map(
+    boot_info: &multiboot2::BootInformation,
+) -> Result<BootMemoryMap, BootMemoryMapError> {
+    use multiboot2::MemoryAreaType;
+
+    let mut map = BootMemoryMap::new();
     let memory_map_tag = boot_info
         .memory_map_tag()
         .expect("Memory map not available");
-    for region in memory_map_tag.memory_areas() {
-        use multiboot2::MemoryAreaType;
-        let typ = MemoryAreaType::from(region.typ());
-        if typ == MemoryAreaType::Available {
-            let kernel_physical_end = kernel_virt2phys(kernel_end());
-            let start = PhysAddr::from_usize(region.start_address() as _);
-            let start = start.max(kernel_physical_end).align_up_4k();
-            let end = PhysAddr::from_usize(region.end_address() as _);
-            if end <= start {
-                continue;
-            }
+    for area in memory_map_tag.memory_areas() {
+        let kind = match MemoryAreaType::from(area.typ()) {
+            MemoryAreaType::Available => RegionKind::Free,
+            MemoryAreaType::AcpiAvailable => RegionKind::AcpiReclaimable,
+            _ => RegionKind::Reserved,
+        };
+        // Physical addresses are usize, so on x86 memory above 4 GiB is
+        // skipped, even with PAE. Page tables could map it, but the
+        // boot memory map and the frame database can't describe it
+        let Ok(start) = usize::try_from(area.start_address()) else {
+            continue;
+        };
+        let end = usize::try_from(area.end_address()).unwrap_or(usize::MAX);
+        map.add(PhysAddr::from_usize(start), PhysAddr::from_usize(end), kind)?;
+    }
 
-            if PAGE_ALLOCATOR
-                .add_zone(start.as_usize(), memory_addr::align_down_4k(end - start))
-                .is_err()
-            {
-                crate::println!("Failed to add memory zone {:#x} to {:#x}", start, end);
-            }
-        }
+    // Real mode IVT, BIOS data area, EBDA, VGA memory and BIOS ROM
+    map.add(pa!(0), pa!(0x100000), RegionKind::Reserved)?;
+    map.add(
+        pa!(0x100000),
+        kernel_virt2phys(kernel_end()),
+        RegionKind::Kernel,
+    )?;
+    // Boot information is accessed through the identity mapping,
+    // so it's addresses are physical
+    map.add(
+        PhysAddr::from_usize(boot_info.start_address()),
+        PhysAddr::from_usize(boot_info.end_address()),
+        RegionKind::BootInfo,
+    )?;
+    for module in boot_info.module_tags() {
+        map.add(
+            PhysAddr::from_usize(module.start_address() as _),
+            PhysAddr::from_usize(module.end_address() as _),
+            RegionKind::Module,
+        )?;
+    }
+
+    map.sanitize()?;
+    Ok(map)
+}
+
+/// Allocate the page frame database from free memory in the map, and map it.
+/// It is set up before the page allocator has any memory, so that every
+/// allocation is tracked in it
+fn setup_frame_database(map: &mut BootMemoryMap) -> Result<(), BootMemoryMapError> {
+    use crate::memory::address_space::nested_page_table::{
+        NestedPageTable as _, NestedPageTableLevel as _,
+    };
+    use crate::memory::{FrameDatabase, MappingFlags};
+
+    let size = FrameDatabase::size(map.memory_start(), map.memory_end()).align_up_4k();
+    assert!(size <= FRAME_DATABASE_SIZE - usize::from(PageSize::LARGE));
+    let start = map
+        .regions_of(RegionKind::Free)
+        .map(|region| (region.start.align_up_4k(), region.end))
+        .find(|&(start, end)| end > start && end - start >= size)
+        .map(|(start, _)| start)
+        .expect("Not enough memory for the frame database");
+    map.add(start, start + size, RegionKind::FrameDatabase)?;
+    map.sanitize()?;
+
+    // Large pages are mapped in the page tables, that the
+    // bootstrap has set up, no page tables have to be allocated
+    let large_page = usize::from(PageSize::LARGE);
+    let paddr = start.align_down(large_page);
+    let top_level = <Memory as crate::arch::MemoryTrait>::kernel_address_space().top_level();
+    for offset in (0..(start + size).align_up(large_page) - paddr).step_by(large_page) {
+        top_level
+            .map_page(
+                VirtAddr::from_usize(FRAME_DATABASE_START + offset),
+                paddr + offset,
+                PageSize::LARGE,
+                MappingFlags::PRESENT | MappingFlags::READ | MappingFlags::WRITE,
+                &PAGE_ALLOCATOR,
+            )
+            .expect("Failed to map the frame database");
+    }
+
+    let frames = unsafe {
+        crate::memory::frame::init(
+            VirtAddr::from_usize(FRAME_DATABASE_START + (start - paddr)),
+            map,
+        )
+    };
+    crate::println!(
+        "Frame database: {} frames at {:#x}",
+        frames.frames().len(),
+        start
+    );
+    Ok(())
+}
+
+/// Add a free physical memory range to the page allocator
+fn add_zone(start: PhysAddr, end: PhysAddr) {
+    let start = start.align_up_4k();
+    let end = end.align_down_4k();
+    if end <= start {
+        return;
+    }
+
+    if PAGE_ALLOCATOR
+        .add_zone(start.as_usize(), end - start)
+        .is_err()
+    {
+        crate::println!("Failed to add memory zone {:#x} to {:#x}", start, end);
+    }
+}
+
+/// Try to resolve a page fault: copy a copy-on-write
+/// page or map a page of a memory area
+pub(super) fn handle_page_fault(
+    vaddr: VirtAddr,
+    error_code: usize,
+) -> Result<(), crate::memory::FaultError> {
+    use crate::memory::address_space::nested_page_table::NestedPageTable as _;
+    use crate::memory::{FaultError, MappingFlags};
+
+    const PRESENT: usize = 1 << 0;
+    const WRITE: usize = 1 << 1;
+    const USER: usize = 1 << 2;
+    const INSTRUCTION_FETCH: usize = 1 << 4;
+
+    let mut access = MappingFlags::READ;
+    if error_code & WRITE != 0 {
+        access |= MappingFlags::WRITE;
+    }
+    if error_code & USER != 0 {
+        access |= MappingFlags::USER;
+    }
+    if error_code & INSTRUCTION_FETCH != 0 {
+        access |= MappingFlags::EXECUTE;
+    }
+
+    let address_space = AddressSpace::current();
+    if error_code & PRESENT == 0 {
+        address_space.handle_fault(vaddr, access, &PAGE_ALLOCATOR)
+    } else if error_code & WRITE != 0 && address_space.resolve_cow(vaddr, &PAGE_ALLOCATOR)? {
+        Ok(())
+    } else {
+        Err(FaultError::AccessViolation(vaddr, access))
     }
 }
 
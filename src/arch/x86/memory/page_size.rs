@@ -4,23 +4,32 @@
 pub enum PageSize {
     #[default]
     Size4K = 0x1000,
-    #[cfg(target_arch = "x86")]
+    #[cfg(all(target_arch = "x86", not(feature = "pae")))]
     Size4M = 0x400000,
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(any(target_arch = "x86_64", feature = "pae"))]
     Size2M = 0x200000,
     #[cfg(target_arch = "x86_64")]
     Size1G = 0x40000000,
 }
 
+impl PageSize {
+    /// Large page, mapped by an entry of the page table right above the last level
+    #[cfg(all(target_arch = "x86", not(feature = "pae")))]
+    pub const LARGE: Self = Self::Size4M;
+    /// Large page, mapped by an entry of the page table right above the last level
+    #[cfg(any(target_arch = "x86_64", feature = "pae"))]
+    pub const LARGE: Self = Self::Size2M;
+}
+
 impl TryFrom<usize> for PageSize {
     type Error = ();
 
     fn try_from(size: usize) -> Result<Self, Self::Error> {
         match size {
             0x1000 => Ok(Self::Size4K),
-            #[cfg(target_arch = "x86")]
+            #[cfg(all(target_arch = "x86", not(feature = "pae")))]
             0x400000 => Ok(Self::Size4M),
-            #[cfg(target_arch = "x86_64")]
+            #[cfg(any(target_arch = "x86_64", feature = "pae"))]
             0x200000 => Ok(Self::Size2M),
             #[cfg(target_arch = "x86_64")]
             0x40000000 => Ok(Self::Size1G),
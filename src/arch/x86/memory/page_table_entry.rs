@@ -1,11 +1,26 @@
 use super::*;
 use crate::memory::MappingFlags;
 
+#[cfg(not(feature = "pae"))]
+/// Raw page table entry, entries are pointer-sized without PAE
+type RawEntry = usize;
+#[cfg(feature = "pae")]
+/// Raw page table entry, entries are always 64-bit with PAE
+type RawEntry = u64;
+
+/// Bits of a page table entry, that hold the physical address
+#[cfg(all(target_arch = "x86", not(feature = "pae")))]
+const ADDRESS_MASK: RawEntry = !0xfff;
+/// Bits of a page table entry, that hold the physical address.
+/// High bits are available to software or hold the NX bit
+#[cfg(any(target_arch = "x86_64", feature = "pae"))]
+const ADDRESS_MASK: RawEntry = 0x000f_ffff_ffff_f000;
+
 bitflags::bitflags! {
     /// Page table entry flags (first byte from the right)
     #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
     #[repr(transparent)]
-    pub(super) struct PTEFlags: usize {
+    pub(super) struct PTEFlags: RawEntry {
         /// Present
         const P       = 1 << 0;
         /// Read/write; if 0, writes may not be allowed
@@ -20,6 +35,39 @@ bitflags::bitflags! {
         const PS      = 1 << 7;
         /// Global; if set, invulnerable to TLB invalidation
         const G       = 1 << 8;
+        /// Copy-on-write (available to software); the page is shared read-only
+        const COW     = 1 << 9;
+        /// No execute; if set, instruction fetches are not allowed. Only valid if NX is enabled
+        #[cfg(any(target_arch = "x86_64", feature = "pae"))]
+        const NX      = 1 << 63;
+    }
+}
+
+/// Whether the CPU supports the NX bit and it was enabled
+#[cfg(any(target_arch = "x86_64", feature = "pae"))]
+static NX_ENABLED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Enable the NX bit in page table entries, if the CPU supports it.
+/// Otherwise all mapped pages stay executable
+#[cfg(any(target_arch = "x86_64", feature = "pae"))]
+pub(super) fn enable_nx() {
+    const EFER_NXE: u64 = 1 << 11;
+    const EXTENDED_FEATURES: u32 = 0x8000_0001;
+    const EXECUTE_DISABLE: u32 = 1 << 20;
+
+    // raw-cpuid needs SSE to query the CPU on x86, so use CPUID directly
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::__cpuid;
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::__cpuid;
+    let supported = __cpuid(EXTENDED_FEATURES - 1).eax >= EXTENDED_FEATURES
+        && __cpuid(EXTENDED_FEATURES).edx & EXECUTE_DISABLE != 0;
+    if supported {
+        unsafe {
+            use x86::msr::{rdmsr, wrmsr, IA32_EFER};
+            wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_NXE);
+        }
+        NX_ENABLED.store(true, core::sync::atomic::Ordering::SeqCst);
     }
 }
 
@@ -32,9 +80,11 @@ impl From<MappingFlags> for PTEFlags {
         if value.contains(MappingFlags::WRITE) {
             flags |= Self::RW;
         }
-        #[cfg(target_arch = "x86_64")]
-        if value.contains(MappingFlags::EXECUTE) {
-            todo!()
+        #[cfg(any(target_arch = "x86_64", feature = "pae"))]
+        if !value.contains(MappingFlags::EXECUTE)
+            && NX_ENABLED.load(core::sync::atomic::Ordering::SeqCst)
+        {
+            flags |= Self::NX;
         }
         if value.contains(MappingFlags::USER) {
             flags |= Self::US;
@@ -45,6 +95,9 @@ impl From<MappingFlags> for PTEFlags {
         if value.contains(MappingFlags::GLOBAL) {
             flags |= Self::G;
         }
+        if value.contains(MappingFlags::COPY_ON_WRITE) {
+            flags |= Self::COW;
+        }
         flags
     }
 }
@@ -58,8 +111,8 @@ impl From<PTEFlags> for MappingFlags {
         if value.contains(PTEFlags::RW) {
             flags |= Self::WRITE;
         }
-        #[cfg(target_arch = "x86_64")]
-        if value.contains(todo!()) {
+        #[cfg(any(target_arch = "x86_64", feature = "pae"))]
+        if !value.contains(PTEFlags::NX) {
             flags |= Self::EXECUTE;
         }
         if value.contains(PTEFlags::US) {
@@ -71,6 +124,9 @@ impl From<PTEFlags> for MappingFlags {
         if value.contains(PTEFlags::G) {
             flags |= Self::GLOBAL;
         }
+        if value.contains(PTEFlags::COW) {
+            flags |= Self::COPY_ON_WRITE;
+        }
         flags
     }
 }
@@ -78,7 +134,7 @@ impl From<PTEFlags> for MappingFlags {
 /// Page table entry
 #[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
-pub(super) struct PTEntry(usize);
+pub(super) struct PTEntry(RawEntry);
 
 impl core::fmt::Debug for PTEntry {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -93,20 +149,33 @@ impl PTEntry {
     pub(super) fn new_page(addr: PhysAddr, page_size: PageSize, flags: PTEFlags) -> Self {
         let mut flags = flags;
         match page_size {
-            #[cfg(target_arch = "x86")]
+            #[cfg(all(target_arch = "x86", not(feature = "pae")))]
             PageSize::Size4M => flags |= PTEFlags::PS,
-            #[cfg(target_arch = "x86_64")]
+            #[cfg(any(target_arch = "x86_64", feature = "pae"))]
             PageSize::Size2M => flags |= PTEFlags::PS,
             #[cfg(target_arch = "x86_64")]
             PageSize::Size1G => flags |= PTEFlags::PS,
             _ => (),
         }
-        Self(addr.as_usize() | flags.bits())
+        Self(addr.as_usize() as RawEntry | flags.bits())
     }
 
-    /// Create a new entry associated with a page table
+    /// Create a new entry associated with a page table. Access
+    /// rights are controlled by the entries of the page table itself
     pub(super) fn new_page_table(addr: PhysAddr) -> Self {
-        Self(addr.as_usize() | (PTEFlags::P | PTEFlags::RW).bits())
+        Self(addr.as_usize() as RawEntry | (PTEFlags::P | PTEFlags::RW | PTEFlags::US).bits())
+    }
+
+    /// Create a new entry associated with a page table, that is only accessible by the kernel
+    pub(super) fn new_kernel_page_table(addr: PhysAddr) -> Self {
+        Self(addr.as_usize() as RawEntry | (PTEFlags::P | PTEFlags::RW).bits())
+    }
+
+    /// Create a new page directory pointer table entry. Those can't
+    /// have access rights, only the present bit
+    #[cfg(feature = "pae")]
+    pub(super) fn new_pdpt_entry(addr: PhysAddr) -> Self {
+        Self(addr.as_usize() as RawEntry | PTEFlags::P.bits())
     }
 
     /// Get flags of this page table entry
@@ -116,6 +185,6 @@ impl PTEntry {
 
     /// Get the address this page table entry holds
     pub(super) fn address(&self) -> PhysAddr {
-        PhysAddr::from_usize(self.0 & !0x000000ff)
+        PhysAddr::from_usize((self.0 & ADDRESS_MASK) as _)
     }
 }
@@ -0,0 +1,114 @@
+use super::*;
+
+#[cfg(target_arch = "x86")]
+/// Number of bits an entry of the kernel page table covers. Kernel page table
+/// is the top level page table, or the last page directory with PAE
+pub(super) const KERNEL_TABLE_BITS: usize = PAGE_LEVEL_BITS + 12;
+#[cfg(target_arch = "x86_64")]
+/// Number of bits an entry of the kernel page table covers. Kernel page table
+/// is the top level page table
+pub(super) const KERNEL_TABLE_BITS: usize = TOP_LEVEL_BITS;
+
+#[cfg(not(feature = "pae"))]
+/// Number of kernel page table entries, that point to page directories
+const RECURSIVE_ENTRIES: usize = 1;
+#[cfg(feature = "pae")]
+/// Number of kernel page table entries, that point to page directories.
+/// All 4 page directories are mapped one after another
+const RECURSIVE_ENTRIES: usize = 4;
+
+#[cfg(target_arch = "x86")]
+/// First kernel page table entry, that points to a page directory.
+/// Page tables of the active address space are mapped at the top of the address space
+pub(super) const RECURSIVE_INDEX: usize = PAGE_TABLE_ENTRIES - RECURSIVE_ENTRIES;
+#[cfg(target_arch = "x86_64")]
+/// Kernel page table entry, that points to the top level page table.
+/// The last entry maps the kernel image, so the one before it is used
+pub(super) const RECURSIVE_INDEX: usize = PAGE_TABLE_ENTRIES - 2;
+
+/// Size of the memory range, where page tables of the active address space are mapped
+const RECURSIVE_SIZE: usize = RECURSIVE_ENTRIES << KERNEL_TABLE_BITS;
+
+#[cfg(target_arch = "x86")]
+/// Start of the memory range, where page tables of the active address space are mapped
+pub(super) const RECURSIVE_START: usize = 0usize.wrapping_sub(RECURSIVE_SIZE);
+#[cfg(target_arch = "x86_64")]
+/// Start of the memory range, where page tables of the active address space are mapped
+pub(super) const RECURSIVE_START: usize = KERNEL_HALF_START | (RECURSIVE_INDEX << KERNEL_TABLE_BITS);
+
+/// Map page directories into the kernel page table, so that they are used as page tables
+pub(super) fn install(kernel_table: &mut PageTable, directories: [PhysAddr; RECURSIVE_ENTRIES]) {
+    for (index, paddr) in directories.into_iter().enumerate() {
+        kernel_table[RECURSIVE_INDEX + index] = PTEntry::new_kernel_page_table(paddr);
+    }
+}
+
+/// Page directories of the address space with the top level page table at paddr
+#[cfg(not(feature = "pae"))]
+pub(super) fn directories(top_level: PhysAddr) -> [PhysAddr; RECURSIVE_ENTRIES] {
+    [top_level]
+}
+
+/// Page directories of the address space with the top level page table at paddr
+#[cfg(feature = "pae")]
+pub(super) fn directories(top_level: PhysAddr) -> [PhysAddr; RECURSIVE_ENTRIES] {
+    let pdpt = super::tmp_page::map::<PageTable>(top_level);
+    core::array::from_fn(|index| pdpt[index].address())
+}
+
+/// Whether vaddr is in the recursive mapping, which is different in every address space
+pub(super) const fn is_recursive(vaddr: usize) -> bool {
+    vaddr.wrapping_sub(RECURSIVE_START) < RECURSIVE_SIZE
+}
+
+/// Address in the recursive mapping, where the page table for vaddr is mapped.
+/// Applied again, gives the address of the page table one level higher
+const fn window(vaddr: usize) -> usize {
+    RECURSIVE_START + ((vaddr >> PAGE_LEVEL_BITS) & (RECURSIVE_SIZE - 1) & !0xfff)
+}
+
+/// Address in the recursive mapping of the page table, that is
+/// `bits` level and manages vaddr
+const fn level_window(vaddr: usize, bits: usize) -> usize {
+    let mut table = window(vaddr);
+    let mut level = 12;
+    while level < bits {
+        table = window(table);
+        level += PAGE_LEVEL_BITS;
+    }
+    table
+}
+
+/// Get a page table at paddr, that is `bits` level and manages vaddr,
+/// through the recursive mapping. Returns None if the page table
+/// is not a part of the active address space
+pub(super) fn page_table(paddr: PhysAddr, bits: usize, vaddr: VirtAddr) -> Option<*mut PageTable> {
+    // Page directory pointer table is not mapped
+    if bits > KERNEL_TABLE_BITS {
+        return None;
+    }
+    let table = level_window(vaddr.as_usize(), bits);
+
+    // Only page tables, that the active page directories point to, are mapped
+    let mapping = window(table) as *const PageTable;
+    let entry = unsafe { (*mapping)[(table >> 12) & (PAGE_TABLE_ENTRIES - 1)] };
+    let flags = entry.flags();
+    if flags.contains(PTEFlags::P) && !flags.contains(PTEFlags::PS) && entry.address() == paddr {
+        Some(table as *mut PageTable)
+    } else {
+        None
+    }
+}
+
+/// Flush the recursive mapping of the page table, that the
+/// entry for vaddr of a `bits` level page table points to
+pub(super) fn flush(vaddr: VirtAddr, bits: usize) {
+    unsafe {
+        if bits > PAGE_LEVEL_BITS + 12 {
+            // Page tables below the sublevel are mapped through it too
+            x86::tlb::flush_all();
+        } else {
+            x86::tlb::flush(level_window(vaddr.as_usize(), bits - PAGE_LEVEL_BITS));
+        }
+    }
+}
@@ -1,19 +1,76 @@
 use super::*;
+use crate::arch::traits::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-linker_symbol! {
-    address(TMP_PAGE_SYMBOL) => "kernel_tmp_page_address";
+/// Number of temporary pages every CPU has. More than one, so
+/// that they can be nested (in a page fault handler, for example)
+const SLOTS_PER_CPU: usize = 4;
+
+/// Page table, that maps temporary pages
+#[repr(C, align(4096))]
+struct SlotsPageTable(PageTable);
+
+static mut SLOTS_PAGE_TABLE: SlotsPageTable = SlotsPageTable([PTEntry::NULL; PAGE_TABLE_ENTRIES]);
+
+/// Temporary pages in use, a bit per page for every CPU
+static USED: [AtomicUsize; <crate::arch::Cpu as CpuTrait>::MAX_CPUS] =
+    [const { AtomicUsize::new(0) }; <crate::arch::Cpu as CpuTrait>::MAX_CPUS];
+
+#[cfg(target_arch = "x86")]
+/// Start of the temporary pages, right below the recursive mapping
+const SLOTS_START: usize =
+    super::recursive::RECURSIVE_START - (1 << super::recursive::KERNEL_TABLE_BITS);
+#[cfg(target_arch = "x86_64")]
+/// Start of the temporary pages, right after the heap in the page directory of the kernel image
+const SLOTS_START: usize = KERNEL_HEAP_START + KERNEL_HEAP_SIZE;
+
+/// Map the page table with temporary pages into the page directory, that
+/// maps SLOTS_START. It's shared by all address spaces, just like the rest of the kernel half
+pub(super) fn setup(directory: &mut PageTable) {
+    let index = (SLOTS_START >> (PAGE_LEVEL_BITS + 12)) & (PAGE_TABLE_ENTRIES - 1);
+    let page_table = VirtAddr::from_usize(&raw const SLOTS_PAGE_TABLE as _);
+    directory[index] = PTEntry::new_kernel_page_table(kernel_virt2phys(page_table));
+}
+
+/// Physical page, mapped to a temporary page of this CPU. Page is unmapped when dropped.
+/// The thread isn't preempted while it holds one, so slots can't run out
+/// because of threads, that were switched away from while holding theirs
+pub(super) struct TmpPage<T> {
+    cpu: usize,
+    slot: usize,
+    _phantom: core::marker::PhantomData<*mut T>,
 }
 
-extern "C" {
-    #[link_name = "kernel_tmp_page_entry_address"]
-    static TMP_PAGE_ENTRY: *mut PTEntry;
+impl<T> TmpPage<T> {
+    fn address(&self) -> VirtAddr {
+        let index = self.cpu * SLOTS_PER_CPU + self.slot;
+        VirtAddr::from_usize(SLOTS_START + (index << 12))
+    }
 }
 
-static TMP_PAGE_MUTEX: crate::sync::Lock = crate::sync::Lock::new(());
+impl<T> core::ops::Deref for TmpPage<T> {
+    type Target = T;
 
-/// Map a physical address to the TMP page. Returns virtual address of the TMP page
-pub(super) fn map<T>(addr: PhysAddr) -> crate::sync::MappedLockGuard<T> {
-    // TODO: Make it lock-free for multiple CPUs
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.address().as_ptr_of() }
+    }
+}
+
+impl<T> core::ops::DerefMut for TmpPage<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.address().as_mut_ptr_of() }
+    }
+}
+
+impl<T> Drop for TmpPage<T> {
+    fn drop(&mut self) {
+        USED[self.cpu].fetch_and(!(1 << self.slot), Ordering::SeqCst);
+        crate::thread::scheduler::preempt_enable();
+    }
+}
+
+/// Map a physical address to a free temporary page of this CPU
+pub(super) fn map<T>(addr: PhysAddr) -> TmpPage<T> {
     debug_assert!(
         core::mem::size_of::<T>() <= memory_addr::PAGE_SIZE_4K,
         "TMP page is mapped with a type bigger than one page"
@@ -24,14 +81,31 @@ pub(super) fn map<T>(addr: PhysAddr) -> crate::sync::MappedLockGuard<T> {
         addr
     );
 
-    crate::sync::LockGuard::map(TMP_PAGE_MUTEX.lock(), |_| {
-        let entry = PTEntry::new_page(addr, PageSize::Size4K, PTEFlags::P | PTEFlags::RW);
-        unsafe {
-            if *TMP_PAGE_ENTRY != entry {
-                *TMP_PAGE_ENTRY = entry;
-                x86::tlb::flush(address().as_usize());
-            }
+    // Thread must stay on this CPU, and keep other threads off it's slots
+    crate::thread::scheduler::preempt_disable();
+    let cpu = crate::arch::Cpu::cpu_id();
+    let slot = loop {
+        let slot = (!USED[cpu].load(Ordering::SeqCst)).trailing_zeros() as usize;
+        assert!(slot < SLOTS_PER_CPU, "CPU {} ran out of TMP pages", cpu);
+        // An interrupt handler might have taken it in between
+        if USED[cpu].fetch_or(1 << slot, Ordering::SeqCst) & (1 << slot) == 0 {
+            break slot;
+        }
+    };
+
+    let page: TmpPage<T> = TmpPage {
+        cpu,
+        slot,
+        _phantom: core::marker::PhantomData,
+    };
+    let entry = PTEntry::new_page(addr, PageSize::Size4K, PTEFlags::P | PTEFlags::RW);
+    unsafe {
+        let slots = &raw mut SLOTS_PAGE_TABLE;
+        let slot_entry = &mut (*slots).0[cpu * SLOTS_PER_CPU + slot];
+        if *slot_entry != entry {
+            *slot_entry = entry;
+            x86::tlb::flush(page.address().as_usize());
         }
-        unsafe { &mut *address().as_mut_ptr_of() }
-    })
+    }
+    page
 }
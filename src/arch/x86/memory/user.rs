@@ -0,0 +1,106 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether SMAP is enabled. `stac` and `clac` are invalid instructions without it
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable SMEP and SMAP, if the CPU supports them. The kernel
+/// can't execute user memory and can only access it on purpose
+pub(super) fn setup() {
+    const STRUCTURED_FEATURES: u32 = 7;
+    const SMEP: u32 = 1 << 7;
+    const SMAP: u32 = 1 << 20;
+
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::{__cpuid, __cpuid_count};
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    use x86::controlregs::{cr4, cr4_write, Cr4};
+
+    if __cpuid(0).eax < STRUCTURED_FEATURES {
+        return;
+    }
+    let features = __cpuid_count(STRUCTURED_FEATURES, 0).ebx;
+    unsafe {
+        if features & SMEP != 0 {
+            cr4_write(cr4() | Cr4::CR4_ENABLE_SMEP);
+        }
+        if features & SMAP != 0 {
+            cr4_write(cr4() | Cr4::CR4_ENABLE_SMAP);
+            SMAP_ENABLED.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Entry of the exception fixup table. If an instruction at `instruction`
+/// faults, execution continues at `fixup` instead of panicking
+#[repr(C)]
+struct ExTableEntry {
+    instruction: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    #[link_name = "ex_table_start"]
+    static EX_TABLE_START: ExTableEntry;
+    #[link_name = "ex_table_end"]
+    static EX_TABLE_END: ExTableEntry;
+}
+
+/// Find where to continue after a fault at ip, if it's allowed to fault
+pub(in crate::arch::x86) fn exception_fixup(ip: usize) -> Option<usize> {
+    let table = unsafe {
+        let start = &raw const EX_TABLE_START;
+        let end = &raw const EX_TABLE_END;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table
+        .iter()
+        .find(|entry| entry.instruction == ip)
+        .map(|entry| entry.fixup)
+}
+
+/// Copy len bytes from src to dst, allowing access to user memory.
+/// Returns the number of bytes left, if the copy faulted
+///
+/// # Safety
+/// Kernel side of the copy must be valid for len bytes
+pub(super) unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let smap = SMAP_ENABLED.load(Ordering::SeqCst);
+    let left: usize;
+    unsafe {
+        if smap {
+            core::arch::asm!("stac", options(nomem, nostack));
+        }
+        // esi is reserved by LLVM, so it's swapped in and out by hand.
+        // A fault in `rep movsb` leaves the number of bytes left in ecx
+        #[cfg(target_arch = "x86")]
+        core::arch::asm!(
+            "xchg {src}, %esi",
+            "1: rep movsb",
+            "2: xchg {src}, %esi",
+            ".pushsection .ex_table, \"a\"",
+            ".long 1b, 2b",
+            ".popsection",
+            src = inout(reg) src => _,
+            inout("ecx") len => left,
+            inout("edi") dst => _,
+            options(att_syntax, nostack),
+        );
+        #[cfg(target_arch = "x86_64")]
+        core::arch::asm!(
+            "1: rep movsb",
+            "2:",
+            ".pushsection .ex_table, \"a\"",
+            ".quad 1b, 2b",
+            ".popsection",
+            inout("rsi") src => _,
+            inout("rcx") len => left,
+            inout("rdi") dst => _,
+            options(att_syntax, nostack),
+        );
+        if smap {
+            core::arch::asm!("clac", options(nomem, nostack));
+        }
+    }
+    left
+}
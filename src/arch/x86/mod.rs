@@ -1,5 +1,12 @@
 #[cfg(target_arch = "x86")]
-core::arch::global_asm!(include_str!("x32/bootstrap.S"), options(att_syntax));
+core::arch::global_asm!(
+    ".set PAE, {pae}",
+    include_str!("x32/bootstrap.S"),
+    pae = const cfg!(feature = "pae") as u8,
+    options(att_syntax)
+);
+#[cfg(target_arch = "x86_64")]
+core::arch::global_asm!(include_str!("x64/bootstrap.S"), options(att_syntax));
 
 /// Early logging facilities
 mod early_logger;
@@ -7,9 +14,22 @@ mod early_logger;
 /// CPU Interface
 mod cpu;
 
+/// GDT, TSS and privilege levels
+mod gdt;
+
 /// Interrupts and IDT
 mod interrupts;
 
+/// Timer interrupts
+mod timer;
+
+/// Global allocator, kernel heap
+mod allocator;
+
+/// Kernel thread context switching, entering user mode
+mod context;
+pub use context::enter_user;
+
 /// Paging implementation
 /// I spent a lot of time here.
 /// And I hate every single second of it.
@@ -24,28 +44,16 @@ impl crate::arch::ArchTrait for Arch {
     type EarlyLogger = early_logger::EarlyLogger;
     type Cpu = cpu::Cpu;
     type Memory = memory::Memory;
-}
-
-/// Allocator
-mod allocator {
-    const SIZE: usize = 0x1000;
-    static mut ARENA: [u8; SIZE] = [0; SIZE];
-
-    // TODO: Use system allocator on OOM
-    #[global_allocator]
-    static ALLOCATOR: talc::Talck<spin::Mutex<()>, talc::ClaimOnOom> = talc::Talc::new(unsafe {
-        // if we're in a hosted environment, the Rust runtime may allocate before
-        // main() is called, so we need to initialize the arena automatically
-        talc::ClaimOnOom::new(talc::Span::from_slice(core::ptr::addr_of_mut!(ARENA)))
-    })
-    .lock();
+    type Context = context::Context;
+    type Timer = timer::Timer;
 }
 
 /// Kernel setup function. First thing that is called
-/// after assembly bootstrap setus up GDT and higher-half address space
+/// after assembly bootstrap setus up a temporary GDT and higher-half address space
 #[no_mangle]
-pub extern "cdecl" fn ksetup(mb_magic: u32, mbi_ptr: u32) -> ! {
+pub extern "C" fn ksetup(mb_magic: u32, mbi_ptr: u32) -> ! {
     crate::println!("Hello, SATAN!");
+    gdt::setup();
     interrupts::setup();
 
     let boot_info = if mb_magic == multiboot2::MAGIC {
@@ -67,6 +75,7 @@ pub extern "cdecl" fn ksetup(mb_magic: u32, mbi_ptr: u32) -> ! {
     };
 
     memory::setup_paging(&boot_info);
+    timer::setup();
 
     #[cfg(feature = "kernel-tests")]
     tests::run();
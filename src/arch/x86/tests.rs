@@ -2,7 +2,12 @@ use crate::arch::traits::*;
 
 pub(super) fn run() -> ! {
     test_syscalls();
-    test_paging();
+    test_zone_classes();
+    crate::memory::tests::run();
+    crate::thread::tests::run();
+    crate::sync::tests::run();
+    test_user_mode();
+    crate::memory::tests::test_paging();
     panic!("Testing finished");
 }
 
@@ -12,39 +17,105 @@ fn test_syscalls() {
     }
 }
 
-fn test_paging() {
+fn test_zone_classes() {
     use crate::memory::*;
     let page_allocator = crate::arch::Memory::page_allocator();
-    let kernel_address_space = crate::arch::Memory::kernel_address_space();
 
-    crate::println!("Total memory: {}", page_allocator.total_memory());
+    for stats in page_allocator.zone_stats() {
+        crate::println!("{}", stats);
+    }
+
+    let isa = page_allocator
+        .alloc_constrained(
+            0x10000,
+            &AllocConstraints::isa_dma().with_alignment(0x10000),
+        )
+        .unwrap();
+    assert_eq!(isa.len(), 1);
+    let (addr, size) = isa[0];
+    assert_eq!(addr.as_usize() % 0x10000, 0);
+    assert!(addr.as_usize() + size <= ZoneClass::DMA_END);
+    page_allocator.free(addr, size);
+
+    let pages = 16;
+    let contiguous = PageAllocatorTrait::<crate::arch::x86::memory::PageSize>::alloc_contiguous(
+        page_allocator,
+        pages,
+        0x10000,
+    )
+    .unwrap();
+    assert_eq!(contiguous.as_usize() % 0x10000, 0);
+    PageAllocatorTrait::<crate::arch::x86::memory::PageSize>::free_contiguous(
+        page_allocator,
+        contiguous,
+        pages,
+        0x10000,
+    );
+
+    // DMA memory is only used, when nothing else is left
+    let page = page_allocator.alloc(0x1000).unwrap();
+    assert!(page.as_usize() >= ZoneClass::DMA_END);
+    page_allocator.free(page, 0x1000);
 
-    use crate::memory::MappingFlags;
-    use crate::memory::{AddressSpaceTrait, PageSizeTrait};
-    let test = 0xc0801000 as *mut u32;
-    let test = kernel_address_space
+    let scattered = page_allocator
+        .alloc_constrained(0x5000, &AllocConstraints::new().with_contiguous(false))
+        .unwrap();
+    assert!(scattered.iter().map(|&(_, size)| size).sum::<usize>() >= 0x5000);
+    for (addr, size) in scattered {
+        page_allocator.free(addr, size);
+    }
+}
+
+fn test_user_mode() {
+    use crate::memory::*;
+    let page_allocator = crate::arch::Memory::page_allocator();
+    let address_space = crate::arch::Memory::kernel_address_space();
+
+    // mov $42, %eax; mov %eax, (%esp); xor %eax, %eax; int $0x80; jmp .
+    // Same instructions in 32 and 64 bit mode, last syscall exits
+    const PROGRAM: [u8; 14] = [
+        0xb8, 0x2a, 0x00, 0x00, 0x00, 0x89, 0x04, 0x24, 0x31, 0xc0, 0xcd, 0x80, 0xeb, 0xfe,
+    ];
+    let code = VirtAddr::from_usize(0x40000000);
+    let stack = code + 4096;
+    address_space
+        .map_alloc(
+            code,
+            4096,
+            MappingFlags::PRESENT
+                | MappingFlags::READ
+                | MappingFlags::WRITE
+                | MappingFlags::EXECUTE
+                | MappingFlags::WRITE_EXECUTE
+                | MappingFlags::USER,
+            page_allocator,
+        )
+        .unwrap();
+    address_space
         .map_alloc(
-            VirtAddr::from_mut_ptr_of(test),
-            crate::arch::x86::memory::PageSize::MIN as _,
-            MappingFlags::PRESENT | MappingFlags::READ | MappingFlags::WRITE,
+            stack,
+            4096,
+            MappingFlags::PRESENT | MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER,
             page_allocator,
         )
+        .unwrap();
+    UserSlice::new(code, PROGRAM.len()).write(&PROGRAM).unwrap();
+    let result = UserPtr::<u32>::new(stack + 4096 - 16);
+    result.write(0).unwrap();
+
+    fn user(sp: usize) {
+        super::enter_user(VirtAddr::from_usize(0x40000000), VirtAddr::from_usize(sp));
+    }
+    crate::thread::spawn(user, result.addr().as_usize())
         .unwrap()
-        .as_mut_ptr_of();
-    crate::println!("Mapped!");
-    crate::println!("Allocated memory: {}", page_allocator.allocated_memory());
-    unsafe {
-        *test = 42;
-    };
-    crate::println!("Wrote!");
-    crate::println!("Testing page mapping: {}", unsafe { *test });
-    kernel_address_space
-        .unmap_free(VirtAddr::from_mut_ptr_of(test), 4096, page_allocator)
+        .join();
+    assert_eq!(result.read().unwrap(), 42);
+    crate::println!("User mode program wrote {}", result.read().unwrap());
+
+    address_space
+        .unmap_free(code, 4096, page_allocator)
+        .unwrap();
+    address_space
+        .unmap_free(stack, 4096, page_allocator)
         .unwrap();
-    crate::println!(
-        "Allocated memory after freeing: {}",
-        page_allocator.allocated_memory()
-    );
-    crate::println!("Testing page unmapping (You should see a page fault):");
-    crate::println!("Huh? {}", unsafe { *test });
 }
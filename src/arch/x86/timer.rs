@@ -0,0 +1,36 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Timer interrupts per second
+const TICK_RATE: u64 = 100;
+
+/// Frequency of the PIT oscillator
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Number of timer interrupts since the timer was set up
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Handle a timer interrupt, IRQ 0 of the master PIC
+pub(super) fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    crate::thread::scheduler::tick();
+}
+
+pub struct Timer;
+
+impl crate::arch::TimerTrait for Timer {
+    fn ticks() -> u64 {
+        TICKS.load(Ordering::SeqCst) as u64
+    }
+}
+
+/// Start periodic timer interrupts with channel 0 of the PIT
+pub(super) fn setup() {
+    let divisor = (PIT_FREQUENCY / TICK_RATE) as u16;
+    unsafe {
+        // Channel 0, low then high byte of the divisor, square wave generator
+        x86::io::outb(0x43, 0x36);
+        x86::io::outb(0x40, divisor as u8);
+        x86::io::outb(0x40, (divisor >> 8) as u8);
+    }
+    crate::println!("Timer is setup, {} Hz", TICK_RATE);
+}
@@ -16,3 +16,6 @@ pub mod log;
 
 /// Memory interfaces
 pub mod memory;
+
+/// Kernel threads
+pub mod thread;
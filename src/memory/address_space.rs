@@ -20,6 +20,12 @@ bitflags::bitflags! {
         const UNCACHED      = 1 << 5;
         /// The memory globally accessible, doesn't invalidate TLB.
         const GLOBAL        = 1 << 6;
+        /// The memory is shared with another address space and
+        /// has to be copied before it is written to.
+        const COPY_ON_WRITE = 1 << 7;
+        /// The memory may be writable and executable at the same time.
+        /// Such mappings are refused otherwise. Not stored in page tables
+        const WRITE_EXECUTE = 1 << 8;
     }
 }
 
@@ -32,6 +38,9 @@ pub enum MappingError {
     /// Page allocation failed
     #[error("page allocation failed")]
     PageAllocationFailed,
+    /// No free virtual address range big enough
+    #[error("virtual address range allocation failed")]
+    VirtualAllocationFailed,
 
     /// Mapping an unaligned address
     #[error("mapping an unaligned address {0:#x}")]
@@ -45,6 +54,15 @@ pub enum MappingError {
     /// Unmapping part of a large page
     #[error("unmapping part of a large page at {0:#x}")]
     UnmappingPartOfLargePage(PhysAddr),
+    /// Changing flags of a part of a large page
+    #[error("protecting part of a large page at {0:#x}")]
+    ProtectingPartOfLargePage(PhysAddr),
+    /// Mapping memory that is both writable and executable, without [`MappingFlags::WRITE_EXECUTE`]
+    #[error("mapping writable and executable memory at {0:#x}")]
+    WritableExecutable(VirtAddr),
+    /// Sharing a page before the frame database, that counts references, is initialized
+    #[error("sharing page at {0:#x} without a frame database")]
+    SharingWithoutFrameDatabase(PhysAddr),
 }
 
 /// Result type for memory mapping operations
@@ -52,6 +70,10 @@ pub type MappingResult<T> = Result<T, MappingError>;
 
 pub mod nested_page_table;
 
+/// Virtual memory areas
+pub mod vma;
+use vma::{Vma, VmaError};
+
 /// Address space allows for control over accessible memory
 pub trait AddressSpaceTrait<PageSize: PageSizeTrait> {
     // pub fn map(&mut self, vaddr: VirtAddr, paddr: PhysAddr, size: usize) -> MappingResult<VirtAddr>;
@@ -76,4 +98,20 @@ pub trait AddressSpaceTrait<PageSize: PageSizeTrait> {
         size: usize,
         alloc: &impl PageAllocatorTrait<PageSize>,
     ) -> MappingResult<()>;
+
+    /// Reserve a memory area. Its pages are mapped lazily, when accessed
+    fn reserve(&self, area: Vma) -> Result<(), VmaError>;
+
+    /// Remove a memory area starting at vaddr and unmap all of its pages
+    fn release(
+        &self,
+        vaddr: VirtAddr,
+        alloc: &impl PageAllocatorTrait<PageSize>,
+    ) -> Result<(), VmaError>;
+
+    /// Create a copy of this address space. User pages are shared
+    /// between both address spaces and copied when written to
+    fn fork(&self, alloc: &impl PageAllocatorTrait<PageSize>) -> MappingResult<Self>
+    where
+        Self: Sized;
 }
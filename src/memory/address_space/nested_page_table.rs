@@ -1,5 +1,6 @@
 use memory_addr::MemoryAddr;
 
+use super::vma::{Vma, VmaBacking};
 use super::{MappingError, MappingFlags, MappingResult};
 use super::{PageAllocatorTrait, PageSizeTrait};
 use super::{PhysAddr, VirtAddr};
@@ -53,6 +54,12 @@ pub trait NestedPageTableLevel: Clone + Sized {
         alloc: &impl PageAllocatorTrait<Self::PageSize>,
     ) -> MappingResult<()>;
 
+    /// Whether the sublevel for vaddr must stay, even when nothing is mapped
+    /// through it anymore. Such sublevels are never freed by [`Self::unmap_free`]
+    fn keeps_sublevel(&self, _vaddr: VirtAddr) -> bool {
+        false
+    }
+
     /// Set an entry in this level. vaddr might not be aligned if entry
     /// is [`PageTableEntry::Level`]
     fn set_entry(&self, vaddr: VirtAddr, entry: PageTableEntry<Self>) -> MappingResult<()>;
@@ -60,6 +67,24 @@ pub trait NestedPageTableLevel: Clone + Sized {
     /// Get an entry in this page table. vaddr might not be aligned
     fn get_entry(&self, vaddr: VirtAddr) -> MappingResult<PageTableEntry<Self>>;
 
+    /// Write to a physical page at paddr. `write` is called for every
+    /// chunk of the page with an offset of the chunk into the page
+    fn write_page(
+        &self,
+        paddr: PhysAddr,
+        page_size: Self::PageSize,
+        write: impl FnMut(usize, &mut [u8]),
+    );
+
+    /// Copy contents of a page mapped at vaddr in the current address space
+    /// into physical memory at paddr
+    fn copy_page(&self, vaddr: VirtAddr, paddr: PhysAddr, page_size: Self::PageSize) {
+        self.write_page(paddr, page_size, |offset, chunk| {
+            let src = (vaddr + offset).as_ptr();
+            chunk.copy_from_slice(unsafe { core::slice::from_raw_parts(src, chunk.len()) });
+        });
+    }
+
     /// Map a single (possibly large/huge) page.
     fn map_page(
         &self,
@@ -69,6 +94,7 @@ pub trait NestedPageTableLevel: Clone + Sized {
         flags: MappingFlags,
         alloc: &impl PageAllocatorTrait<Self::PageSize>,
     ) -> MappingResult<()> {
+        check_write_execute(vaddr, flags)?;
         if self.page_size() == Some(page_size) {
             self.set_entry(vaddr, PageTableEntry::Page(paddr, flags))
         } else {
@@ -105,11 +131,10 @@ pub trait NestedPageTableLevel: Clone + Sized {
             if page < vaddr || page + region_size > vaddr + size {
                 match entry {
                     PageTableEntry::Level(level) => {
-                        if page < vaddr {
-                            level.unmap_free(vaddr, page + region_size - vaddr, alloc)?;
-                        } else {
-                            level.unmap_free(page, vaddr + size - page, alloc)?;
-                        }
+                        // Range might end in the same region it starts in
+                        let sub_start = page.max(vaddr);
+                        let sub_end = (page + region_size).min(vaddr + size);
+                        level.unmap_free(sub_start, sub_end - sub_start, alloc)?;
                         let mut mapped = false;
                         for entry_addr in (page.as_usize()..page.as_usize() + region_size)
                             .step_by(level.region_size())
@@ -120,7 +145,7 @@ pub trait NestedPageTableLevel: Clone + Sized {
                                 break;
                             }
                         }
-                        if !mapped {
+                        if !mapped && !self.keeps_sublevel(page) {
                             self.free_sublevel(level, alloc)?;
                             self.set_entry(page, PageTableEntry::NULL)?;
                         }
@@ -135,8 +160,10 @@ pub trait NestedPageTableLevel: Clone + Sized {
                 match entry {
                     PageTableEntry::Level(level) => {
                         level.unmap_free(page, region_size, alloc)?;
-                        self.free_sublevel(level, alloc)?;
-                        self.set_entry(page, PageTableEntry::NULL)?;
+                        if !self.keeps_sublevel(page) {
+                            self.free_sublevel(level, alloc)?;
+                            self.set_entry(page, PageTableEntry::NULL)?;
+                        }
                     }
                     PageTableEntry::Page(paddr, flags) => {
                         if flags.contains(MappingFlags::PRESENT) {
@@ -149,6 +176,131 @@ pub trait NestedPageTableLevel: Clone + Sized {
         }
         Ok(())
     }
+
+    /// Change flags of pages mapped in [vaddr; vaddr + size).
+    /// Pages, that are not mapped, are skipped
+    fn protect(&self, vaddr: VirtAddr, size: usize, flags: MappingFlags) -> MappingResult<()> {
+        check_write_execute(vaddr, flags)?;
+        let region_size = self.region_size();
+        let start = vaddr.align_down(region_size);
+        let end = (vaddr + size).align_up(region_size);
+        for page in (start.as_usize()..end.as_usize()).step_by(region_size) {
+            let page = VirtAddr::from(page);
+            match self.get_entry(page)? {
+                PageTableEntry::Level(level) => {
+                    let start = page.max(vaddr);
+                    let end = (page + region_size).min(vaddr + size);
+                    level.protect(start, end - start, flags)?;
+                }
+                PageTableEntry::Page(paddr, old_flags) => {
+                    if !old_flags.contains(MappingFlags::PRESENT) {
+                        continue;
+                    }
+                    if page < vaddr || page + region_size > vaddr + size {
+                        return Err(MappingError::ProtectingPartOfLargePage(paddr));
+                    }
+                    self.set_entry(
+                        page,
+                        PageTableEntry::Page(paddr, flags | MappingFlags::PRESENT),
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a region of this page table level into `child`. User pages are
+    /// shared and marked copy-on-write, everything else is copied as-is.
+    /// vaddr and size must be aligned to [`Self::region_size`]
+    fn fork(
+        &self,
+        child: &Self,
+        vaddr: VirtAddr,
+        size: usize,
+        alloc: &impl PageAllocatorTrait<Self::PageSize>,
+    ) -> MappingResult<()> {
+        let region_size = self.region_size();
+        for page in (vaddr.as_usize()..vaddr.as_usize() + size).step_by(region_size) {
+            let page = VirtAddr::from(page);
+            let entry = self.get_entry(page)?;
+            if !entry.mapped() {
+                continue;
+            }
+
+            match entry {
+                PageTableEntry::Level(level) => {
+                    let sublevel = child
+                        .new_sublevel(alloc)
+                        .ok_or(MappingError::PageAllocationFailed)?;
+                    child.set_entry(page, PageTableEntry::Level(sublevel.clone()))?;
+                    level.fork(&sublevel, page, region_size, alloc)?;
+                }
+                PageTableEntry::Page(paddr, mut flags) => {
+                    if flags.contains(MappingFlags::PRESENT | MappingFlags::USER) {
+                        if !alloc.share(paddr, self.page_size().unwrap()) {
+                            return Err(MappingError::SharingWithoutFrameDatabase(paddr));
+                        }
+                        if flags.contains(MappingFlags::WRITE) {
+                            flags.remove(MappingFlags::WRITE);
+                            flags.insert(MappingFlags::COPY_ON_WRITE);
+                            self.set_entry(page, PageTableEntry::Page(paddr, flags))?;
+                        }
+                    }
+                    child.set_entry(page, PageTableEntry::Page(paddr, flags))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve a write to a copy-on-write page at vaddr: copy the page if it
+    /// is still shared, or make it writable again if this is the last reference.
+    /// Returns false if there is no copy-on-write page at this address
+    fn resolve_cow(
+        &self,
+        vaddr: VirtAddr,
+        alloc: &impl PageAllocatorTrait<Self::PageSize>,
+    ) -> MappingResult<bool> {
+        match self.get_entry(vaddr)? {
+            PageTableEntry::Level(level) => level.resolve_cow(vaddr, alloc),
+            PageTableEntry::Page(paddr, mut flags) => {
+                if !flags.contains(MappingFlags::PRESENT | MappingFlags::COPY_ON_WRITE) {
+                    return Ok(false);
+                }
+
+                let page = vaddr.align_down(self.region_size());
+                let page_size = self.page_size().unwrap();
+                flags.remove(MappingFlags::COPY_ON_WRITE);
+                flags.insert(MappingFlags::WRITE);
+                if alloc.is_shared(paddr, page_size) {
+                    let copy = alloc
+                        .alloc(page_size)
+                        .ok_or(MappingError::PageAllocationFailed)?;
+                    self.copy_page(page, copy, page_size);
+                    self.set_entry(page, PageTableEntry::Page(copy, flags))?;
+                    alloc.free(paddr, page_size);
+                } else {
+                    self.set_entry(page, PageTableEntry::Page(paddr, flags))?;
+                }
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Page tables are changed by one thread at a time. Kernel half is shared
+/// by every address space, and two threads, that map pages next to each
+/// other, could both install a new table for the same empty entry
+static PAGE_TABLES: crate::sync::Mutex<()> = crate::sync::mutex(());
+
+/// Refuse mappings, that are both writable and executable, unless explicitly allowed
+fn check_write_execute(vaddr: VirtAddr, flags: MappingFlags) -> MappingResult<()> {
+    if flags.contains(MappingFlags::WRITE | MappingFlags::EXECUTE)
+        && !flags.contains(MappingFlags::WRITE_EXECUTE)
+    {
+        return Err(MappingError::WritableExecutable(vaddr));
+    }
+    Ok(())
 }
 
 /// Implementation of [`super::AddressSpaceTrait`] for a nested page table
@@ -163,7 +315,8 @@ pub trait NestedPageTable {
     /// Get top level page table for this address space
     fn top_level(&self) -> Self::Level;
 
-    /// Implementation of [`super::AddressSpaceTrait::map_alloc`]
+    /// Implementation of [`super::AddressSpaceTrait::map_alloc`]. If a page
+    /// can't be allocated or mapped, pages mapped before it are unmapped
     fn map_alloc(
         &self,
         vaddr: VirtAddr,
@@ -171,19 +324,101 @@ pub trait NestedPageTable {
         flags: MappingFlags,
         alloc: &impl PageAllocatorTrait<Self::PageSize>,
     ) -> MappingResult<VirtAddr> {
+        let page_size: usize = Self::PageSize::MIN.into();
         // TODO: Possibly bigger pages
-        for page in 0..size / Self::PageSize::MIN.into() {
-            self.top_level().map_page(
-                vaddr + page * Self::PageSize::MIN.into(),
-                alloc.alloc(Self::PageSize::MIN).unwrap(),
-                Self::PageSize::MIN,
-                flags,
-                alloc,
-            )?;
+        for page in 0..size / page_size {
+            let mapped = alloc
+                .alloc(Self::PageSize::MIN)
+                .ok_or(MappingError::PageAllocationFailed)
+                .and_then(|paddr| {
+                    let _page_tables = PAGE_TABLES.lock();
+                    self.top_level()
+                        .map_page(
+                            vaddr + page * page_size,
+                            paddr,
+                            Self::PageSize::MIN,
+                            flags,
+                            alloc,
+                        )
+                        .inspect_err(|_| alloc.free(paddr, Self::PageSize::MIN))
+                });
+            if let Err(err) = mapped {
+                // Failure to map is reported, not a failure to clean up after it
+                if page > 0 {
+                    let _ = self.unmap_free(vaddr, page * page_size, alloc);
+                }
+                return Err(err);
+            }
         }
         Ok(vaddr)
     }
 
+    /// Map a page at vaddr to an already allocated page at paddr
+    fn map(
+        &self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+        alloc: &impl PageAllocatorTrait<Self::PageSize>,
+    ) -> MappingResult<()> {
+        let _page_tables = PAGE_TABLES.lock();
+        self.top_level()
+            .map_page(vaddr, paddr, Self::PageSize::MIN, flags, alloc)
+    }
+
+    /// Map a page of a memory area at vaddr, that is accessed for the first time
+    fn populate(
+        &self,
+        area: &Vma,
+        vaddr: VirtAddr,
+        alloc: &impl PageAllocatorTrait<Self::PageSize>,
+    ) -> MappingResult<()> {
+        let page_size = Self::PageSize::MIN;
+        let page = vaddr.align_down(page_size.into());
+        let offset = page - area.start;
+        let top_level = self.top_level();
+        let paddr = match &area.backing {
+            VmaBacking::Anonymous => {
+                let paddr = alloc
+                    .alloc(page_size)
+                    .ok_or(MappingError::PageAllocationFailed)?;
+                top_level.write_page(paddr, page_size, |_, chunk| chunk.fill(0));
+                paddr
+            }
+            VmaBacking::Physical(base) => {
+                // Unmapping will free the page, so add a reference for the mapping
+                let paddr = *base + offset;
+                if !alloc.share(paddr, page_size) {
+                    return Err(MappingError::SharingWithoutFrameDatabase(paddr));
+                }
+                paddr
+            }
+            VmaBacking::File {
+                file,
+                offset: file_offset,
+            } => {
+                let paddr = alloc
+                    .alloc(page_size)
+                    .ok_or(MappingError::PageAllocationFailed)?;
+                top_level.write_page(paddr, page_size, |chunk_offset, chunk| {
+                    file.read(file_offset + offset + chunk_offset, chunk)
+                });
+                paddr
+            }
+        };
+
+        let _page_tables = PAGE_TABLES.lock();
+        top_level
+            .map_page(
+                page,
+                paddr,
+                page_size,
+                area.flags | MappingFlags::PRESENT,
+                alloc,
+            )
+            .inspect_err(|_| alloc.free(paddr, page_size))
+    }
+
     /// Implementation of [`super::AddressSpaceTrait::unmap_free`]
     fn unmap_free(
         &self,
@@ -191,6 +426,37 @@ pub trait NestedPageTable {
         size: usize,
         alloc: &impl PageAllocatorTrait<Self::PageSize>,
     ) -> MappingResult<()> {
+        let _page_tables = PAGE_TABLES.lock();
         self.top_level().unmap_free(vaddr, size, alloc)
     }
+
+    /// Change flags of pages mapped in [vaddr; vaddr + size), see [`NestedPageTableLevel::protect`]
+    fn protect(&self, vaddr: VirtAddr, size: usize, flags: MappingFlags) -> MappingResult<()> {
+        let _page_tables = PAGE_TABLES.lock();
+        self.top_level().protect(vaddr, size, flags)
+    }
+
+    /// Copy [vaddr; vaddr + size) of this address space into the top level
+    /// table of another one, see [`NestedPageTableLevel::fork`]
+    fn fork_into(
+        &self,
+        child: &Self::Level,
+        vaddr: VirtAddr,
+        size: usize,
+        alloc: &impl PageAllocatorTrait<Self::PageSize>,
+    ) -> MappingResult<()> {
+        let _page_tables = PAGE_TABLES.lock();
+        self.top_level().fork(child, vaddr, size, alloc)
+    }
+
+    /// Resolve a write to a copy-on-write page at vaddr,
+    /// see [`NestedPageTableLevel::resolve_cow`]
+    fn resolve_cow(
+        &self,
+        vaddr: VirtAddr,
+        alloc: &impl PageAllocatorTrait<Self::PageSize>,
+    ) -> MappingResult<bool> {
+        let _page_tables = PAGE_TABLES.lock();
+        self.top_level().resolve_cow(vaddr, alloc)
+    }
 }
@@ -0,0 +1,150 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use super::{MappingError, MappingFlags};
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+
+/// A file that can back a memory area
+pub trait FileBacking: Send + Sync {
+    /// Read a chunk of the file at offset into buffer.
+    /// Bytes past the end of the file should be zeroed
+    fn read(&self, offset: usize, buffer: &mut [u8]);
+}
+
+/// Where the contents of a memory area come from
+#[derive(Clone)]
+pub enum VmaBacking {
+    /// Pages are allocated and zeroed on first access
+    Anonymous,
+    /// Area maps physical memory starting at this address (MMIO, framebuffers, etc.)
+    Physical(PhysAddr),
+    /// Pages are allocated and read from a file, starting at offset, on first access
+    File {
+        file: Arc<dyn FileBacking>,
+        offset: usize,
+    },
+}
+
+impl core::fmt::Debug for VmaBacking {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Anonymous => write!(f, "Anonymous"),
+            Self::Physical(paddr) => write!(f, "Physical({:#x})", paddr),
+            Self::File { offset, .. } => write!(f, "File(offset: {:#x})", offset),
+        }
+    }
+}
+
+/// Virtual memory area, a region of an address space that is
+/// reserved, but not necessarily mapped
+#[derive(Clone, Debug)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub size: usize,
+    pub flags: MappingFlags,
+    pub backing: VmaBacking,
+}
+
+impl Vma {
+    pub fn new(start: VirtAddr, size: usize, flags: MappingFlags, backing: VmaBacking) -> Self {
+        Self {
+            start,
+            size,
+            flags,
+            backing,
+        }
+    }
+
+    /// Address right after the end of the area
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Returns true if vaddr is inside of this area
+    pub fn contains(&self, vaddr: VirtAddr) -> bool {
+        vaddr >= self.start && vaddr < self.end()
+    }
+
+    /// Returns true if memory accesses with these flags are allowed in this area
+    pub fn allows(&self, access: MappingFlags) -> bool {
+        self.flags.contains(access)
+    }
+}
+
+/// Kinds of errors for memory area operations
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum VmaError {
+    /// Area overlaps with an existing one
+    #[error("memory area overlaps with an existing area at {0:#x}")]
+    Overlapping(VirtAddr),
+    /// Area is not page aligned
+    #[error("memory area at {0:#x} is not aligned")]
+    Unaligned(VirtAddr),
+    /// There is no area at this address
+    #[error("no memory area at {0:#x}")]
+    NotFound(VirtAddr),
+    /// Failed to map or unmap pages of an area
+    #[error(transparent)]
+    Mapping(#[from] MappingError),
+}
+
+/// Kinds of errors that happen when resolving page faults
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum FaultError {
+    /// Faulting address is outside of any memory area
+    #[error("page fault at {0:#x} outside of any memory area")]
+    NoArea(VirtAddr),
+    /// Access is not allowed by the area
+    #[error("access {1:?} to {0:#x} is not allowed")]
+    AccessViolation(VirtAddr, MappingFlags),
+    /// Failed to map the page
+    #[error(transparent)]
+    Mapping(#[from] MappingError),
+}
+
+/// Sorted set of non-overlapping memory areas of an address space
+#[derive(Clone, Debug, Default)]
+pub struct VmaSet {
+    areas: BTreeMap<VirtAddr, Vma>,
+}
+
+impl VmaSet {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Add an area to the set
+    pub fn insert(&mut self, area: Vma) -> Result<(), VmaError> {
+        if !area.start.is_aligned_4k() || !area.size.is_multiple_of(memory_addr::PAGE_SIZE_4K) {
+            return Err(VmaError::Unaligned(area.start));
+        }
+        if let Some(prev) = self.areas.range(..area.end()).next_back() {
+            if prev.1.end() > area.start {
+                return Err(VmaError::Overlapping(*prev.0));
+            }
+        }
+        self.areas.insert(area.start, area);
+        Ok(())
+    }
+
+    /// Remove an area starting at vaddr
+    pub fn remove(&mut self, vaddr: VirtAddr) -> Result<Vma, VmaError> {
+        self.areas.remove(&vaddr).ok_or(VmaError::NotFound(vaddr))
+    }
+
+    /// Find an area that contains vaddr
+    pub fn find(&self, vaddr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=vaddr)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(vaddr))
+    }
+
+    /// Iterate over all areas in order
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+}
@@ -0,0 +1,233 @@
+use super::{FormatSize, PhysAddr};
+
+/// Maximum number of regions a boot memory map can hold.
+/// Boot memory map is built before the heap is usable, so it can't grow
+const MAX_REGIONS: usize = 128;
+
+/// Kind of a physical memory region. When regions overlap, the
+/// kind that comes later in this list wins
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RegionKind {
+    /// Free memory, can be given to the page allocator
+    Free,
+    /// Memory holding ACPI tables, can be freed after they are parsed
+    AcpiReclaimable,
+    /// Boot information passed by the bootloader
+    BootInfo,
+    /// Modules loaded by the bootloader (initrd, etc.)
+    Module,
+    /// Page frame database
+    FrameDatabase,
+    /// Kernel image
+    Kernel,
+    /// Memory that must not be used (firmware, MMIO, defective RAM, etc.)
+    Reserved,
+}
+
+impl RegionKind {
+    /// All region kinds, in order of priority
+    pub const ALL: [Self; 7] = [
+        Self::Free,
+        Self::AcpiReclaimable,
+        Self::BootInfo,
+        Self::Module,
+        Self::FrameDatabase,
+        Self::Kernel,
+        Self::Reserved,
+    ];
+
+    /// Short name of the kind, for reports
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Free => "free",
+            Self::AcpiReclaimable => "acpi",
+            Self::BootInfo => "boot info",
+            Self::Module => "modules",
+            Self::FrameDatabase => "frame db",
+            Self::Kernel => "kernel",
+            Self::Reserved => "firmware",
+        }
+    }
+}
+
+/// A region of physical memory, [start; end)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    const NULL: Self = Self {
+        start: PhysAddr::from_usize(0),
+        end: PhysAddr::from_usize(0),
+        kind: RegionKind::Reserved,
+    };
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// Errors building a boot memory map
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum BootMemoryMapError {
+    #[error("too many memory regions (maximum is {MAX_REGIONS})")]
+    TooManyRegions,
+}
+
+/// Physical memory map, built from the map provided by the bootloader
+/// and ranges that are known to be in use. After [`Self::sanitize`],
+/// regions are sorted and don't overlap
+#[derive(Clone, Debug)]
+pub struct BootMemoryMap {
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+}
+
+impl BootMemoryMap {
+    pub const fn new() -> Self {
+        Self {
+            regions: [Region::NULL; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Add a region to the map. It may overlap with existing regions
+    pub fn add(
+        &mut self,
+        start: PhysAddr,
+        end: PhysAddr,
+        kind: RegionKind,
+    ) -> Result<(), BootMemoryMapError> {
+        if end <= start {
+            return Ok(());
+        }
+        if self.len == MAX_REGIONS {
+            return Err(BootMemoryMapError::TooManyRegions);
+        }
+        self.regions[self.len] = Region { start, end, kind };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Resolve overlaps (the more restrictive kind wins), sort
+    /// the regions and merge adjacent regions of the same kind
+    pub fn sanitize(&mut self) -> Result<(), BootMemoryMapError> {
+        // Every region boundary is a point where the kind might change
+        let mut points = [PhysAddr::from_usize(0); MAX_REGIONS * 2];
+        for (index, region) in self.regions().iter().enumerate() {
+            points[index * 2] = region.start;
+            points[index * 2 + 1] = region.end;
+        }
+        let points = &mut points[..self.len * 2];
+        points.sort_unstable();
+
+        let mut sanitized = Self::new();
+        for window in points.windows(2) {
+            let (start, end) = (window[0], window[1]);
+            if start == end {
+                continue;
+            }
+            let Some(kind) = self
+                .regions()
+                .iter()
+                .filter(|region| region.start <= start && region.end >= end)
+                .map(|region| region.kind)
+                .max()
+            else {
+                continue;
+            };
+
+            match sanitized.regions[..sanitized.len].last_mut() {
+                Some(last) if last.kind == kind && last.end == start => last.end = end,
+                _ => sanitized.add(start, end, kind)?,
+            }
+        }
+        *self = sanitized;
+        Ok(())
+    }
+
+    /// All regions in the map
+    pub fn regions(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
+
+    /// Regions of a specific kind
+    pub fn regions_of(&self, kind: RegionKind) -> impl Iterator<Item = &Region> {
+        self.regions()
+            .iter()
+            .filter(move |region| region.kind == kind)
+    }
+
+    /// Total size of regions of a specific kind
+    pub fn total(&self, kind: RegionKind) -> usize {
+        self.regions_of(kind).map(Region::size).sum()
+    }
+
+    /// Start of the first region, that is not reserved. Physical
+    /// memory doesn't start at 0 on every architecture
+    pub fn memory_start(&self) -> PhysAddr {
+        self.regions()
+            .iter()
+            .filter(|region| region.kind != RegionKind::Reserved)
+            .map(|region| region.start)
+            .min()
+            .unwrap_or(PhysAddr::from_usize(0))
+    }
+
+    /// End of the last region, that is not reserved
+    pub fn memory_end(&self) -> PhysAddr {
+        self.regions()
+            .iter()
+            .filter(|region| region.kind != RegionKind::Reserved)
+            .map(|region| region.end)
+            .max()
+            .unwrap_or(PhysAddr::from_usize(0))
+    }
+
+    /// Change kind of all regions of one kind to another. Regions, that
+    /// end up next to a region of the same kind, are merged with it
+    pub fn convert(&mut self, from: RegionKind, to: RegionKind) {
+        let mut len = 0;
+        for index in 0..self.len {
+            let mut region = self.regions[index];
+            if region.kind == from {
+                region.kind = to;
+            }
+            match self.regions[..len].last_mut() {
+                Some(last) if last.kind == region.kind && last.end == region.start => {
+                    last.end = region.end
+                }
+                _ => {
+                    self.regions[len] = region;
+                    len += 1;
+                }
+            }
+        }
+        self.len = len;
+    }
+}
+
+impl Default for BootMemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Display for BootMemoryMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for region in self.regions() {
+            writeln!(
+                f,
+                "{:#010x}-{:#010x} {:?} ({})",
+                region.start,
+                region.end,
+                region.kind,
+                FormatSize(region.size() as _)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use crate::arch::traits::*;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use super::boot_memory_map::{BootMemoryMap, RegionKind};
+use super::{MemoryAddr, PageSizeTrait, PhysAddr, VirtAddr};
+
+bitflags::bitflags! {
+    /// State of a physical page frame
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct FrameFlags: u32 {
+        /// Frame is not managed by the page allocator
+        /// (firmware, kernel image, boot modules, etc.)
+        const RESERVED   = 1 << 0;
+        /// Frame is allocated
+        const ALLOCATED  = 1 << 1;
+        /// Frame must not be freed or moved
+        const PINNED     = 1 << 2;
+        /// Frame holds slab objects
+        const SLAB       = 1 << 3;
+        /// Frame holds a page table
+        const PAGE_TABLE = 1 << 4;
+    }
+}
+
+/// Descriptor of a single physical page frame. Reference count and
+/// owner of a multi-page allocation are kept in it's first frame
+#[derive(Debug, Default)]
+pub struct Frame {
+    flags: AtomicU32,
+    references: AtomicU32,
+    /// Owner defined tag, for example for reverse mapping
+    owner: AtomicUsize,
+}
+
+impl Frame {
+    const fn new(flags: FrameFlags) -> Self {
+        Self {
+            flags: AtomicU32::new(flags.bits()),
+            references: AtomicU32::new(0),
+            owner: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_retain(self.flags.load(Ordering::SeqCst))
+    }
+
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::SeqCst);
+    }
+
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::SeqCst);
+    }
+
+    /// Number of references to the frame. Allocated frames start with one
+    pub fn references(&self) -> u32 {
+        self.references.load(Ordering::SeqCst)
+    }
+
+    pub fn owner(&self) -> usize {
+        self.owner.load(Ordering::SeqCst)
+    }
+
+    pub fn set_owner(&self, owner: usize) {
+        self.owner.store(owner, Ordering::SeqCst);
+    }
+}
+
+/// Array of frame descriptors for all physical memory, indexed by address.
+/// Physical memory doesn't have to start at 0, the first descriptor is of
+/// the frame at `base`
+pub struct FrameDatabase {
+    base: usize,
+    frames: &'static [Frame],
+}
+
+fn frame_size() -> usize {
+    <crate::arch::Memory as MemoryTrait>::PageSize::MIN.into()
+}
+
+impl FrameDatabase {
+    /// Size of the database, that covers physical memory in [start; end)
+    pub fn size(start: PhysAddr, end: PhysAddr) -> usize {
+        let first = start.as_usize() / frame_size();
+        (end.as_usize().div_ceil(frame_size()) - first) * core::mem::size_of::<Frame>()
+    }
+
+    /// Physical address of the first frame, that has a descriptor
+    pub fn base(&self) -> PhysAddr {
+        PhysAddr::from_usize(self.base)
+    }
+
+    /// Get descriptor of the frame at paddr
+    pub fn get(&self, paddr: PhysAddr) -> Option<&Frame> {
+        let index = paddr.as_usize().checked_sub(self.base)? / frame_size();
+        self.frames.get(index)
+    }
+
+    /// Descriptors of all frames in [start; start + size)
+    pub fn range(&self, start: PhysAddr, size: usize) -> &[Frame] {
+        let index = |addr: usize| addr.saturating_sub(self.base);
+        let first = (index(start.as_usize()) / frame_size()).min(self.frames.len());
+        let last = index(start.as_usize() + size)
+            .div_ceil(frame_size())
+            .min(self.frames.len());
+        &self.frames[first..last]
+    }
+
+    /// All frame descriptors
+    pub fn frames(&self) -> &[Frame] {
+        self.frames
+    }
+
+    /// Number of frames that have all of the flags
+    pub fn count(&self, flags: FrameFlags) -> usize {
+        self.frames
+            .iter()
+            .filter(|frame| frame.flags().contains(flags))
+            .count()
+    }
+
+    /// Mark frames as allocated, with a single reference
+    pub fn allocate(&self, start: PhysAddr, size: usize) {
+        for frame in self.range(start, size) {
+            frame
+                .flags
+                .store(FrameFlags::ALLOCATED.bits(), Ordering::SeqCst);
+            frame.references.store(1, Ordering::SeqCst);
+            frame.owner.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// Add a reference to the frame at start
+    pub fn share(&self, start: PhysAddr) {
+        if let Some(frame) = self.get(start) {
+            frame.references.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Drop a reference to frames in [start; start + size). Returns
+    /// true if that was the last reference and memory can be freed
+    pub fn release(&self, start: PhysAddr, size: usize) -> bool {
+        let Some(frame) = self.get(start) else {
+            return true;
+        };
+        let flags = frame.flags();
+        assert!(
+            !flags.contains(FrameFlags::PAGE_TABLE),
+            "Freeing frame {:#x} that is used by a page table",
+            start
+        );
+        assert!(
+            !flags.contains(FrameFlags::PINNED),
+            "Freeing pinned frame {:#x}",
+            start
+        );
+
+        // Frames that weren't allocated only get extra references
+        if !flags.contains(FrameFlags::ALLOCATED) {
+            let mut references = frame.references();
+            while references > 0 {
+                match frame.references.compare_exchange_weak(
+                    references,
+                    references - 1,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => references = current,
+                }
+            }
+            return false;
+        }
+        if frame.references.fetch_sub(1, Ordering::SeqCst) > 1 {
+            return false;
+        }
+        for frame in self.range(start, size) {
+            frame.flags.store(0, Ordering::SeqCst);
+            frame.references.store(0, Ordering::SeqCst);
+        }
+        true
+    }
+
+    /// Clear [`FrameFlags::RESERVED`] from frames, that are given to the page allocator
+    pub fn unreserve(&self, start: PhysAddr, size: usize) {
+        for frame in self.range(start, size) {
+            frame.remove_flags(FrameFlags::RESERVED);
+        }
+    }
+}
+
+static FRAMES: spin::Once<FrameDatabase> = spin::Once::new();
+
+/// Get the frame database, if it was initialized
+pub fn frames() -> Option<&'static FrameDatabase> {
+    FRAMES.get()
+}
+
+/// Initialize the frame database at vaddr. Frames that are
+/// not free in the boot memory map are marked reserved
+///
+/// # Safety
+/// [`FrameDatabase::size`] bytes of the map's memory range
+/// at vaddr must be mapped, writable and not used for anything else
+pub unsafe fn init(vaddr: VirtAddr, map: &BootMemoryMap) -> &'static FrameDatabase {
+    FRAMES.call_once(|| {
+        let base = map.memory_start().align_down(frame_size());
+        let count =
+            FrameDatabase::size(base, map.memory_end()) / core::mem::size_of::<Frame>();
+        let frames: &'static mut [Frame] = unsafe {
+            let frames = vaddr.as_mut_ptr_of::<Frame>();
+            for index in 0..count {
+                frames.add(index).write(Frame::new(FrameFlags::RESERVED));
+            }
+            core::slice::from_raw_parts_mut(frames, count)
+        };
+
+        for region in map.regions_of(RegionKind::Free) {
+            let first = (region.start - base).div_ceil(frame_size());
+            let last = ((region.end - base) / frame_size()).min(count);
+            for frame in frames[first.min(last)..last].iter() {
+                frame.remove_flags(FrameFlags::RESERVED);
+            }
+        }
+        FrameDatabase {
+            base: base.as_usize(),
+            frames,
+        }
+    })
+}
@@ -0,0 +1,193 @@
+use crate::arch::traits::*;
+use crate::sync::{mutex, Mutex};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
+use talc::{OomHandler, Span, Talc};
+
+use super::{AddressSpaceTrait, FormatSize, MappingFlags, PageSizeTrait, VirtAddr};
+
+/// Smallest amount of memory the heap grows by at once
+const MIN_GROWTH: usize = 0x10000;
+
+/// Talc OOM handler, that claims a static arena on the first OOM.
+/// Growing the heap after that is up to [`Heap`]
+struct ClaimArena(Span);
+
+impl OomHandler for ClaimArena {
+    fn handle_oom(talc: &mut Talc<Self>, _layout: Layout) -> Result<(), ()> {
+        let arena = core::mem::replace(&mut talc.oom_handler.0, Span::empty());
+        if arena.is_empty() {
+            return Err(());
+        }
+        unsafe {
+            talc.claim(arena)?;
+        }
+        Ok(())
+    }
+}
+
+/// Part of the heap, that is mapped on demand, in a dedicated virtual memory range
+struct Growth {
+    start: usize,
+    end: usize,
+    heap: Span,
+}
+
+/// Kernel heap, that starts in a static arena and then grows, mapping new pages
+/// using the page allocator. Mapping takes the page table lock and allocates
+/// page tables, so it's done without holding the lock of the allocator itself
+pub struct Heap {
+    talc: Mutex<Talc<ClaimArena>>,
+    /// Heap is grown by one thread at a time
+    growth: Mutex<Growth>,
+}
+
+impl Heap {
+    /// Create a heap that claims the arena on first OOM, and then
+    /// maps memory into [start; start + size) range
+    ///
+    /// # Safety
+    /// The arena must conform to the requirements laid out by [`Talc::claim`],
+    /// and the virtual range must be free and reserved for the heap
+    pub const unsafe fn new(arena: Span, start: usize, size: usize) -> Self {
+        Self {
+            talc: mutex(Talc::new(ClaimArena(arena))),
+            growth: mutex(Growth {
+                start,
+                end: start + size,
+                heap: Span::empty(),
+            }),
+        }
+    }
+
+    /// Get heap usage statistics
+    pub fn stats(&self) -> HeapStats {
+        let mapped = self.growth.lock().heap.size();
+        HeapStats::new(&self.talc.lock(), mapped)
+    }
+
+    /// Map enough memory after the end of the heap for the allocation
+    fn grow(&self, growth: &mut Growth, layout: Layout) -> Result<(), ()> {
+        // Leave some space for chunk tags and alignment
+        let page_size = <crate::arch::Memory as MemoryTrait>::PageSize::MIN.into();
+        let size = (layout.size() + layout.align() + 4 * core::mem::size_of::<usize>())
+            .max(MIN_GROWTH)
+            .next_multiple_of(page_size);
+        let top = match growth.heap.get_base_acme() {
+            Some((_, acme)) => acme as usize,
+            None => growth.start,
+        };
+        if top + size > growth.end {
+            return Err(());
+        }
+
+        crate::arch::Memory::kernel_address_space()
+            .map_alloc(
+                VirtAddr::from_usize(top),
+                size,
+                MappingFlags::PRESENT | MappingFlags::READ | MappingFlags::WRITE,
+                crate::arch::Memory::page_allocator(),
+            )
+            .map_err(|_| ())?;
+
+        let heap = Span::from_base_size(growth.start as _, top + size - growth.start);
+        let mut talc = self.talc.lock();
+        growth.heap = unsafe {
+            if growth.heap.is_empty() {
+                talc.claim(heap)?
+            } else {
+                talc.extend(growth.heap, heap)
+            }
+        };
+        Ok(())
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = unsafe { self.talc.lock().malloc(layout) } {
+            return ptr.as_ptr();
+        }
+
+        // Another thread might have grown the heap, while this one waited for it
+        let mut growth = self.growth.lock();
+        loop {
+            if let Ok(ptr) = unsafe { self.talc.lock().malloc(layout) } {
+                return ptr.as_ptr();
+            }
+            if self.grow(&mut growth, layout).is_err() {
+                return core::ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.talc.lock().free(NonNull::new_unchecked(ptr), layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old = unsafe { NonNull::new_unchecked(ptr) };
+        {
+            let mut talc = self.talc.lock();
+            if new_size <= layout.size() {
+                unsafe { talc.shrink(old, layout, new_size) };
+                return ptr;
+            }
+            if let Ok(ptr) = unsafe { talc.grow_in_place(old, layout, new_size) } {
+                return ptr.as_ptr();
+            }
+        }
+
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new = unsafe { self.alloc(new_layout) };
+        if !new.is_null() {
+            unsafe {
+                new.copy_from_nonoverlapping(ptr, layout.size());
+                self.dealloc(ptr, layout);
+            }
+        }
+        new
+    }
+}
+
+/// Kernel heap usage statistics
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Memory claimed by the heap, including the static arena
+    pub claimed: usize,
+    /// Memory mapped for the growable part of the heap
+    pub mapped: usize,
+    /// Memory allocated by the kernel
+    pub allocated: usize,
+    /// Memory available for allocation without growing the heap
+    pub available: usize,
+    /// Number of live allocations
+    pub allocations: usize,
+}
+
+impl HeapStats {
+    pub fn new<O: OomHandler>(talc: &Talc<O>, mapped: usize) -> Self {
+        let counters = talc.get_counters();
+        Self {
+            claimed: counters.claimed_bytes,
+            mapped,
+            allocated: counters.allocated_bytes,
+            available: counters.available_bytes,
+            allocations: counters.allocation_count,
+        }
+    }
+}
+
+impl core::fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} allocated in {} allocations, {} available, {} claimed ({} mapped)",
+            FormatSize(self.allocated as _),
+            self.allocations,
+            FormatSize(self.available as _),
+            FormatSize(self.claimed as _),
+            FormatSize(self.mapped as _),
+        )
+    }
+}
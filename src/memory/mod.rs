@@ -2,11 +2,44 @@ pub use memory_addr::{pa, va, va_range, MemoryAddr, PhysAddr, VirtAddr};
 
 /// Address space implementations
 pub mod address_space;
+pub use address_space::vma::{FaultError, Vma, VmaBacking, VmaError};
 pub use address_space::{AddressSpaceTrait, MappingError, MappingFlags, MappingResult};
 
+/// Physical memory map, built at boot
+pub mod boot_memory_map;
+pub use boot_memory_map::{BootMemoryMap, RegionKind};
+
+/// Physical page frame database
+pub mod frame;
+pub use frame::{Frame, FrameDatabase, FrameFlags};
+
+/// Growable kernel heap
+pub mod heap;
+pub use heap::HeapStats;
+
+/// Kernel virtual memory allocator
+pub mod vmalloc;
+pub use vmalloc::{vfree, vmalloc};
+
+/// Slab allocator for fixed-size kernel objects
+pub mod slab;
+pub use slab::{CacheStats, ObjectBox, ObjectCache, SlabStats};
+
+/// Memory usage statistics
+pub mod stats;
+pub use stats::MemoryStats;
+
+/// Safe access to user memory
+pub mod user;
+pub use user::{copy_from_user, copy_to_user, UserAccessError, UserPtr, UserSlice};
+
+/// Tests of memory management, that every architecture runs
+#[cfg(feature = "kernel-tests")]
+pub mod tests;
+
 /// Different page allocator implementaitons
 pub mod page_allocator;
-pub use page_allocator::PageAllocatorTrait;
+pub use page_allocator::{AllocConstraints, PageAllocatorTrait, ZoneClass, ZoneStats};
 
 /// Page size trait, implement for an enum (or a struct) that could hold valid page sizes
 pub trait PageSizeTrait: Copy + PartialEq + Eq + TryFrom<usize> + Into<usize> {
@@ -30,8 +63,32 @@ impl core::ops::DerefMut for FormatSize {
     }
 }
 
+/// Formatted size, that is padded as a whole
+struct SizeBuffer {
+    bytes: [u8; 16],
+    len: usize,
+}
+
+impl core::fmt::Write for SizeBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = self
+            .bytes
+            .get_mut(self.len..self.len + s.len())
+            .ok_or(core::fmt::Error)?;
+        bytes.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+/// Width and alignment apply to the whole size, like `{:>12}`
 impl core::fmt::Display for FormatSize {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        use core::fmt::Write;
+        let mut buffer = SizeBuffer {
+            bytes: [0; 16],
+            len: 0,
+        };
         let mut value = self.0;
         let mut order = 0;
         let orders = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
@@ -41,15 +98,17 @@ impl core::fmt::Display for FormatSize {
         }
 
         if value >= 10 {
-            write!(f, "{} {}", value, orders[order])
+            write!(buffer, "{} {}", value, orders[order])?;
         } else {
             write!(
-                f,
+                buffer,
                 "{}.{} {}",
                 value,
                 ((self.0 * 10) >> (order * 10)) % 10,
                 orders[order]
-            )
+            )?;
         }
+        // Only ASCII is written
+        f.pad(core::str::from_utf8(&buffer.bytes[..buffer.len]).unwrap())
     }
 }
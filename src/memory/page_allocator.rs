@@ -1,10 +1,206 @@
-use super::PageSizeTrait;
+use super::{FormatSize, PageSizeTrait};
 use memory_addr::PhysAddr;
 
 pub mod zoned_buddy;
 pub use zoned_buddy::ZonedBuddy;
 
+/// Number of buddy orders reported in [`ZoneStats`]. Order n
+/// is a block of 2^n pages, larger free blocks are split
+pub const MAX_ORDER: usize = 11;
+
+/// Class of a memory zone, defined by the physical addresses it covers.
+/// Classes are ordered by address, allocations try them in [`ZoneClass::PREFERENCE`] order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ZoneClass {
+    /// Memory below 16 MiB, reachable by legacy ISA DMA
+    Dma,
+    /// Memory below 4 GiB, reachable by 32-bit devices
+    Dma32,
+    /// Memory above 4 GiB, below the start of high memory
+    Normal,
+    /// Memory beyond the kernel's direct reach
+    High,
+}
+
+impl ZoneClass {
+    /// All zone classes, from the lowest to the highest
+    pub const ALL: [Self; 4] = [Self::Dma, Self::Dma32, Self::Normal, Self::High];
+
+    /// Order, in which allocations try the classes: normal memory first, then
+    /// high memory, and memory, that DMA capable devices need, last
+    pub const PREFERENCE: [Self; 4] = [Self::Normal, Self::High, Self::Dma32, Self::Dma];
+
+    /// End of ISA DMA memory
+    pub const DMA_END: usize = 0x1000000;
+    /// End of memory reachable by 32-bit devices. Saturates on 32-bit targets
+    pub const DMA32_END: usize = match (u32::MAX as usize).checked_add(1) {
+        Some(end) => end,
+        None => usize::MAX,
+    };
+
+    /// Get class of a physical address, given the start of high memory
+    pub fn of(addr: usize, high_memory: usize) -> Self {
+        if addr < Self::DMA_END {
+            Self::Dma
+        } else if addr >= high_memory {
+            Self::High
+        } else if addr < Self::DMA32_END {
+            Self::Dma32
+        } else {
+            Self::Normal
+        }
+    }
+
+    /// Get the address where memory of this class ends
+    pub fn end(self, high_memory: usize) -> usize {
+        match self {
+            Self::Dma => Self::DMA_END,
+            Self::Dma32 if high_memory < Self::DMA32_END => high_memory,
+            Self::Dma32 => Self::DMA32_END,
+            Self::Normal => high_memory,
+            Self::High => usize::MAX,
+        }
+    }
+}
+
+/// Memory usage of all zones of a class
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZoneStats {
+    pub class: ZoneClass,
+    pub total: usize,
+    pub allocated: usize,
+    /// Number of free blocks of every order
+    pub free_blocks: [usize; MAX_ORDER],
+}
+
+impl ZoneStats {
+    pub const fn new(class: ZoneClass) -> Self {
+        Self {
+            class,
+            total: 0,
+            allocated: 0,
+            free_blocks: [0; MAX_ORDER],
+        }
+    }
+
+    pub fn free(&self) -> usize {
+        self.total - self.allocated
+    }
+}
+
+impl core::fmt::Display for ZoneStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:?}: {} free of {}, free blocks by order:",
+            self.class,
+            FormatSize(self.free() as _),
+            FormatSize(self.total as _)
+        )?;
+        for count in self.free_blocks {
+            write!(f, " {}", count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Constraints for physical memory allocations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocConstraints {
+    /// Highest zone class to allocate from, every class up to it can be used
+    pub class: ZoneClass,
+    /// Allocated memory must end at or below this address
+    pub max_addr: usize,
+    /// Alignment of the allocation (or of every chunk, if not contiguous)
+    pub alignment: usize,
+    /// Allocate a single physically contiguous chunk
+    pub contiguous: bool,
+}
+
+impl AllocConstraints {
+    /// No constraints, any contiguous memory
+    pub const fn new() -> Self {
+        Self {
+            class: ZoneClass::High,
+            max_addr: usize::MAX,
+            alignment: 1,
+            contiguous: true,
+        }
+    }
+
+    /// Memory for legacy ISA DMA
+    pub const fn isa_dma() -> Self {
+        Self::new()
+            .with_class(ZoneClass::Dma)
+            .with_max_addr(ZoneClass::DMA_END)
+    }
+
+    /// Memory for 32-bit DMA capable devices
+    pub const fn dma32() -> Self {
+        Self::new().with_max_addr(ZoneClass::DMA32_END)
+    }
+
+    pub const fn with_class(mut self, class: ZoneClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    pub const fn with_max_addr(mut self, max_addr: usize) -> Self {
+        self.max_addr = max_addr;
+        self
+    }
+
+    pub const fn with_alignment(mut self, alignment: usize) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    pub const fn with_contiguous(mut self, contiguous: bool) -> Self {
+        self.contiguous = contiguous;
+        self
+    }
+}
+
+impl Default for AllocConstraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub trait PageAllocatorTrait<PageSize: PageSizeTrait> {
     fn alloc(&self, size: PageSize) -> Option<PhysAddr>;
     fn free(&self, allocation: PhysAddr, size: PageSize);
+
+    /// Add a reference to an allocation. Shared allocation is only
+    /// freed after [`Self::free`] is called once for every reference.
+    /// Returns false, if there is nowhere to count the references yet
+    fn share(&self, allocation: PhysAddr, size: PageSize) -> bool;
+    /// Returns true if the allocation is referenced more than once
+    fn is_shared(&self, allocation: PhysAddr, size: PageSize) -> bool;
+
+    /// Allocate physically contiguous memory, `pages` pages of
+    /// [`PageSizeTrait::MIN`] size, aligned to `align` bytes.
+    /// The fallback only succeeds if there is a page size that fits
+    fn alloc_contiguous(&self, pages: usize, align: usize) -> Option<PhysAddr> {
+        let size = contiguous_size::<PageSize>(pages, align).ok()?;
+        self.alloc(size)
+    }
+
+    /// Free memory allocated with [`Self::alloc_contiguous`]
+    fn free_contiguous(&self, allocation: PhysAddr, pages: usize, align: usize) {
+        if let Ok(size) = contiguous_size::<PageSize>(pages, align) {
+            self.free(allocation, size);
+        }
+    }
+}
+
+/// Smallest power of two size that holds `pages` pages and is aligned
+fn contiguous_size<PageSize: PageSizeTrait>(
+    pages: usize,
+    align: usize,
+) -> Result<PageSize, <PageSize as TryFrom<usize>>::Error> {
+    let size = (pages * PageSize::MIN.into())
+        .max(align)
+        .next_power_of_two();
+    PageSize::try_from(size)
 }
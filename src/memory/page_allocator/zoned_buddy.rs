@@ -1,9 +1,10 @@
 use crate::arch::traits::*;
 use core::alloc::AllocError;
-use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use super::{PageAllocatorTrait, PageSizeTrait, PhysAddr};
-use crate::sync::RwLock;
+use super::{AllocConstraints, PageAllocatorTrait, PageSizeTrait, PhysAddr};
+use super::{ZoneClass, ZoneStats, MAX_ORDER};
+use crate::sync::{rw_lock, RwLock};
 
 struct CpuId;
 impl lock_free_buddy_allocator::cpuid::Cpu for CpuId {
@@ -15,6 +16,7 @@ impl lock_free_buddy_allocator::cpuid::Cpu for CpuId {
 struct Zone<const PAGE_SIZE: usize> {
     start: usize,
     size: usize,
+    class: ZoneClass,
     allocated: AtomicUsize,
     buddy: lock_free_buddy_allocator::buddy_alloc::BuddyAlloc<
         'static,
@@ -22,23 +24,68 @@ struct Zone<const PAGE_SIZE: usize> {
         CpuId,
         alloc::alloc::Global,
     >,
+    /// Blocks, that the buddy handed out, a bit for each. The buddy
+    /// tree can't be inspected, so free blocks are counted from this
+    used: alloc::boxed::Box<[AtomicUsize]>,
+}
+
+impl<const PAGE_SIZE: usize> Zone<PAGE_SIZE> {
+    fn contains(&self, start: usize, size: usize) -> bool {
+        start >= self.start && start + size <= self.start + self.size
+    }
+
+    /// Indices of the blocks of a range
+    fn blocks(&self, start: usize, size: usize) -> core::ops::Range<usize> {
+        (start - self.start) / PAGE_SIZE..(start - self.start + size) / PAGE_SIZE
+    }
+
+    /// Record, that a range was allocated from the buddy, or freed to it
+    fn mark(&self, start: usize, size: usize, used: bool) {
+        const BITS: usize = usize::BITS as usize;
+        for block in self.blocks(start, size) {
+            let word = &self.used[block / BITS];
+            if used {
+                word.fetch_or(1 << (block % BITS), Ordering::SeqCst);
+            } else {
+                word.fetch_and(!(1 << (block % BITS)), Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn is_free(&self, start: usize, size: usize) -> bool {
+        const BITS: usize = usize::BITS as usize;
+        self.blocks(start, size).all(|block| {
+            self.used[block / BITS].load(Ordering::SeqCst) & (1 << (block % BITS)) == 0
+        })
+    }
 }
 
 /// Zone-based buddy allocator. Manages zones,
 /// each zone having a separate binary buddy,
 /// similar to how linux does this
+/// Every zone belongs to a [`ZoneClass`], allocations try the allowed
+/// classes in [`ZoneClass::PREFERENCE`] order, so DMA memory is used last
 /// Core RwLock is only locked for wiritng when adding zones
 pub struct ZonedBuddy<const BLOCK_SIZE: usize> {
     zones: RwLock<alloc::vec::Vec<Zone<BLOCK_SIZE>>>,
+    high_memory: usize,
 }
 
 impl<const BLOCK_SIZE: usize> ZonedBuddy<BLOCK_SIZE> {
     pub const fn new() -> Self {
         Self {
-            zones: RwLock::new(alloc::vec::Vec::new()),
+            zones: rw_lock(alloc::vec::Vec::new()),
+            high_memory: usize::MAX,
         }
     }
 
+    /// Set physical address where high memory starts. Memory
+    /// above it is only used when normal memory is exhausted
+    pub const fn with_high_memory(mut self, start: usize) -> Self {
+        self.high_memory = start;
+        self
+    }
+
     pub fn add_zone(&self, start: usize, size: usize) -> Result<(), AllocError> {
         debug_assert!(
             start % BLOCK_SIZE == 0,
@@ -47,55 +94,239 @@ impl<const BLOCK_SIZE: usize> ZonedBuddy<BLOCK_SIZE> {
         );
         debug_assert!(size % BLOCK_SIZE == 0, "size is not aligned ({:#x})", size);
 
-        if !size.is_power_of_two() {
-            let mut start = start;
-            for bit in 0..usize::BITS {
-                let size_p2 = 1 << bit;
-                if size & size_p2 != 0 {
-                    self.add_zone(start, size_p2)?;
-                    start += size_p2;
-                }
+        // Zones are naturally aligned, so that buddy blocks are
+        // aligned to their size, and never cross class boundaries
+        let end = start + size;
+        let mut start = start;
+        while start < end {
+            let class = ZoneClass::of(start, self.high_memory);
+            let limit = end.min(class.end(self.high_memory));
+            let mut size = 1 << (limit - start).ilog2();
+            if start != 0 {
+                size = size.min(1 << start.trailing_zeros());
             }
-        } else {
-            self.zones.write().push(Zone {
-                start,
-                size,
-                allocated: AtomicUsize::new(0),
-                buddy: lock_free_buddy_allocator::buddy_alloc::BuddyAlloc::new(
-                    start,
-                    size / BLOCK_SIZE,
-                    &alloc::alloc::Global,
-                )
-                .ok_or(AllocError)?,
-            });
+            // Buddy of a single block has no nodes to split, it can't be built.
+            // At most a block at each end of an unaligned range is lost
+            if size > BLOCK_SIZE {
+                self.add_buddy(start, size, class)?;
+            }
+            start += size;
         }
         Ok(())
     }
 
+    fn add_buddy(&self, start: usize, size: usize, class: ZoneClass) -> Result<(), AllocError> {
+        // Kernel heap might need to allocate pages to grow, so
+        // never allocate while holding the lock for writing
+        let words = (size / BLOCK_SIZE).div_ceil(usize::BITS as usize);
+        let mut used = alloc::vec::Vec::new();
+        used.try_reserve_exact(words).map_err(|_| AllocError)?;
+        used.resize_with(words, || AtomicUsize::new(0));
+        let zone = Zone {
+            start,
+            size,
+            class,
+            allocated: AtomicUsize::new(0),
+            buddy: lock_free_buddy_allocator::buddy_alloc::BuddyAlloc::new(
+                start,
+                size / BLOCK_SIZE,
+                &alloc::alloc::Global,
+            )
+            .ok_or(AllocError)?,
+            used: used.into_boxed_slice(),
+        };
+        self.reserve_zone()?;
+        self.zones.write().push(zone);
+        Ok(())
+    }
+
+    /// Make sure there is space for one more zone
+    fn reserve_zone(&self) -> Result<(), AllocError> {
+        loop {
+            let capacity = {
+                let zones = self.zones.read();
+                if zones.len() < zones.capacity() {
+                    return Ok(());
+                }
+                zones.capacity()
+            };
+
+            let mut grown = alloc::vec::Vec::new();
+            grown
+                .try_reserve_exact(capacity * 2 + 4)
+                .map_err(|_| AllocError)?;
+            let mut zones = self.zones.write();
+            if zones.capacity() == capacity {
+                grown.append(&mut zones);
+                core::mem::swap(&mut *zones, &mut grown);
+            }
+            // Freeing the old vector might need the heap, that waits
+            // for the zones to grow, so it's freed after unlocking
+            drop(zones);
+            drop(grown);
+        }
+    }
+
     pub fn alloc(&self, size: usize) -> Option<PhysAddr> {
+        self.alloc_block(size, &AllocConstraints::new())
+    }
+
+    /// Size of a block, that is allocated for a request. Buddy blocks are
+    /// a power of two in size, and aligned to their size
+    fn block_size(size: usize, alignment: usize) -> usize {
+        size.max(alignment).max(BLOCK_SIZE).next_power_of_two()
+    }
+
+    /// Allocate a single block satisfying the constraints, whether they
+    /// ask for contiguous memory or not. Freed with [`Self::free`]
+    pub fn alloc_block(&self, size: usize, constraints: &AllocConstraints) -> Option<PhysAddr> {
+        let size = Self::block_size(size, constraints.alignment);
         let blocks = size / BLOCK_SIZE;
-        for zone in self.zones.read().iter() {
-            if let Some(addr) = zone.buddy.alloc(blocks) {
-                zone.allocated
-                    .fetch_add(size, core::sync::atomic::Ordering::SeqCst);
-                return Some(PhysAddr::from_usize(addr));
+        let zones = self.zones.read();
+        for class in ZoneClass::PREFERENCE {
+            if class > constraints.class {
+                continue;
+            }
+            for zone in zones.iter() {
+                if zone.class != class || zone.size < size || zone.start >= constraints.max_addr {
+                    continue;
+                }
+                let Some(addr) = zone.buddy.alloc(blocks) else {
+                    continue;
+                };
+                if addr + size > constraints.max_addr {
+                    zone.buddy.free(addr, blocks);
+                    continue;
+                }
+                zone.mark(addr, size, true);
+                zone.allocated.fetch_add(size, Ordering::SeqCst);
+                let addr = PhysAddr::from_usize(addr);
+                if let Some(frames) = crate::memory::frame::frames() {
+                    frames.allocate(addr, size);
+                }
+                return Some(addr);
             }
         }
         None
     }
 
+    /// Allocate memory satisfying the constraints. Returns allocated
+    /// chunks and their sizes, a single one if the allocation is contiguous.
+    /// Sizes are rounded up to a power of two, every chunk must be
+    /// freed with [`Self::free`] using the returned size
+    pub fn alloc_constrained(
+        &self,
+        size: usize,
+        constraints: &AllocConstraints,
+    ) -> Option<alloc::vec::Vec<(PhysAddr, usize)>> {
+        if constraints.contiguous {
+            let addr = self.alloc_block(size, constraints)?;
+            return Some(alloc::vec![(
+                addr,
+                Self::block_size(size, constraints.alignment)
+            )]);
+        }
+
+        let mut chunks = alloc::vec::Vec::new();
+        let mut remaining = size.next_multiple_of(BLOCK_SIZE);
+        while remaining > 0 {
+            // Try to get the biggest chunk first
+            let mut chunk = 1 << remaining.ilog2();
+            let addr = loop {
+                if let Some(addr) = self.alloc_block(chunk, constraints) {
+                    break addr;
+                }
+                if chunk <= BLOCK_SIZE.max(constraints.alignment) {
+                    for (addr, size) in chunks {
+                        self.free(addr, size);
+                    }
+                    return None;
+                }
+                chunk /= 2;
+            };
+            let chunk = Self::block_size(chunk, constraints.alignment);
+            chunks.push((addr, chunk));
+            remaining -= chunk.min(remaining);
+        }
+        Some(chunks)
+    }
+
+    /// Get memory usage of every zone class
+    pub fn zone_stats(&self) -> [ZoneStats; ZoneClass::ALL.len()] {
+        let mut stats = ZoneClass::ALL.map(ZoneStats::new);
+        for zone in self.zones.read().iter() {
+            let stats = &mut stats[zone.class as usize];
+            stats.total += zone.size;
+            stats.allocated += zone.allocated.load(Ordering::SeqCst);
+            Self::count_free_blocks(zone, &mut stats.free_blocks);
+        }
+        stats
+    }
+
+    /// Split free memory of a zone into the biggest aligned blocks, like the buddy does
+    fn count_free_blocks(zone: &Zone<BLOCK_SIZE>, free_blocks: &mut [usize; MAX_ORDER]) {
+        let end = zone.start + zone.size;
+        let mut addr = zone.start;
+        while addr < end {
+            if !zone.is_free(addr, BLOCK_SIZE) {
+                addr += BLOCK_SIZE;
+                continue;
+            }
+            let mut order = 0;
+            while order + 1 < MAX_ORDER {
+                let size = BLOCK_SIZE << (order + 1);
+                if !(addr - zone.start).is_multiple_of(size)
+                    || addr + size > end
+                    || !zone.is_free(addr, size)
+                {
+                    break;
+                }
+                order += 1;
+            }
+            free_blocks[order] += 1;
+            addr += BLOCK_SIZE << order;
+        }
+    }
+
     pub fn free(&self, allocation: PhysAddr, size: usize) {
         let start = allocation.as_usize();
+        let size = Self::block_size(size, BLOCK_SIZE);
         let blocks = size / BLOCK_SIZE;
         for zone in self.zones.read().iter() {
-            if start >= zone.start && start + size <= zone.start + zone.size {
-                zone.buddy.free(allocation.as_usize(), blocks);
-                zone.allocated
-                    .fetch_sub(size, core::sync::atomic::Ordering::SeqCst);
+            if zone.contains(start, size) {
+                if let Some(frames) = crate::memory::frame::frames() {
+                    if !frames.release(allocation, size) {
+                        continue;
+                    }
+                }
+
+                // Block is marked free first, it can be allocated again right after
+                zone.mark(start, size, false);
+                zone.buddy.free(start, blocks);
+                zone.allocated.fetch_sub(size, Ordering::SeqCst);
             }
         }
     }
 
+    /// Add a reference to an allocated block. It will only
+    /// be freed after [`Self::free`] is called for every reference.
+    /// References are kept in the frame database, returns
+    /// false if it's not initialized yet
+    pub fn share(&self, allocation: PhysAddr) -> bool {
+        let Some(frames) = crate::memory::frame::frames() else {
+            return false;
+        };
+        frames.share(allocation);
+        true
+    }
+
+    /// Returns true if the block is referenced more than once
+    pub fn is_shared(&self, allocation: PhysAddr) -> bool {
+        crate::memory::frame::frames()
+            .and_then(|frames| frames.get(allocation))
+            .is_some_and(|frame| frame.references() > 1)
+    }
+
     /// Returns total amount of memory managed by the allocator.
     /// To get free space, use [`Self::total_memory`] - [`Self::allocated_memory`]
     pub fn total_memory(&self) -> usize {
@@ -108,7 +339,7 @@ impl<const BLOCK_SIZE: usize> ZonedBuddy<BLOCK_SIZE> {
     /// Returns the amount of allocated memory
     pub fn allocated_memory(&self) -> usize {
         self.zones.read().iter().fold(0, |acc, zone| {
-            acc + zone.allocated.load(core::sync::atomic::Ordering::SeqCst)
+            acc + zone.allocated.load(Ordering::SeqCst)
         })
     }
 }
@@ -129,4 +360,26 @@ impl<const BLOCK_SIZE: usize, PageSize: PageSizeTrait> PageAllocatorTrait<PageSi
     fn free(&self, allocation: PhysAddr, size: PageSize) {
         self.free(allocation, size.into())
     }
+
+    fn share(&self, allocation: PhysAddr, _size: PageSize) -> bool {
+        self.share(allocation)
+    }
+
+    fn is_shared(&self, allocation: PhysAddr, _size: PageSize) -> bool {
+        self.is_shared(allocation)
+    }
+
+    fn alloc_contiguous(&self, pages: usize, align: usize) -> Option<PhysAddr> {
+        self.alloc_block(
+            pages * PageSize::MIN.into(),
+            &AllocConstraints::new().with_alignment(align),
+        )
+    }
+
+    fn free_contiguous(&self, allocation: PhysAddr, pages: usize, align: usize) {
+        self.free(
+            allocation,
+            Self::block_size(pages * PageSize::MIN.into(), align),
+        )
+    }
 }
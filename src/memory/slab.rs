@@ -0,0 +1,546 @@
+use crate::arch::traits::*;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{FormatSize, VirtAddr};
+use crate::sync::{mutex, Mutex};
+
+/// Size of a single slab
+const SLAB_SIZE: usize = memory_addr::PAGE_SIZE_4K;
+/// Number of objects a per-CPU magazine can hold
+const MAGAZINE_SIZE: usize = 16;
+/// Number of empty slabs a cache keeps around before returning them
+const MAX_EMPTY_SLABS: usize = 2;
+/// Byte freed objects are filled with in debug builds
+#[cfg(debug_assertions)]
+const POISON: u8 = 0x6b;
+
+/// Memory used by slabs of all caches
+static SLAB_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// Caches that report their statistics, see [`ObjectCache::register`]
+static CACHES: Mutex<alloc::vec::Vec<&'static dyn StatsSource>> = mutex(alloc::vec::Vec::new());
+
+/// Allocate a new slab from the page allocator. It may have to map the page,
+/// so it's called without holding the locks of the cache
+fn alloc_slab() -> Option<NonNull<u8>> {
+    let slab = crate::arch::Memory::alloc_kernel_page()?;
+    SLAB_MEMORY.fetch_add(SLAB_SIZE, Ordering::Relaxed);
+    NonNull::new(slab.as_mut_ptr())
+}
+
+/// Return memory of a slab to the page allocator
+fn free_slab(slab: NonNull<u8>) {
+    crate::arch::Memory::free_kernel_page(VirtAddr::from_mut_ptr_of(slab.as_ptr()));
+    SLAB_MEMORY.fetch_sub(SLAB_SIZE, Ordering::Relaxed);
+}
+
+/// Slab allocator usage
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Memory used by slabs of all caches, registered or not
+    pub memory: usize,
+    /// Statistics of registered caches
+    pub caches: alloc::vec::Vec<CacheStats>,
+}
+
+/// Get slab allocator usage
+pub fn stats() -> SlabStats {
+    SlabStats {
+        memory: SLAB_MEMORY.load(Ordering::Relaxed),
+        caches: CACHES.lock().iter().map(|cache| cache.stats()).collect(),
+    }
+}
+
+/// Type-erased cache, to keep caches of different types in the registry
+trait StatsSource: Sync {
+    fn stats(&self) -> CacheStats;
+}
+
+impl<T: Send> StatsSource for ObjectCache<T> {
+    fn stats(&self) -> CacheStats {
+        self.stats()
+    }
+}
+
+// -------------------------------- Slabs
+/// Object on a free list of a slab
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Slab header, placed at the start of every slab
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+/// Slabs with free objects and counters, shared between all CPUs
+struct Depot {
+    /// Doubly-linked list of slabs that have free objects
+    partial: Option<NonNull<Slab>>,
+    /// Empty slabs, that are taken out of the cache, to be freed without holding it's locks
+    released: Option<NonNull<Slab>>,
+    slabs: usize,
+    empty: usize,
+}
+
+unsafe impl Send for Depot {}
+
+/// Per-CPU stack of free objects, to avoid locking the depot on every allocation
+struct Magazine {
+    objects: [Option<NonNull<u8>>; MAGAZINE_SIZE],
+    count: usize,
+}
+
+unsafe impl Send for Magazine {}
+
+impl Magazine {
+    const EMPTY: Self = Self {
+        objects: [None; MAGAZINE_SIZE],
+        count: 0,
+    };
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        self.objects[self.count].take()
+    }
+
+    fn push(&mut self, object: NonNull<u8>) -> bool {
+        if self.count == MAGAZINE_SIZE {
+            return false;
+        }
+        self.objects[self.count] = Some(object);
+        self.count += 1;
+        true
+    }
+}
+
+/// Object cache statistics
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// Size of a single object, including padding
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    /// Objects currently allocated
+    pub active: usize,
+    pub allocations: usize,
+    /// Allocations served from per-CPU magazines
+    pub magazine_hits: usize,
+}
+
+impl CacheStats {
+    /// Memory used by slabs of the cache
+    pub fn memory(&self) -> usize {
+        self.slabs * SLAB_SIZE
+    }
+}
+
+impl core::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}: {} objects of {} bytes active, {} slabs ({}), {} allocations ({} from magazines)",
+            self.name,
+            self.active,
+            self.object_size,
+            self.slabs,
+            FormatSize(self.memory() as _),
+            self.allocations,
+            self.magazine_hits,
+        )
+    }
+}
+
+/// Slab allocator for objects of type T, with per-CPU magazines.
+/// Objects are allocated from page-sized slabs, allocated with [`MemoryTrait::alloc_kernel_page`]
+pub struct ObjectCache<T> {
+    name: &'static str,
+    constructor: Option<fn() -> T>,
+    destructor: Option<fn(&mut T)>,
+    depot: Mutex<Depot>,
+    magazines: [Mutex<Magazine>; <crate::arch::Cpu as CpuTrait>::MAX_CPUS],
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    magazine_hits: AtomicUsize,
+    _phantom: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for ObjectCache<T> {}
+unsafe impl<T: Send> Sync for ObjectCache<T> {}
+
+impl<T> ObjectCache<T> {
+    const ALIGN: usize = {
+        let align = core::mem::align_of::<T>();
+        let link_align = core::mem::align_of::<FreeObject>();
+        if align > link_align {
+            align
+        } else {
+            link_align
+        }
+    };
+    /// Object size, including padding and space for a free list link
+    const OBJECT_SIZE: usize = {
+        let size = core::mem::size_of::<T>();
+        let link_size = core::mem::size_of::<FreeObject>();
+        let size = if size > link_size { size } else { link_size };
+        size.next_multiple_of(Self::ALIGN)
+    };
+    /// Offset of the first object in a slab
+    const FIRST_OBJECT: usize = core::mem::size_of::<Slab>().next_multiple_of(Self::ALIGN);
+    const OBJECTS_PER_SLAB: usize = {
+        assert!(
+            Self::FIRST_OBJECT + Self::OBJECT_SIZE <= SLAB_SIZE,
+            "object is too big for a slab"
+        );
+        (SLAB_SIZE - Self::FIRST_OBJECT) / Self::OBJECT_SIZE
+    };
+
+    /// Create a new cache. Name is used for statistics
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            constructor: None,
+            destructor: None,
+            depot: mutex(Depot {
+                partial: None,
+                released: None,
+                slabs: 0,
+                empty: 0,
+            }),
+            magazines: [const { mutex(Magazine::EMPTY) }; <crate::arch::Cpu as CpuTrait>::MAX_CPUS],
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            magazine_hits: AtomicUsize::new(0),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Set a constructor, used by [`Self::alloc`]
+    pub const fn with_constructor(mut self, constructor: fn() -> T) -> Self {
+        self.constructor = Some(constructor);
+        self
+    }
+
+    /// Set a destructor, called on every object before it's dropped and freed
+    pub const fn with_destructor(mut self, destructor: fn(&mut T)) -> Self {
+        self.destructor = Some(destructor);
+        self
+    }
+
+    /// Add the cache to the registry, to report it's statistics in [`stats`]
+    pub fn register(&'static self)
+    where
+        T: Send,
+    {
+        let mut caches = CACHES.lock();
+        if !caches.iter().any(|&cache| core::ptr::addr_eq(cache, self)) {
+            caches.push(self);
+        }
+    }
+
+    /// Allocate an object, initialized by the constructor. Panics if there is no constructor
+    pub fn alloc(&self) -> Option<ObjectBox<'_, T>> {
+        let constructor = self
+            .constructor
+            .unwrap_or_else(|| panic!("object cache {} has no constructor", self.name));
+        self.alloc_with(constructor())
+    }
+
+    /// Allocate an object, initialized with value
+    pub fn alloc_with(&self, value: T) -> Option<ObjectBox<'_, T>> {
+        let object = self.alloc_uninit()?;
+        let object = object.cast::<T>();
+        unsafe {
+            object.write(value);
+        }
+        Some(ObjectBox {
+            cache: self,
+            object,
+        })
+    }
+
+    /// Allocate memory for an object
+    pub fn alloc_uninit(&self) -> Option<NonNull<MaybeUninit<T>>> {
+        let object = loop {
+            if let Some(object) = self.take_cached() {
+                break object;
+            }
+            let slab = alloc_slab()?;
+            self.add_slab(&mut self.depot.lock(), slab);
+        };
+
+        #[cfg(debug_assertions)]
+        self.check_poison(object);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Some(object.cast())
+    }
+
+    /// Free memory of an object, allocated with [`Self::alloc_uninit`]
+    ///
+    /// # Safety
+    /// Object must be allocated from this cache, and must not be used after this call
+    pub unsafe fn free_uninit(&self, object: NonNull<MaybeUninit<T>>) {
+        let object = object.cast::<u8>();
+        #[cfg(debug_assertions)]
+        unsafe {
+            object.add(core::mem::size_of::<FreeObject>()).write_bytes(
+                POISON,
+                Self::OBJECT_SIZE - core::mem::size_of::<FreeObject>(),
+            );
+        }
+        self.frees.fetch_add(1, Ordering::Relaxed);
+
+        let cpu = crate::arch::Cpu::cpu_id();
+        let mut magazine = self.magazines[cpu].lock();
+        if !magazine.push(object) {
+            let mut depot = self.depot.lock();
+            while magazine.count > MAGAZINE_SIZE / 2 {
+                let object = magazine.pop().unwrap();
+                self.return_object(&mut depot, object);
+            }
+            magazine.push(object);
+            drop(depot);
+            drop(magazine);
+            self.free_released();
+        }
+    }
+
+    /// Return all objects cached in magazines of all CPUs, and release empty slabs
+    pub fn shrink(&self) {
+        for magazine in self.magazines.iter() {
+            let mut magazine = magazine.lock();
+            let mut depot = self.depot.lock();
+            while let Some(object) = magazine.pop() {
+                self.return_object(&mut depot, object);
+            }
+        }
+
+        let mut depot = self.depot.lock();
+        let mut slab = depot.partial;
+        while let Some(current) = slab {
+            slab = unsafe { current.as_ref().next };
+            if unsafe { current.as_ref().in_use } == 0 {
+                self.release_slab(&mut depot, current);
+            }
+        }
+        drop(depot);
+        self.free_released();
+    }
+
+    /// Get cache statistics
+    pub fn stats(&self) -> CacheStats {
+        let allocations = self.allocations.load(Ordering::Relaxed);
+        CacheStats {
+            name: self.name,
+            object_size: Self::OBJECT_SIZE,
+            objects_per_slab: Self::OBJECTS_PER_SLAB,
+            slabs: self.depot.lock().slabs,
+            active: allocations - self.frees.load(Ordering::Relaxed),
+            allocations,
+            magazine_hits: self.magazine_hits.load(Ordering::Relaxed),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_poison(&self, object: NonNull<u8>) {
+        let poisoned = unsafe {
+            core::slice::from_raw_parts(
+                object.add(core::mem::size_of::<FreeObject>()).as_ptr(),
+                Self::OBJECT_SIZE - core::mem::size_of::<FreeObject>(),
+            )
+        };
+        if let Some(offset) = poisoned.iter().position(|&byte| byte != POISON) {
+            panic!(
+                "object at {:p} in cache {} was modified after free (offset {})",
+                object,
+                self.name,
+                offset + core::mem::size_of::<FreeObject>()
+            );
+        }
+    }
+
+    // -------------------------------- Slab management
+    /// Take an object from the magazine of this CPU, refilling it from the depot
+    fn take_cached(&self) -> Option<NonNull<u8>> {
+        let cpu = crate::arch::Cpu::cpu_id();
+        let mut magazine = self.magazines[cpu].lock();
+        if let Some(object) = magazine.pop() {
+            self.magazine_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(object);
+        }
+
+        let mut depot = self.depot.lock();
+        while magazine.count < MAGAZINE_SIZE / 2 {
+            match self.take_object(&mut depot) {
+                Some(object) => magazine.push(object),
+                None => break,
+            };
+        }
+        magazine.pop()
+    }
+
+    fn take_object(&self, depot: &mut Depot) -> Option<NonNull<u8>> {
+        let mut slab = depot.partial?;
+
+        let slab_ref = unsafe { slab.as_mut() };
+        let object = slab_ref.free.unwrap();
+        slab_ref.free = unsafe { object.as_ref().next };
+        if slab_ref.in_use == 0 {
+            depot.empty -= 1;
+        }
+        slab_ref.in_use += 1;
+        if slab_ref.free.is_none() {
+            Self::unlink(depot, slab);
+        }
+        Some(object.cast())
+    }
+
+    fn return_object(&self, depot: &mut Depot, object: NonNull<u8>) {
+        let slab_addr = memory_addr::align_down(object.as_ptr() as usize, SLAB_SIZE);
+        let mut slab = NonNull::new(slab_addr as *mut Slab).unwrap();
+        let slab_ref = unsafe { slab.as_mut() };
+
+        let was_full = slab_ref.free.is_none();
+        let mut object = object.cast::<FreeObject>();
+        unsafe {
+            object.as_mut().next = slab_ref.free;
+        }
+        slab_ref.free = Some(object);
+        slab_ref.in_use -= 1;
+        if was_full {
+            Self::link(depot, slab);
+        }
+
+        if slab_ref.in_use == 0 {
+            depot.empty += 1;
+            if depot.empty > MAX_EMPTY_SLABS {
+                self.release_slab(depot, slab);
+            }
+        }
+    }
+
+    fn add_slab(&self, depot: &mut Depot, memory: NonNull<u8>) {
+        let slab = memory.cast::<Slab>();
+
+        // Build a free list of all objects in the slab
+        let mut free = None;
+        for index in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object = unsafe { memory.add(Self::FIRST_OBJECT + index * Self::OBJECT_SIZE) };
+            #[cfg(debug_assertions)]
+            unsafe {
+                object.write_bytes(POISON, Self::OBJECT_SIZE);
+            }
+            let object = object.cast::<FreeObject>();
+            unsafe {
+                object.write(FreeObject { next: free });
+            }
+            free = Some(object);
+        }
+
+        unsafe {
+            slab.write(Slab {
+                prev: None,
+                next: None,
+                free,
+                in_use: 0,
+            });
+        }
+        depot.slabs += 1;
+        depot.empty += 1;
+        Self::link(depot, slab);
+    }
+
+    /// Take an empty slab out of the cache, it's freed by [`Self::free_released`]
+    fn release_slab(&self, depot: &mut Depot, mut slab: NonNull<Slab>) {
+        Self::unlink(depot, slab);
+        depot.slabs -= 1;
+        depot.empty -= 1;
+        unsafe {
+            slab.as_mut().next = depot.released;
+        }
+        depot.released = Some(slab);
+    }
+
+    /// Free slabs, that were released, after the locks of the cache are dropped
+    fn free_released(&self) {
+        let mut slab = self.depot.lock().released.take();
+        while let Some(current) = slab {
+            slab = unsafe { current.as_ref().next };
+            free_slab(current.cast());
+        }
+    }
+
+    fn link(depot: &mut Depot, mut slab: NonNull<Slab>) {
+        unsafe {
+            slab.as_mut().prev = None;
+            slab.as_mut().next = depot.partial;
+            if let Some(mut next) = depot.partial {
+                next.as_mut().prev = Some(slab);
+            }
+        }
+        depot.partial = Some(slab);
+    }
+
+    fn unlink(depot: &mut Depot, mut slab: NonNull<Slab>) {
+        let slab = unsafe { slab.as_mut() };
+        match slab.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = slab.next },
+            None => depot.partial = slab.next,
+        }
+        if let Some(mut next) = slab.next {
+            unsafe {
+                next.as_mut().prev = slab.prev;
+            }
+        }
+        slab.prev = None;
+        slab.next = None;
+    }
+}
+
+/// Owned object, allocated from an [`ObjectCache`]. Returned to the cache when dropped
+pub struct ObjectBox<'a, T> {
+    cache: &'a ObjectCache<T>,
+    object: NonNull<T>,
+}
+
+impl<T> core::ops::Deref for ObjectBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> core::ops::DerefMut for ObjectBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T> Drop for ObjectBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(destructor) = self.cache.destructor {
+                destructor(self.object.as_mut());
+            }
+            self.object.drop_in_place();
+            self.cache.free_uninit(self.object.cast());
+        }
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for ObjectBox<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&**self, f)
+    }
+}
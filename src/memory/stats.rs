@@ -0,0 +1,66 @@
+use super::boot_memory_map::RegionKind;
+use super::page_allocator::{ZoneClass, ZoneStats};
+use super::slab::SlabStats;
+use super::{FormatSize, HeapStats};
+
+/// Memory usage of the whole system
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Page allocator usage, by zone class
+    pub zones: [ZoneStats; ZoneClass::ALL.len()],
+    /// Memory used by page tables
+    pub page_tables: usize,
+    pub heap: HeapStats,
+    pub slab: SlabStats,
+    /// Memory that is not managed by the page allocator, by boot memory map region kind
+    pub reserved: alloc::vec::Vec<(RegionKind, usize)>,
+}
+
+impl MemoryStats {
+    /// Memory managed by the page allocator
+    pub fn total(&self) -> usize {
+        self.zones.iter().map(|zone| zone.total).sum()
+    }
+
+    /// Memory allocated from the page allocator
+    pub fn allocated(&self) -> usize {
+        self.zones.iter().map(|zone| zone.allocated).sum()
+    }
+
+    pub fn free(&self) -> usize {
+        self.total() - self.allocated()
+    }
+}
+
+/// Formatted like linux's /proc/meminfo, one value per line,
+/// followed by a line for every zone and every slab cache
+impl core::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let line = |f: &mut core::fmt::Formatter<'_>, name: &str, value: usize| {
+            writeln!(
+                f,
+                "{:<25}{:>12}",
+                alloc::format!("{}:", name),
+                FormatSize(value as _)
+            )
+        };
+
+        line(f, "MemTotal", self.total())?;
+        line(f, "MemFree", self.free())?;
+        line(f, "MemUsed", self.allocated())?;
+        line(f, "PageTables", self.page_tables)?;
+        line(f, "HeapMapped", self.heap.mapped)?;
+        line(f, "HeapAllocated", self.heap.allocated)?;
+        line(f, "Slab", self.slab.memory)?;
+        for &(kind, size) in self.reserved.iter() {
+            line(f, &alloc::format!("Reserved({})", kind.name()), size)?;
+        }
+        for zone in self.zones.iter().filter(|zone| zone.total > 0) {
+            writeln!(f, "{}", zone)?;
+        }
+        for cache in self.slab.caches.iter() {
+            writeln!(f, "{}", cache)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+use crate::arch::traits::*;
+
+pub fn run() {
+    test_boot_memory_map();
+    test_frame_database();
+    test_heap();
+    test_slab();
+    test_memory_stats();
+    test_write_execute();
+    test_fork();
+    test_user_access();
+    test_demand_paging();
+    test_large_pages();
+}
+
+fn test_boot_memory_map() {
+    use crate::memory::*;
+
+    let mut map = BootMemoryMap::new();
+    map.add(pa!(0), pa!(0x10000), RegionKind::Free).unwrap();
+    map.add(pa!(0x10000), pa!(0x20000), RegionKind::Free)
+        .unwrap();
+    map.add(pa!(0x8000), pa!(0x9000), RegionKind::Module)
+        .unwrap();
+    map.add(pa!(0x1f000), pa!(0x30000), RegionKind::Reserved)
+        .unwrap();
+    map.sanitize().unwrap();
+    let regions = |map: &BootMemoryMap| -> alloc::vec::Vec<_> {
+        map.regions()
+            .iter()
+            .map(|region| (region.start.as_usize(), region.end.as_usize(), region.kind))
+            .collect()
+    };
+    assert_eq!(
+        regions(&map),
+        [
+            (0, 0x8000, RegionKind::Free),
+            (0x8000, 0x9000, RegionKind::Module),
+            (0x9000, 0x1f000, RegionKind::Free),
+            (0x1f000, 0x30000, RegionKind::Reserved),
+        ]
+    );
+    // Freed module is merged with the memory around it
+    map.convert(RegionKind::Module, RegionKind::Free);
+    assert_eq!(
+        regions(&map),
+        [
+            (0, 0x1f000, RegionKind::Free),
+            (0x1f000, 0x30000, RegionKind::Reserved),
+        ]
+    );
+
+    // ACPI memory becomes free, once it's released, releasing it again changes nothing
+    let total = crate::arch::Memory::page_allocator().total_memory();
+    crate::arch::Memory::release_acpi_memory();
+    let released = crate::arch::Memory::page_allocator().total_memory();
+    assert!(released >= total);
+    crate::arch::Memory::release_acpi_memory();
+    assert_eq!(
+        crate::arch::Memory::page_allocator().total_memory(),
+        released
+    );
+    let map = crate::arch::Memory::boot_memory_map();
+    crate::println!(
+        "Free memory at boot: {}",
+        FormatSize(map.total(RegionKind::Free) as _)
+    );
+    assert_eq!(map.total(RegionKind::AcpiReclaimable), 0);
+    assert!(map
+        .regions()
+        .windows(2)
+        .all(|pair| pair[0].end <= pair[1].start
+            && !(pair[0].end == pair[1].start && pair[0].kind == pair[1].kind)));
+}
+
+fn test_frame_database() {
+    use crate::memory::*;
+    let page_allocator = crate::arch::Memory::page_allocator();
+    let frames = frame::frames().unwrap();
+    let page_size = <crate::arch::Memory as MemoryTrait>::PageSize::MIN.into();
+
+    let page = page_allocator.alloc(page_size).unwrap();
+    let frame = frames.get(page).unwrap();
+    assert!(frame.flags().contains(FrameFlags::ALLOCATED));
+    assert_eq!(frame.references(), 1);
+
+    assert!(page_allocator.share(page));
+    assert!(page_allocator.is_shared(page));
+    page_allocator.free(page, page_size);
+    assert!(frame.flags().contains(FrameFlags::ALLOCATED));
+    page_allocator.free(page, page_size);
+    assert!(!frame.flags().contains(FrameFlags::ALLOCATED));
+
+    let kernel = crate::arch::Memory::boot_memory_map()
+        .regions()
+        .iter()
+        .find(|region| region.kind == RegionKind::Kernel)
+        .unwrap()
+        .start;
+    assert!(frames
+        .get(kernel)
+        .unwrap()
+        .flags()
+        .contains(FrameFlags::RESERVED));
+}
+
+fn test_heap() {
+    let before = crate::arch::Memory::heap_stats();
+    crate::println!("Kernel heap: {}", before);
+    let big = alloc::vec![42u8; 0x100000];
+    let after = crate::arch::Memory::heap_stats();
+    crate::println!("Kernel heap: {}", after);
+    assert!(after.mapped >= before.mapped + big.len());
+    assert!(big.iter().all(|&byte| byte == 42));
+}
+
+fn test_slab() {
+    use crate::memory::ObjectCache;
+
+    #[derive(Debug)]
+    struct Object {
+        id: usize,
+        data: [u32; 7],
+    }
+
+    static CACHE: ObjectCache<Object> = ObjectCache::new("test").with_constructor(|| Object {
+        id: 0,
+        data: [42; 7],
+    });
+
+    CACHE.register();
+    let mut objects = alloc::vec::Vec::new();
+    for id in 0..1000 {
+        let mut object = CACHE.alloc().unwrap();
+        assert_eq!(object.data, [42; 7]);
+        object.id = id;
+        objects.push(object);
+    }
+    crate::println!("{}", CACHE.stats());
+    for (id, object) in objects.iter().enumerate() {
+        assert_eq!(object.id, id);
+    }
+    drop(objects);
+    CACHE.shrink();
+    crate::println!("{}", CACHE.stats());
+    assert_eq!(CACHE.stats().slabs, 0);
+}
+
+fn test_memory_stats() {
+    let stats = crate::arch::Memory::memory_stats();
+    crate::println!("{}", stats);
+    assert!(stats.page_tables > 0);
+    assert!(stats.slab.caches.iter().any(|cache| cache.name == "test"));
+    assert_eq!(
+        stats.total(),
+        crate::arch::Memory::page_allocator().total_memory()
+    );
+}
+
+fn test_write_execute() {
+    use crate::memory::*;
+
+    let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+    assert!(matches!(
+        vmalloc(4096, flags),
+        Err(MappingError::WritableExecutable(_))
+    ));
+    let page = vmalloc(4096, flags | MappingFlags::WRITE_EXECUTE).unwrap();
+    vfree(page).unwrap();
+}
+
+fn test_fork() {
+    use crate::memory::*;
+    let page_allocator = crate::arch::Memory::page_allocator();
+    let parent = crate::arch::Memory::kernel_address_space();
+
+    // User memory can't be accessed directly, if the CPU protects it
+    let test = UserPtr::<u32>::new(crate::arch::Memory::user_range().0 + 0x40000000);
+    parent
+        .map_alloc(
+            test.addr(),
+            4096,
+            MappingFlags::PRESENT | MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER,
+            page_allocator,
+        )
+        .unwrap();
+    test.write(1).unwrap();
+
+    let child = parent.fork(page_allocator).unwrap();
+    crate::println!("Forked!");
+    test.write(2).unwrap();
+    child.activate();
+    crate::println!("Child sees {}, parent wrote {}", test.read().unwrap(), 2);
+    assert_eq!(test.read().unwrap(), 1);
+    test.write(3).unwrap();
+    parent.activate();
+    assert_eq!(test.read().unwrap(), 2);
+
+    child.unmap_free(test.addr(), 4096, page_allocator).unwrap();
+    parent
+        .unmap_free(test.addr(), 4096, page_allocator)
+        .unwrap();
+}
+
+fn test_user_access() {
+    use crate::memory::*;
+
+    // Kernel memory is not user memory
+    let kernel = UserPtr::<u32>::new(crate::arch::Memory::vmalloc_range().0);
+    assert!(matches!(
+        kernel.read(),
+        Err(UserAccessError::NotUserMemory(_, _))
+    ));
+
+    // Faults are turned into errors
+    let unmapped = UserSlice::new(crate::arch::Memory::user_range().0 + 0x60000000, 16);
+    assert_eq!(
+        unmapped.read_to_vec(),
+        Err(UserAccessError::Fault(unmapped.addr()))
+    );
+    assert_eq!(
+        unmapped.write(&[42; 16]),
+        Err(UserAccessError::Fault(unmapped.addr()))
+    );
+}
+
+fn test_demand_paging() {
+    use crate::memory::*;
+    let page_allocator = crate::arch::Memory::page_allocator();
+    let kernel_address_space = crate::arch::Memory::kernel_address_space();
+
+    let start = crate::arch::Memory::user_range().0 + 0x50000000;
+    let size = 0x1000000;
+    kernel_address_space
+        .reserve(Vma::new(
+            start,
+            size,
+            MappingFlags::READ | MappingFlags::WRITE,
+            VmaBacking::Anonymous,
+        ))
+        .unwrap();
+    let allocated = page_allocator.allocated_memory();
+
+    let test = (start + size / 2).as_mut_ptr_of::<u32>();
+    assert_eq!(unsafe { *test }, 0);
+    unsafe {
+        *test = 42;
+    }
+    assert_eq!(unsafe { *test }, 42);
+    crate::println!(
+        "Reserved {}, allocated {}",
+        FormatSize(size as _),
+        FormatSize((page_allocator.allocated_memory() - allocated) as _)
+    );
+
+    kernel_address_space.release(start, page_allocator).unwrap();
+}
+
+fn test_large_pages() {
+    use crate::memory::address_space::nested_page_table::{
+        NestedPageTable as _, NestedPageTableLevel as _,
+    };
+    use crate::memory::*;
+    type PageSize = <crate::arch::Memory as MemoryTrait>::PageSize;
+    let page_allocator = crate::arch::Memory::page_allocator();
+    let top_level = crate::arch::Memory::kernel_address_space().top_level();
+
+    let vaddr = crate::arch::Memory::user_range().0 + 0x70000000;
+    let size = usize::from(PageSize::LARGE);
+    let paddr = page_allocator.alloc(size).unwrap();
+    top_level
+        .map_page(
+            vaddr,
+            paddr,
+            PageSize::LARGE,
+            MappingFlags::PRESENT | MappingFlags::READ | MappingFlags::WRITE,
+            page_allocator,
+        )
+        .unwrap();
+    let test = (vaddr + size - 4).as_mut_ptr_of::<u32>();
+    unsafe {
+        *test = 42;
+        assert_eq!(*test, 42);
+    }
+    crate::println!("Mapped a {} page at {:#x}", FormatSize(size as _), vaddr);
+    top_level.unmap_free(vaddr, size, page_allocator).unwrap();
+}
+
+/// Ends with a page fault, so it has to be the last test
+pub fn test_paging() {
+    use crate::memory::*;
+    let page_allocator = crate::arch::Memory::page_allocator();
+
+    crate::println!("Total memory: {}", page_allocator.total_memory());
+
+    let test = vmalloc(
+        <crate::arch::Memory as MemoryTrait>::PageSize::MIN.into(),
+        MappingFlags::READ | MappingFlags::WRITE,
+    )
+    .unwrap()
+    .as_mut_ptr_of::<u32>();
+    let neighbour = vmalloc(4096, MappingFlags::READ | MappingFlags::WRITE).unwrap();
+    assert!(neighbour >= VirtAddr::from_mut_ptr_of(test) + 2 * 4096);
+    vfree(neighbour).unwrap();
+    // Running out of memory takes back everything, that was mapped. Kernel
+    // half of 32-bit architectures might have less virtual memory than there is free
+    let allocated = page_allocator.allocated_memory();
+    let free = page_allocator.total_memory() - allocated;
+    let (start, end) = crate::arch::Memory::vmalloc_range();
+    if free + 4096 < end - start {
+        assert!(matches!(
+            vmalloc(free + 4096, MappingFlags::READ | MappingFlags::WRITE),
+            Err(MappingError::PageAllocationFailed)
+        ));
+        assert_eq!(page_allocator.allocated_memory(), allocated);
+        let retry = vmalloc(4096, MappingFlags::READ | MappingFlags::WRITE).unwrap();
+        assert_eq!(retry, neighbour);
+        vfree(retry).unwrap();
+    }
+    crate::println!("Mapped at {:p}!", test);
+    crate::println!("Allocated memory: {}", page_allocator.allocated_memory());
+    unsafe {
+        *test = 42;
+    };
+    crate::println!("Wrote!");
+    crate::println!("Testing page mapping: {}", unsafe { *test });
+    vfree(VirtAddr::from_mut_ptr_of(test)).unwrap();
+    crate::println!(
+        "Allocated memory after freeing: {}",
+        page_allocator.allocated_memory()
+    );
+    crate::println!("Testing page unmapping (You should see a page fault):");
+    crate::println!("Huh? {}", unsafe { *test });
+}
@@ -0,0 +1,149 @@
+use crate::arch::traits::*;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+
+use super::VirtAddr;
+
+/// Errors accessing user memory. All of them mean a bad address (EFAULT)
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum UserAccessError {
+    /// Range doesn't lie in the user half of the address space
+    #[error("address range {0:#x}+{1:#x} is not in user memory")]
+    NotUserMemory(VirtAddr, usize),
+    /// Memory is not mapped or not accessible
+    #[error("fault accessing user memory at {0:#x}")]
+    Fault(VirtAddr),
+}
+
+/// Check that [addr; addr + len) lies in the user half of the address space
+fn check_range(addr: VirtAddr, len: usize) -> Result<(), UserAccessError> {
+    let (start, end) = crate::arch::Memory::user_range();
+    match addr.as_usize().checked_add(len) {
+        Some(range_end) if addr >= start && range_end <= end.as_usize() => Ok(()),
+        _ => Err(UserAccessError::NotUserMemory(addr, len)),
+    }
+}
+
+/// Copy bytes from user memory at src into dst
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserAccessError> {
+    check_range(src, dst.len())?;
+    let left = unsafe { crate::arch::Memory::copy_user(dst.as_mut_ptr(), src.as_ptr(), dst.len()) };
+    match left {
+        0 => Ok(()),
+        left => Err(UserAccessError::Fault(src + (dst.len() - left))),
+    }
+}
+
+/// Copy bytes from src into user memory at dst
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserAccessError> {
+    check_range(dst, src.len())?;
+    let left = unsafe { crate::arch::Memory::copy_user(dst.as_mut_ptr(), src.as_ptr(), src.len()) };
+    match left {
+        0 => Ok(()),
+        left => Err(UserAccessError::Fault(dst + (src.len() - left))),
+    }
+}
+
+/// Types that can be copied from and to user memory
+///
+/// # Safety
+/// Any bit pattern must be a valid value of the type
+pub unsafe trait UserData: Copy {}
+
+macro_rules! user_data {
+    ($($ty: ty),*) => {
+        $(unsafe impl UserData for $ty {})*
+    };
+}
+
+user_data!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+unsafe impl<T: UserData, const N: usize> UserData for [T; N] {}
+
+/// Pointer to a value in user memory. It's never dereferenced
+/// directly, values are copied with [`copy_from_user`] and [`copy_to_user`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserPtr<T: UserData> {
+    addr: VirtAddr,
+    _phantom: PhantomData<*mut T>,
+}
+
+impl<T: UserData> UserPtr<T> {
+    pub fn new(addr: VirtAddr) -> Self {
+        Self {
+            addr,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    /// Copy the value from user memory
+    pub fn read(&self) -> Result<T, UserAccessError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr().cast(), core::mem::size_of::<T>())
+        };
+        copy_from_user(bytes, self.addr)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Copy the value into user memory
+    pub fn write(&self, value: T) -> Result<(), UserAccessError> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts((&raw const value).cast::<u8>(), core::mem::size_of::<T>())
+        };
+        copy_to_user(self.addr, bytes)
+    }
+}
+
+/// Range of bytes in user memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserSlice {
+    addr: VirtAddr,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: VirtAddr, len: usize) -> Self {
+        Self { addr, len }
+    }
+
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy the start of the slice into buffer. Buffer must not be longer than the slice
+    pub fn read(&self, buffer: &mut [u8]) -> Result<(), UserAccessError> {
+        assert!(
+            buffer.len() <= self.len,
+            "Reading past the end of a user slice"
+        );
+        copy_from_user(buffer, self.addr)
+    }
+
+    /// Copy buffer into the start of the slice. Buffer must not be longer than the slice
+    pub fn write(&self, buffer: &[u8]) -> Result<(), UserAccessError> {
+        assert!(
+            buffer.len() <= self.len,
+            "Writing past the end of a user slice"
+        );
+        copy_to_user(self.addr, buffer)
+    }
+
+    /// Copy the whole slice into a vector
+    pub fn read_to_vec(&self) -> Result<alloc::vec::Vec<u8>, UserAccessError> {
+        let mut buffer = alloc::vec![0; self.len];
+        copy_from_user(&mut buffer, self.addr)?;
+        Ok(buffer)
+    }
+}
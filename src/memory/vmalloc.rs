@@ -0,0 +1,114 @@
+use crate::arch::traits::*;
+use alloc::collections::BTreeMap;
+
+use super::{AddressSpaceTrait, MappingError, MappingFlags, MappingResult, PageSizeTrait};
+use super::{MemoryAddr, VirtAddr};
+use crate::sync::{mutex, Mutex};
+
+/// Allocator of virtual address ranges. Every allocation is
+/// followed by an unmapped guard page to catch overflows
+struct VirtualRanges {
+    /// Free ranges, start address to size
+    free: BTreeMap<usize, usize>,
+    /// Allocated ranges, start address to size (without the guard page)
+    allocated: BTreeMap<usize, usize>,
+}
+
+impl VirtualRanges {
+    fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(start.as_usize(), end - start);
+        Self {
+            free,
+            allocated: BTreeMap::new(),
+        }
+    }
+
+    fn alloc(&mut self, size: usize, guard: usize) -> Option<usize> {
+        let (&start, &free_size) = self
+            .free
+            .iter()
+            .find(|(_, &free_size)| free_size >= size + guard)?;
+        self.free.remove(&start);
+        if free_size > size + guard {
+            self.free
+                .insert(start + size + guard, free_size - size - guard);
+        }
+        self.allocated.insert(start, size);
+        Some(start)
+    }
+
+    /// Free an allocated range
+    fn free(&mut self, start: usize, guard: usize) {
+        let Some(size) = self.allocated.remove(&start) else {
+            return;
+        };
+        let mut free_start = start;
+        let mut free_size = size + guard;
+
+        // Merge with neighbouring free ranges
+        if let Some((&prev, &prev_size)) = self.free.range(..start).next_back() {
+            if prev + prev_size == start {
+                self.free.remove(&prev);
+                free_start = prev;
+                free_size += prev_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&(start + size + guard)) {
+            free_size += next_size;
+        }
+        self.free.insert(free_start, free_size);
+    }
+}
+
+static RANGES: Mutex<Option<VirtualRanges>> = mutex(None);
+
+fn guard_size() -> usize {
+    <crate::arch::Memory as MemoryTrait>::PageSize::MIN.into()
+}
+
+/// Allocate a free range of kernel virtual memory and map newly allocated pages there
+pub fn vmalloc(size: usize, flags: MappingFlags) -> MappingResult<VirtAddr> {
+    let size = size.align_up(guard_size());
+    let start = RANGES
+        .lock()
+        .get_or_insert_with(|| {
+            let (start, end) = crate::arch::Memory::vmalloc_range();
+            VirtualRanges::new(start, end)
+        })
+        .alloc(size, guard_size())
+        .ok_or(MappingError::VirtualAllocationFailed)?;
+
+    crate::arch::Memory::kernel_address_space()
+        .map_alloc(
+            VirtAddr::from_usize(start),
+            size,
+            flags | MappingFlags::PRESENT,
+            crate::arch::Memory::page_allocator(),
+        )
+        .inspect_err(|_| {
+            // Pages, that were mapped before the failure, are already unmapped
+            if let Some(ranges) = RANGES.lock().as_mut() {
+                ranges.free(start, guard_size());
+            }
+        })
+}
+
+/// Unmap and free memory allocated with [`vmalloc`]
+pub fn vfree(vaddr: VirtAddr) -> MappingResult<()> {
+    let size = RANGES
+        .lock()
+        .as_ref()
+        .and_then(|ranges| ranges.allocated.get(&vaddr.as_usize()).copied())
+        .ok_or(MappingError::UnmappingNotMapped(vaddr))?;
+    crate::arch::Memory::kernel_address_space().unmap_free(
+        vaddr,
+        size,
+        crate::arch::Memory::page_allocator(),
+    )?;
+
+    if let Some(ranges) = RANGES.lock().as_mut() {
+        ranges.free(vaddr.as_usize(), guard_size());
+    }
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use super::WaitQueue;
+
+/// Condition variable for blocking mutexes, like [`super::SleepMutex`].
+/// Waiting can wake up spuriously, so the condition has to be checked
+/// in a loop, or with [`Condvar::wait_while`]
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock the mutex and block until notified, then lock it again.
+    /// Notifications, that come after the thread joins the queue and before
+    /// it blocks, aren't lost. Must not be used with a spinlock
+    pub fn wait<R: lock_api::RawMutex, T>(&self, guard: &mut lock_api::MutexGuard<'_, R, T>) {
+        let current = self.waiters.join();
+        lock_api::MutexGuard::unlocked(guard, crate::thread::park);
+        self.waiters.leave(&current);
+    }
+
+    /// Block until `condition` returns false for the protected data
+    pub fn wait_while<R: lock_api::RawMutex, T>(
+        &self,
+        guard: &mut lock_api::MutexGuard<'_, R, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) {
+        while condition(&mut **guard) {
+            self.wait(guard);
+        }
+    }
+
+    /// Wake up a waiting thread, returns false if there were none
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    /// Wake up every waiting thread, returns how many there were
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
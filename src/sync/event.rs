@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::WaitQueue;
+
+/// Flag, that threads can block on until it's set. It stays set, until it's reset
+pub struct Event {
+    set: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Set the event, waking up every waiting thread
+    pub fn set(&self) {
+        self.set.store(true, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    /// Clear the event, so that threads block on it again
+    pub fn reset(&self) {
+        self.set.store(false, Ordering::Release);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Block until the event is set
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.is_set());
+    }
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,202 @@
+use super::lockdep::{self, Class};
+use crate::arch::traits::*;
+use crate::thread::scheduler::{preempt_disable, preempt_enable};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Interrupt state of a CPU, while it holds interrupt-safe locks
+struct IrqState {
+    /// Number of interrupt-safe locks held
+    depth: AtomicUsize,
+    /// Interrupts were enabled, when the first lock was taken
+    enabled: AtomicBool,
+}
+
+impl IrqState {
+    const fn new() -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+            enabled: AtomicBool::new(false),
+        }
+    }
+}
+
+static CPUS: [IrqState; <crate::arch::Cpu as CpuTrait>::MAX_CPUS] =
+    [const { IrqState::new() }; <crate::arch::Cpu as CpuTrait>::MAX_CPUS];
+
+/// Disable interrupts, remembering if they were enabled before the
+/// outermost call. Locks can be released in any order, as only the last
+/// [`irq_restore`] brings the state back
+fn irq_save() {
+    let enabled = crate::arch::Cpu::disable_interrupts();
+    // Interrupts are disabled, so nothing else touches the state of this CPU
+    let cpu = &CPUS[crate::arch::Cpu::cpu_id()];
+    if cpu.depth.fetch_add(1, Ordering::Relaxed) == 0 {
+        cpu.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Undo [`irq_save`]
+fn irq_restore() {
+    let cpu = &CPUS[crate::arch::Cpu::cpu_id()];
+    if cpu.depth.fetch_sub(1, Ordering::Relaxed) == 1 && cpu.enabled.load(Ordering::Relaxed) {
+        crate::arch::Cpu::enable_interrupts();
+    }
+}
+
+/// Spinlock, that disables interrupts on this CPU while it's held, so
+/// it can be taken by interrupt handlers too. Preemption is disabled
+/// as well, and a pending switch happens once interrupts are back
+pub struct RawIrqSafeSpinlock {
+    lock: spin::Mutex<()>,
+    class: Class,
+}
+
+impl RawIrqSafeSpinlock {
+    /// Interrupt-safe spinlock of the class of locks made by the caller
+    #[allow(clippy::new_without_default)]
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            lock: spin::Mutex::new(()),
+            class: Class::here(),
+        }
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawIrqSafeSpinlock {
+    /// Every lock needs a class, so it's made with [`Self::new`] instead
+    const INIT: Self = panic!("Lock is made without a lock class");
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        preempt_disable();
+        irq_save();
+        lockdep::acquire(self.class, false);
+        lock_api::RawMutex::lock(&self.lock);
+    }
+
+    fn try_lock(&self) -> bool {
+        preempt_disable();
+        irq_save();
+        let locked = lock_api::RawMutex::try_lock(&self.lock);
+        if locked {
+            lockdep::try_acquired(self.class, false);
+        } else {
+            irq_restore();
+            preempt_enable();
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        lockdep::release(self.class);
+        unsafe { lock_api::RawMutex::unlock(&self.lock) };
+        irq_restore();
+        preempt_enable();
+    }
+
+    fn is_locked(&self) -> bool {
+        lock_api::RawMutex::is_locked(&self.lock)
+    }
+}
+
+/// Readers-writer spinlock, that disables interrupts on this CPU while it's held
+pub struct RawIrqSafeRwSpinlock {
+    lock: spin::RwLock<()>,
+    class: Class,
+}
+
+impl RawIrqSafeRwSpinlock {
+    /// Interrupt-safe readers-writer spinlock of the class of locks made by the caller
+    #[allow(clippy::new_without_default)]
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            lock: spin::RwLock::new(()),
+            class: Class::here(),
+        }
+    }
+}
+
+unsafe impl lock_api::RawRwLock for RawIrqSafeRwSpinlock {
+    /// Every lock needs a class, so it's made with [`Self::new`] instead
+    const INIT: Self = panic!("Lock is made without a lock class");
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock_shared(&self) {
+        preempt_disable();
+        irq_save();
+        lockdep::acquire(self.class, true);
+        lock_api::RawRwLock::lock_shared(&self.lock);
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        preempt_disable();
+        irq_save();
+        let locked = lock_api::RawRwLock::try_lock_shared(&self.lock);
+        if locked {
+            lockdep::try_acquired(self.class, true);
+        } else {
+            irq_restore();
+            preempt_enable();
+        }
+        locked
+    }
+
+    unsafe fn unlock_shared(&self) {
+        lockdep::release(self.class);
+        unsafe { lock_api::RawRwLock::unlock_shared(&self.lock) };
+        irq_restore();
+        preempt_enable();
+    }
+
+    fn lock_exclusive(&self) {
+        preempt_disable();
+        irq_save();
+        lockdep::acquire(self.class, false);
+        lock_api::RawRwLock::lock_exclusive(&self.lock);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        preempt_disable();
+        irq_save();
+        let locked = lock_api::RawRwLock::try_lock_exclusive(&self.lock);
+        if locked {
+            lockdep::try_acquired(self.class, false);
+        } else {
+            irq_restore();
+            preempt_enable();
+        }
+        locked
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        lockdep::release(self.class);
+        unsafe { lock_api::RawRwLock::unlock_exclusive(&self.lock) };
+        irq_restore();
+        preempt_enable();
+    }
+
+    fn is_locked(&self) -> bool {
+        lock_api::RawRwLock::is_locked(&self.lock)
+    }
+}
+
+pub type IrqSafeMutex<T> = lock_api::Mutex<RawIrqSafeSpinlock, T>;
+pub type IrqSafeMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawIrqSafeSpinlock, T>;
+
+pub type IrqSafeRwLock<T> = lock_api::RwLock<RawIrqSafeRwSpinlock, T>;
+pub type IrqSafeRwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawIrqSafeRwSpinlock, T>;
+pub type IrqSafeRwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawIrqSafeRwSpinlock, T>;
+
+/// Interrupt-safe mutex of the class of locks made by the caller
+#[track_caller]
+pub const fn irq_safe_mutex<T>(value: T) -> IrqSafeMutex<T> {
+    IrqSafeMutex::from_raw(RawIrqSafeSpinlock::new(), value)
+}
+
+/// Interrupt-safe readers-writer lock of the class of locks made by the caller
+#[track_caller]
+pub const fn irq_safe_rw_lock<T>(value: T) -> IrqSafeRwLock<T> {
+    IrqSafeRwLock::from_raw(RawIrqSafeRwSpinlock::new(), value)
+}
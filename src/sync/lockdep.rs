@@ -0,0 +1,429 @@
+use crate::arch::traits::*;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Number of lock classes, that can be tracked at once
+const MAX_CLASSES: usize = 256;
+
+/// Number of locks, that a CPU or a thread can hold at once
+const MAX_HELD: usize = 16;
+
+/// Longest lock chain, that is reported
+const MAX_CHAIN: usize = 8;
+
+const WORDS: usize = MAX_CLASSES / 64;
+
+/// Validator runs only in debug builds, until it gets turned off by a
+/// violation or by running out of space
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Violations fail the check with a panic, instead of only being reported
+static PANIC_ON_VIOLATION: AtomicBool = AtomicBool::new(true);
+
+static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug)]
+struct HeldLock {
+    class: u16,
+    /// Taken for reading, a reader lock can be taken recursively
+    shared: bool,
+}
+
+/// Locks, in the order they were taken
+#[derive(Clone, Copy)]
+struct Held {
+    locks: [HeldLock; MAX_HELD],
+    len: usize,
+}
+
+impl Held {
+    const fn new() -> Self {
+        Self {
+            locks: [HeldLock {
+                class: 0,
+                shared: false,
+            }; MAX_HELD],
+            len: 0,
+        }
+    }
+
+    fn iter(&self) -> core::slice::Iter<'_, HeldLock> {
+        self.locks[..self.len].iter()
+    }
+}
+
+/// Locks, that a thread holds while it's switched away, like blocking mutexes
+pub struct HeldLocks(spin::Mutex<Held>);
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self(spin::Mutex::new(Held::new()))
+    }
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Class of a lock, that it's validated as. Locks made at the same place
+/// in the code are used the same way, so they share a class, and
+/// the order is learned from all of them. Two locks of a class can't be
+/// held at once, like ones of different CPUs
+#[derive(Clone, Copy, Debug)]
+pub struct Class(&'static Location<'static>);
+
+impl Class {
+    /// Class of locks made by the caller
+    #[track_caller]
+    pub const fn here() -> Self {
+        Self(Location::caller())
+    }
+}
+
+/// Index of the edges, where a lock was taken after another was held,
+/// for every combination of readers and writers
+const fn mode(held_shared: bool, taken_shared: bool) -> usize {
+    (held_shared as usize) << 1 | taken_shared as usize
+}
+
+/// Bit `b` of `after[mode][a]` is set, if a lock of class `b`
+/// was taken while one of class `a` was held
+struct Graph {
+    /// Where the locks of every class are made, `None` if the class is free
+    locks: [Option<&'static Location<'static>>; MAX_CLASSES],
+    after: [[[u64; WORDS]; MAX_CLASSES]; 4],
+    /// Locks held by the thread running on each CPU
+    held: [Held; <crate::arch::Cpu as CpuTrait>::MAX_CPUS],
+}
+
+impl Graph {
+    const fn new() -> Self {
+        Self {
+            locks: [None; MAX_CLASSES],
+            after: [[[0; WORDS]; MAX_CLASSES]; 4],
+            held: [Held::new(); <crate::arch::Cpu as CpuTrait>::MAX_CPUS],
+        }
+    }
+
+    fn find(&self, lock: Option<&'static Location<'static>>) -> Option<u16> {
+        self.locks
+            .iter()
+            .position(|&location| location == lock)
+            .map(|class| class as u16)
+    }
+
+    /// Get the index of a class, registering it the first time it's seen
+    fn class(&mut self, lock: &'static Location<'static>) -> Option<u16> {
+        if let Some(class) = self.find(Some(lock)) {
+            return Some(class);
+        }
+        let class = self.find(None)?;
+        self.locks[class as usize] = Some(lock);
+        Some(class)
+    }
+
+    fn location(&self, class: u16) -> &'static Location<'static> {
+        self.locks[class as usize].expect("Held lock has a class")
+    }
+
+    fn has_edge(&self, from: HeldLock, to: HeldLock) -> bool {
+        let after = &self.after[mode(from.shared, to.shared)][from.class as usize];
+        after[to.class as usize / 64] & (1 << (to.class % 64)) != 0
+    }
+
+    fn add_edge(&mut self, from: HeldLock, to: HeldLock) {
+        let after = &mut self.after[mode(from.shared, to.shared)][from.class as usize];
+        after[to.class as usize / 64] |= 1 << (to.class % 64);
+    }
+
+    /// Find a chain of locks, that were taken one after another from `from` to
+    /// `to`, and can deadlock. Readers only wait for a writer, so a chain
+    /// can't go through a lock, that was both waited for and held by readers
+    fn path(&self, from: HeldLock, to: HeldLock) -> Option<Chain> {
+        // Every lock is visited twice, taken by a reader and by a writer
+        let state = |lock: HeldLock| lock.class * 2 + lock.shared as u16;
+        let mut parent = [u16::MAX; MAX_CLASSES * 2];
+        let mut queue = [0u16; MAX_CLASSES * 2];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = state(from);
+        parent[state(from) as usize] = state(from);
+        let mut found = None;
+        'search: while head < tail {
+            let current = queue[head];
+            head += 1;
+            let (class, taken_shared) = (current / 2, current % 2 == 1);
+            for held_shared in [false, true] {
+                if taken_shared && held_shared {
+                    continue;
+                }
+                for next_shared in [false, true] {
+                    let after = &self.after[mode(held_shared, next_shared)][class as usize];
+                    for next in 0..MAX_CLASSES as u16 {
+                        let next = HeldLock {
+                            class: next,
+                            shared: next_shared,
+                        };
+                        if after[next.class as usize / 64] & (1 << (next.class % 64)) == 0
+                            || parent[state(next) as usize] != u16::MAX
+                        {
+                            continue;
+                        }
+                        parent[state(next) as usize] = current;
+                        queue[tail] = state(next);
+                        tail += 1;
+                        if next.class == to.class && !(next.shared && to.shared) {
+                            found = Some(state(next));
+                            break 'search;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Walk back from `to`, keeping the last locks of a chain, that is too long
+        let mut reversed = Chain::new();
+        let mut current = found?;
+        loop {
+            reversed.push(self.location(current / 2));
+            if current == state(from) {
+                break;
+            }
+            current = parent[current as usize];
+        }
+        let mut chain = Chain::new();
+        for &lock in reversed.locks[..reversed.len].iter().rev().flatten() {
+            chain.push(lock);
+        }
+        chain.truncated = reversed.truncated;
+        Some(chain)
+    }
+}
+
+static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph::new());
+
+/// Lock the dependency graph. Interrupt handlers take locks
+/// too, so interrupts are disabled, while it's locked
+fn with_graph<R>(f: impl FnOnce(&mut Graph) -> R) -> R {
+    let enabled = crate::arch::Cpu::disable_interrupts();
+    let result = f(&mut GRAPH.lock());
+    if enabled {
+        crate::arch::Cpu::enable_interrupts();
+    }
+    result
+}
+
+/// Classes of locks, that were taken one after another
+#[derive(Debug)]
+struct Chain {
+    locks: [Option<&'static Location<'static>>; MAX_CHAIN],
+    len: usize,
+    /// Some locks at the start are missing
+    truncated: bool,
+}
+
+impl Chain {
+    const fn new() -> Self {
+        Self {
+            locks: [None; MAX_CHAIN],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    fn push(&mut self, lock: &'static Location<'static>) {
+        if self.len == MAX_CHAIN {
+            self.truncated = true;
+        } else {
+            self.locks[self.len] = Some(lock);
+            self.len += 1;
+        }
+    }
+}
+
+impl core::fmt::Display for Chain {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.truncated {
+            write!(f, "... -> ")?;
+        }
+        for (index, lock) in self.locks[..self.len].iter().flatten().enumerate() {
+            if index > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", lock)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Violation {
+    /// Lock is taken, while the CPU already holds one of the same class
+    Recursive {
+        lock: &'static Location<'static>,
+        held: Chain,
+    },
+    /// Lock is taken while holding `held`, but it was held itself,
+    /// when `held` was taken before. Two CPUs doing both can deadlock
+    Inversion {
+        lock: &'static Location<'static>,
+        held: &'static Location<'static>,
+        before: Chain,
+    },
+    /// Validator ran out of space, and turns itself off
+    Full(&'static str),
+}
+
+impl core::fmt::Display for Violation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Recursive { lock, held } => write!(
+                f,
+                "Recursive locking of {}, held locks are {}",
+                lock, held
+            ),
+            Self::Inversion { lock, held, before } => write!(
+                f,
+                "Lock order inversion, taking {} while holding {}, but they were taken in order {} before",
+                lock, held, before
+            ),
+            Self::Full(what) => write!(f, "Lock validator ran out of {}, turning it off", what),
+        }
+    }
+}
+
+fn report(violation: Violation) {
+    if let Violation::Full(_) = violation {
+        ENABLED.store(false, Ordering::SeqCst);
+        crate::println!("{}", violation);
+        return;
+    }
+    VIOLATIONS.fetch_add(1, Ordering::SeqCst);
+    if PANIC_ON_VIOLATION.load(Ordering::SeqCst) {
+        // Panicking takes the logger lock, it must not be checked
+        ENABLED.store(false, Ordering::SeqCst);
+        panic!("{}", violation);
+    }
+    crate::println!("{}", violation);
+}
+
+/// Validator is enabled, it's always off in release builds
+pub fn is_enabled() -> bool {
+    cfg!(debug_assertions) && ENABLED.load(Ordering::Relaxed)
+}
+
+/// Only report violations through the logger, instead of panicking. Locks
+/// are still taken after a violation, so a real deadlock will hang
+pub fn set_panic_on_violation(panic: bool) {
+    PANIC_ON_VIOLATION.store(panic, Ordering::SeqCst);
+}
+
+/// Number of violations found so far
+pub fn violations() -> usize {
+    VIOLATIONS.load(Ordering::SeqCst)
+}
+
+/// Check, that a lock can be taken by this CPU, and record the order
+/// it's taken in after every held lock. Called before spinning or
+/// blocking on a lock, so a deadlock is reported instead of hanging
+pub(super) fn acquire(class: Class, shared: bool) {
+    if !is_enabled() {
+        return;
+    }
+    let Class(lock) = class;
+    let violation = with_graph(|graph| {
+        let Some(class) = graph.class(lock) else {
+            return Some(Violation::Full("lock classes"));
+        };
+        let cpu = crate::arch::Cpu::cpu_id();
+        let held = graph.held[cpu];
+        let mut violation = None;
+        for held_lock in held.iter() {
+            if held_lock.class == class {
+                if !(shared && held_lock.shared) && violation.is_none() {
+                    let mut locks = Chain::new();
+                    held.iter()
+                        .for_each(|held| locks.push(graph.location(held.class)));
+                    violation = Some(Violation::Recursive { lock, held: locks });
+                }
+                continue;
+            }
+            let taken = HeldLock { class, shared };
+            if graph.has_edge(*held_lock, taken) {
+                continue;
+            }
+            if let Some(before) = graph.path(taken, *held_lock) {
+                violation.get_or_insert(Violation::Inversion {
+                    lock,
+                    held: graph.location(held_lock.class),
+                    before,
+                });
+                continue;
+            }
+            graph.add_edge(*held_lock, taken);
+        }
+        push(&mut graph.held[cpu], class, shared).or(violation)
+    });
+    if let Some(violation) = violation {
+        report(violation);
+    }
+}
+
+/// Record a lock, that was taken with a try lock. It can't deadlock,
+/// so only locks taken after it are checked
+pub(super) fn try_acquired(class: Class, shared: bool) {
+    if !is_enabled() {
+        return;
+    }
+    let Class(lock) = class;
+    let violation = with_graph(|graph| {
+        let Some(class) = graph.class(lock) else {
+            return Some(Violation::Full("lock classes"));
+        };
+        push(&mut graph.held[crate::arch::Cpu::cpu_id()], class, shared)
+    });
+    if let Some(violation) = violation {
+        report(violation);
+    }
+}
+
+fn push(held: &mut Held, class: u16, shared: bool) -> Option<Violation> {
+    if held.len == MAX_HELD {
+        return Some(Violation::Full("held lock slots"));
+    }
+    held.locks[held.len] = HeldLock { class, shared };
+    held.len += 1;
+    None
+}
+
+/// Record, that this CPU released a lock. Locks can be released in any order
+pub(super) fn release(class: Class) {
+    if !is_enabled() {
+        return;
+    }
+    let Class(lock) = class;
+    with_graph(|graph| {
+        let Some(class) = graph.find(Some(lock)) else {
+            return;
+        };
+        let held = &mut graph.held[crate::arch::Cpu::cpu_id()];
+        if let Some(index) = held.iter().rposition(|held| held.class == class) {
+            held.locks.copy_within(index + 1..held.len, index);
+            held.len -= 1;
+        }
+    });
+}
+
+/// Save locks held by the thread, that is switched away from, and
+/// restore ones of the thread, that is switched to. Interrupts are disabled
+pub fn switch(from: &HeldLocks, to: &HeldLocks) {
+    if !is_enabled() {
+        return;
+    }
+    with_graph(|graph| {
+        let held = &mut graph.held[crate::arch::Cpu::cpu_id()];
+        *from.0.lock() = *held;
+        *held = *to.0.lock();
+    });
+}
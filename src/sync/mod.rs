@@ -0,0 +1,41 @@
+/// Lock dependency validator for debug builds
+pub mod lockdep;
+
+/// Spinlocks, that disable preemption while they are held
+pub mod spinlock;
+pub use spinlock::{lock_nb, mutex, rw_lock, Lock, LockGuard, MappedLockGuard};
+pub use spinlock::{MappedMutexGuard, MappedRwLockReadGuard, MappedRwLockWriteGuard};
+pub use spinlock::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use spinlock::{RawRwSpinlock, RawSpinlock};
+
+/// Spinlocks, that also disable interrupts, for data used by interrupt handlers
+pub mod irq_safe;
+pub use irq_safe::{irq_safe_mutex, irq_safe_rw_lock, IrqSafeMutex, IrqSafeMutexGuard};
+pub use irq_safe::{IrqSafeRwLock, IrqSafeRwLockReadGuard, IrqSafeRwLockWriteGuard};
+pub use irq_safe::{RawIrqSafeRwSpinlock, RawIrqSafeSpinlock};
+
+/// Queues of threads, that wait for something
+pub mod wait_queue;
+pub use wait_queue::WaitQueue;
+
+/// Locks, that block the thread instead of spinning
+pub mod sleep;
+pub use sleep::{sleep_mutex, sleep_rw_lock, RawSleepMutex, RawSleepRwLock};
+pub use sleep::{SleepMutex, SleepMutexGuard};
+pub use sleep::{SleepRwLock, SleepRwLockReadGuard, SleepRwLockWriteGuard};
+
+/// Counting semaphore
+pub mod semaphore;
+pub use semaphore::Semaphore;
+
+/// Condition variable
+pub mod condvar;
+pub use condvar::Condvar;
+
+/// Event, that threads wait to be set
+pub mod event;
+pub use event::Event;
+
+/// Tests of the blocking primitives and the lock validator, that every architecture runs
+#[cfg(feature = "kernel-tests")]
+pub mod tests;
@@ -0,0 +1,176 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::lockdep::{self, Class};
+use super::WaitQueue;
+
+/// Mutex, that blocks the thread while it's locked by another one. Waiters
+/// are woken up one at a time, but a thread, that comes meanwhile, can take
+/// the lock first. Must not be locked while holding a spinlock
+pub struct RawSleepMutex {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    class: Class,
+}
+
+impl RawSleepMutex {
+    /// Sleeping mutex of the class of locks made by the caller
+    #[allow(clippy::new_without_default)]
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            class: Class::here(),
+        }
+    }
+
+    fn take(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawSleepMutex {
+    /// Every lock needs a class, so it's made with [`Self::new`] instead
+    const INIT: Self = panic!("Lock is made without a lock class");
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        lockdep::acquire(self.class, false);
+        self.waiters.wait_until(|| self.take());
+    }
+
+    fn try_lock(&self) -> bool {
+        let locked = self.take();
+        if locked {
+            lockdep::try_acquired(self.class, false);
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        lockdep::release(self.class);
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+/// Readers-writer lock, that blocks the thread while it can't be taken.
+/// Readers, that keep coming, can starve a writer
+pub struct RawSleepRwLock {
+    /// Number of readers, or [`Self::WRITER`] if it's locked for writing
+    state: AtomicUsize,
+    waiters: WaitQueue,
+    class: Class,
+}
+
+impl RawSleepRwLock {
+    const WRITER: usize = usize::MAX;
+
+    /// Sleeping readers-writer lock of the class of locks made by the caller
+    #[allow(clippy::new_without_default)]
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            class: Class::here(),
+        }
+    }
+
+    fn take_shared(&self) -> bool {
+        let mut readers = self.state.load(Ordering::Relaxed);
+        while readers < Self::WRITER - 1 {
+            match self.state.compare_exchange_weak(
+                readers,
+                readers + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => readers = current,
+            }
+        }
+        false
+    }
+
+    fn take_exclusive(&self) -> bool {
+        self.state
+            .compare_exchange(0, Self::WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+unsafe impl lock_api::RawRwLock for RawSleepRwLock {
+    /// Every lock needs a class, so it's made with [`Self::new`] instead
+    const INIT: Self = panic!("Lock is made without a lock class");
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock_shared(&self) {
+        lockdep::acquire(self.class, true);
+        self.waiters.wait_until(|| self.take_shared());
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        let locked = self.take_shared();
+        if locked {
+            lockdep::try_acquired(self.class, true);
+        }
+        locked
+    }
+
+    unsafe fn unlock_shared(&self) {
+        lockdep::release(self.class);
+        if self.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.waiters.wake_one();
+        }
+    }
+
+    fn lock_exclusive(&self) {
+        lockdep::acquire(self.class, false);
+        self.waiters.wait_until(|| self.take_exclusive());
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        let locked = self.take_exclusive();
+        if locked {
+            lockdep::try_acquired(self.class, false);
+        }
+        locked
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        lockdep::release(self.class);
+        self.state.store(0, Ordering::Release);
+        // Every reader can take the lock now
+        self.waiters.wake_all();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != 0
+    }
+}
+
+pub type SleepMutex<T> = lock_api::Mutex<RawSleepMutex, T>;
+pub type SleepMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawSleepMutex, T>;
+
+pub type SleepRwLock<T> = lock_api::RwLock<RawSleepRwLock, T>;
+pub type SleepRwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawSleepRwLock, T>;
+pub type SleepRwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawSleepRwLock, T>;
+
+/// Sleeping mutex of the class of locks made by the caller
+#[track_caller]
+pub const fn sleep_mutex<T>(value: T) -> SleepMutex<T> {
+    SleepMutex::from_raw(RawSleepMutex::new(), value)
+}
+
+/// Sleeping readers-writer lock of the class of locks made by the caller
+#[track_caller]
+pub const fn sleep_rw_lock<T>(value: T) -> SleepRwLock<T> {
+    SleepRwLock::from_raw(RawSleepRwLock::new(), value)
+}
@@ -0,0 +1,162 @@
+use super::lockdep::{self, Class};
+use crate::thread::scheduler::{preempt_disable, preempt_enable};
+
+/// Spinlock, that disables preemption while it's held. A thread,
+/// that was preempted holding a spinlock, would make every
+/// other thread on it's CPU spin until the end of the time slice
+pub struct RawSpinlock {
+    lock: spin::Mutex<()>,
+    class: Class,
+}
+
+impl RawSpinlock {
+    /// Spinlock of the class of locks made by the caller
+    #[allow(clippy::new_without_default)]
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            lock: spin::Mutex::new(()),
+            class: Class::here(),
+        }
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawSpinlock {
+    /// Every lock needs a class, so it's made with [`Self::new`] instead
+    const INIT: Self = panic!("Lock is made without a lock class");
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        preempt_disable();
+        lockdep::acquire(self.class, false);
+        lock_api::RawMutex::lock(&self.lock);
+    }
+
+    fn try_lock(&self) -> bool {
+        preempt_disable();
+        let locked = lock_api::RawMutex::try_lock(&self.lock);
+        if locked {
+            lockdep::try_acquired(self.class, false);
+        } else {
+            preempt_enable();
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        lockdep::release(self.class);
+        unsafe { lock_api::RawMutex::unlock(&self.lock) };
+        preempt_enable();
+    }
+
+    fn is_locked(&self) -> bool {
+        lock_api::RawMutex::is_locked(&self.lock)
+    }
+}
+
+/// Readers-writer spinlock, that disables preemption while it's held
+pub struct RawRwSpinlock {
+    lock: spin::RwLock<()>,
+    class: Class,
+}
+
+impl RawRwSpinlock {
+    /// Readers-writer spinlock of the class of locks made by the caller
+    #[allow(clippy::new_without_default)]
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            lock: spin::RwLock::new(()),
+            class: Class::here(),
+        }
+    }
+}
+
+unsafe impl lock_api::RawRwLock for RawRwSpinlock {
+    /// Every lock needs a class, so it's made with [`Self::new`] instead
+    const INIT: Self = panic!("Lock is made without a lock class");
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock_shared(&self) {
+        preempt_disable();
+        lockdep::acquire(self.class, true);
+        lock_api::RawRwLock::lock_shared(&self.lock);
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        preempt_disable();
+        let locked = lock_api::RawRwLock::try_lock_shared(&self.lock);
+        if locked {
+            lockdep::try_acquired(self.class, true);
+        } else {
+            preempt_enable();
+        }
+        locked
+    }
+
+    unsafe fn unlock_shared(&self) {
+        lockdep::release(self.class);
+        unsafe { lock_api::RawRwLock::unlock_shared(&self.lock) };
+        preempt_enable();
+    }
+
+    fn lock_exclusive(&self) {
+        preempt_disable();
+        lockdep::acquire(self.class, false);
+        lock_api::RawRwLock::lock_exclusive(&self.lock);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        preempt_disable();
+        let locked = lock_api::RawRwLock::try_lock_exclusive(&self.lock);
+        if locked {
+            lockdep::try_acquired(self.class, false);
+        } else {
+            preempt_enable();
+        }
+        locked
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        lockdep::release(self.class);
+        unsafe { lock_api::RawRwLock::unlock_exclusive(&self.lock) };
+        preempt_enable();
+    }
+
+    fn is_locked(&self) -> bool {
+        lock_api::RawRwLock::is_locked(&self.lock)
+    }
+}
+
+pub type Mutex<T> = lock_api::Mutex<RawSpinlock, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinlock, T>;
+pub type MappedMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawSpinlock, T>;
+
+pub type RwLock<T> = lock_api::RwLock<RawRwSpinlock, T>;
+pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawRwSpinlock, T>;
+pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawRwSpinlock, T>;
+pub type MappedRwLockReadGuard<'a, T> = lock_api::MappedRwLockReadGuard<'a, RawRwSpinlock, T>;
+pub type MappedRwLockWriteGuard<'a, T> = lock_api::MappedRwLockWriteGuard<'a, RawRwSpinlock, T>;
+
+/// Mutex of the class of locks made by the caller
+#[track_caller]
+pub const fn mutex<T>(value: T) -> Mutex<T> {
+    Mutex::from_raw(RawSpinlock::new(), value)
+}
+
+/// Readers-writer lock of the class of locks made by the caller
+#[track_caller]
+pub const fn rw_lock<T>(value: T) -> RwLock<T> {
+    RwLock::from_raw(RawRwSpinlock::new(), value)
+}
+
+pub type Lock = Mutex<()>;
+pub type LockGuard = MutexGuard<'static, ()>;
+pub type MappedLockGuard<T> = MappedMutexGuard<'static, T>;
+
+pub fn lock_nb<T>(mutex: &spin::Mutex<T>) -> spin::MutexGuard<T> {
+    match mutex.try_lock() {
+        Some(guard) => guard,
+        None => panic!("Tried to lock a locked mutex!"),
+    }
+}
@@ -0,0 +1,120 @@
+use crate::arch::traits::*;
+
+pub fn run() {
+    test_blocking();
+    test_irq_safe();
+    test_lockdep();
+}
+
+fn test_blocking() {
+    use crate::sync::{sleep_mutex, Condvar, Event, Semaphore, SleepMutex};
+    use crate::thread::*;
+    use alloc::vec::Vec;
+
+    static COUNTER: SleepMutex<usize> = sleep_mutex(0);
+    static DONE: Semaphore = Semaphore::new(0);
+    // Yielding in the critical section makes the other worker block on the mutex
+    fn worker(_: usize) {
+        for _ in 0..5 {
+            let mut counter = COUNTER.lock();
+            let value = *counter;
+            yield_now();
+            *counter = value + 1;
+        }
+        DONE.release();
+    }
+
+    static QUEUE: SleepMutex<Vec<usize>> = sleep_mutex(Vec::new());
+    static NOT_EMPTY: Condvar = Condvar::new();
+    static CONSUMED: Event = Event::new();
+    fn consumer(_: usize) {
+        let mut sum = 0;
+        for _ in 0..3 {
+            let mut queue = QUEUE.lock();
+            NOT_EMPTY.wait_while(&mut queue, |queue| queue.is_empty());
+            sum += queue.remove(0);
+        }
+        assert_eq!(sum, 6);
+        CONSUMED.set();
+    }
+
+    for _ in 0..2 {
+        spawn(worker, 0).unwrap();
+    }
+    DONE.acquire();
+    DONE.acquire();
+    assert_eq!(*COUNTER.lock(), 10);
+
+    spawn(consumer, 0).unwrap();
+    for item in 1..=3 {
+        QUEUE.lock().push(item);
+        NOT_EMPTY.notify_one();
+        yield_now();
+    }
+    CONSUMED.wait();
+    assert!(QUEUE.lock().is_empty());
+    crate::println!("Blocking primitives work, counter is {}", *COUNTER.lock());
+}
+
+fn test_irq_safe() {
+    use crate::sync::{irq_safe_mutex, irq_safe_rw_lock, IrqSafeMutex, IrqSafeRwLock};
+
+    static COUNTER: IrqSafeMutex<usize> = irq_safe_mutex(0);
+    static TABLE: IrqSafeRwLock<usize> = irq_safe_rw_lock(0);
+    fn interrupts_enabled() -> bool {
+        let enabled = crate::arch::Cpu::disable_interrupts();
+        if enabled {
+            crate::arch::Cpu::enable_interrupts();
+        }
+        enabled
+    }
+
+    assert!(interrupts_enabled());
+    let counter = COUNTER.lock();
+    assert!(!interrupts_enabled());
+    let readers = (TABLE.read(), TABLE.read());
+    // Locks are released out of order, interrupts stay disabled until the last one
+    drop(counter);
+    assert!(!interrupts_enabled());
+    drop(readers);
+    assert!(interrupts_enabled());
+
+    // Interrupts, that were disabled before locking, are not enabled by unlocking
+    crate::arch::Cpu::disable_interrupts();
+    *COUNTER.lock() += 1;
+    *TABLE.write() += 1;
+    assert!(!interrupts_enabled());
+    crate::arch::Cpu::enable_interrupts();
+    crate::println!("Interrupt-safe locks restore interrupt state");
+}
+
+fn test_lockdep() {
+    use crate::sync::{lockdep, mutex, rw_lock, Mutex, RwLock};
+
+    if !lockdep::is_enabled() {
+        return;
+    }
+    static FIRST: Mutex<()> = mutex(());
+    static SECOND: Mutex<()> = mutex(());
+    static TABLE: RwLock<()> = rw_lock(());
+    let violations = lockdep::violations();
+    lockdep::set_panic_on_violation(false);
+    {
+        let _first = FIRST.lock();
+        let _second = SECOND.lock();
+    }
+    // Readers can take a lock, that is already read, and don't wait for each other
+    {
+        let _table = TABLE.read();
+        let _first = FIRST.lock();
+        let _again = TABLE.read();
+    }
+    assert_eq!(lockdep::violations(), violations);
+    {
+        let _second = SECOND.lock();
+        let _first = FIRST.lock();
+    }
+    lockdep::set_panic_on_violation(true);
+    assert_eq!(lockdep::violations(), violations + 1);
+    crate::println!("Lock validator caught the inversion");
+}
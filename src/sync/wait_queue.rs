@@ -0,0 +1,83 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use super::{irq_safe_mutex, IrqSafeMutex};
+use crate::thread::Thread;
+
+/// Threads, that are blocked until something happens. Waiters check a
+/// condition after joining the queue, so a wakeup can't be lost between
+/// the check and blocking. Must not be waited on while holding a spinlock,
+/// but interrupt handlers can wake waiters up, as waking never allocates
+pub struct WaitQueue {
+    waiters: IrqSafeMutex<VecDeque<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: irq_safe_mutex(VecDeque::new()),
+        }
+    }
+
+    /// Add the running thread to the queue. It has to [`crate::thread::park`]
+    /// to block and [`Self::leave`] after it's woken up
+    pub(super) fn join(&self) -> Arc<Thread> {
+        let current = crate::thread::current();
+        self.waiters.lock().push_back(current.clone());
+        current
+    }
+
+    /// Remove a thread from the queue, if it's still there after a spurious
+    /// wakeup. Returns false, if it was already woken up and removed
+    pub(super) fn leave(&self, thread: &Arc<Thread>) -> bool {
+        let mut waiters = self.waiters.lock();
+        let len = waiters.len();
+        waiters.retain(|waiter| !Arc::ptr_eq(waiter, thread));
+        waiters.len() != len
+    }
+
+    /// Block until `condition` returns true. It's checked every time
+    /// the thread is woken up, and might have side effects, like taking a lock
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        while !condition() {
+            let current = self.join();
+            if condition() {
+                // Wakeup, that came meanwhile, was meant for someone still waiting
+                if !self.leave(&current) {
+                    self.wake_one();
+                }
+                return;
+            }
+            crate::thread::park();
+            self.leave(&current);
+        }
+    }
+
+    /// Wake up the thread, that waits for the longest time.
+    /// Returns false, if there were no waiters
+    pub fn wake_one(&self) -> bool {
+        let Some(waiter) = self.waiters.lock().pop_front() else {
+            return false;
+        };
+        waiter.unpark();
+        true
+    }
+
+    /// Wake up every waiting thread, returns how many there were.
+    /// Waiters are taken one by one, as freeing the queue would need the heap
+    pub fn wake_all(&self) -> usize {
+        let waiting = self.waiters.lock().len();
+        (0..waiting).take_while(|_| self.wake_one()).count()
+    }
+
+    /// Returns true, if no thread is waiting
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
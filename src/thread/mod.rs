@@ -0,0 +1,308 @@
+use crate::arch::traits::*;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::memory::{AddressSpaceTrait, MappingFlags, MappingResult, PageSizeTrait, VirtAddr};
+use crate::sync::{irq_safe_mutex, mutex, Event, IrqSafeMutex, Mutex};
+
+/// Preemptive scheduler with per-CPU run queues
+pub mod scheduler;
+pub use scheduler::{Policy, Priority};
+
+/// Tests of threads and the scheduler, that every architecture runs
+#[cfg(feature = "kernel-tests")]
+pub mod tests;
+
+/// Size of a kernel stack, without the guard page
+pub const STACK_SIZE: usize = 0x4000;
+
+/// Unique identifier of a thread
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(usize);
+
+impl core::fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What a thread is doing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Running on a CPU
+    Running,
+    /// Waiting for a CPU in the run queue
+    Ready,
+    /// Parked, waiting to be unparked
+    Blocked,
+    /// Exited, waiting to be freed
+    Dead,
+}
+
+/// Kernel stack of a thread. The lowest page is left unmapped, so that
+/// an overflow faults instead of corrupting memory below the stack
+struct Stack {
+    start: VirtAddr,
+}
+
+impl Stack {
+    fn guard_size() -> usize {
+        <crate::arch::Memory as MemoryTrait>::PageSize::MIN.into()
+    }
+
+    fn new() -> MappingResult<Self> {
+        let start = crate::memory::vmalloc(
+            Self::guard_size() + STACK_SIZE,
+            MappingFlags::READ | MappingFlags::WRITE,
+        )?;
+        // vmalloc leaves a guard page above the allocation, unmap one below too
+        let stack = Self { start };
+        crate::arch::Memory::kernel_address_space().unmap_free(
+            start,
+            Self::guard_size(),
+            crate::arch::Memory::page_allocator(),
+        )?;
+        Ok(stack)
+    }
+
+    fn top(&self) -> VirtAddr {
+        self.start + Self::guard_size() + STACK_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        crate::memory::vfree(self.start).expect("failed to free a kernel stack");
+    }
+}
+
+/// Kernel thread
+pub struct Thread {
+    id: ThreadId,
+    state: IrqSafeMutex<ThreadState>,
+    /// Level of the [`Priority`]
+    priority: AtomicU8,
+    /// Time the thread ran, weighted by it's priority. See [`Policy::FairShare`]
+    vruntime: Mutex<u64>,
+    /// CPU, which run queue the thread belongs to
+    cpu: usize,
+    /// Set by [`Thread::unpark`], if the thread was not parked
+    unparked: AtomicBool,
+    /// Saved registers, only valid while the thread is not running
+    context: UnsafeCell<crate::arch::Context>,
+    /// Blocking locks, that the thread holds while it's not running
+    held_locks: crate::sync::lockdep::HeldLocks,
+    /// Set by [`exit`], see [`Thread::join`]
+    exited: Event,
+    /// Thread, that booted the kernel, runs on the boot stack
+    stack: Option<Stack>,
+}
+
+// Context is only accessed by context switches, while the thread is not running
+unsafe impl Sync for Thread {}
+unsafe impl Send for Thread {}
+
+impl Thread {
+    /// Make a thread, that calls `entry(arg)` once it's switched to
+    fn new(entry: fn(usize), arg: usize, priority: Priority) -> MappingResult<Arc<Self>> {
+        let stack = Stack::new()?;
+        let start = Box::into_raw(Box::new((entry, arg)));
+        let context = crate::arch::Context::new(stack.top(), thread_start, start as usize);
+        Ok(Arc::new(Self {
+            id: ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            state: irq_safe_mutex(ThreadState::Ready),
+            priority: AtomicU8::new(priority.level()),
+            vruntime: mutex(0),
+            unparked: AtomicBool::new(false),
+            cpu: crate::arch::Cpu::cpu_id(),
+            context: UnsafeCell::new(context),
+            held_locks: Default::default(),
+            exited: Event::new(),
+            stack: Some(stack),
+        }))
+    }
+
+    /// Adopt the flow of execution, that booted the kernel, as a thread
+    fn boot() -> Arc<Self> {
+        Arc::new(Self {
+            id: ThreadId(0),
+            state: irq_safe_mutex(ThreadState::Running),
+            priority: AtomicU8::new(Priority::NORMAL.level()),
+            vruntime: mutex(0),
+            unparked: AtomicBool::new(false),
+            cpu: crate::arch::Cpu::cpu_id(),
+            context: UnsafeCell::new(crate::arch::Context::default()),
+            held_locks: Default::default(),
+            exited: Event::new(),
+            stack: None,
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn state(&self) -> ThreadState {
+        *self.state.lock()
+    }
+
+    fn set_state(&self, state: ThreadState) {
+        *self.state.lock() = state;
+    }
+
+    /// Block until the thread exits. It might not have switched away yet,
+    /// but it doesn't run anything but the scheduler anymore
+    pub fn join(&self) {
+        self.exited.wait();
+    }
+
+    /// Wake the thread up, if it's parked. Otherwise, it's next [`park`] returns at once
+    pub fn unpark(self: &Arc<Self>) {
+        let blocked = {
+            let mut state = self.state.lock();
+            if *state == ThreadState::Blocked {
+                *state = ThreadState::Ready;
+                true
+            } else {
+                self.unparked.store(true, Ordering::SeqCst);
+                false
+            }
+        };
+        if blocked {
+            scheduler::enqueue(self.clone());
+        }
+    }
+}
+
+impl core::fmt::Debug for Thread {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Thread")
+            .field("id", &self.id)
+            .field("state", &self.state())
+            .field("priority", &self.priority())
+            .field("cpu", &self.cpu)
+            .field("stack", &self.stack.as_ref().map(Stack::top))
+            .finish()
+    }
+}
+
+/// Thread ids, 0 is the boot thread
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Thread running on each CPU
+static CURRENT: [IrqSafeMutex<Option<Arc<Thread>>>; <crate::arch::Cpu as CpuTrait>::MAX_CPUS] =
+    [const { irq_safe_mutex(None) }; <crate::arch::Cpu as CpuTrait>::MAX_CPUS];
+
+/// Threads, that exited on each CPU. A thread can't free the stack
+/// it runs on, so they are freed by the next thread after the switch
+static DEAD: [Mutex<Vec<Arc<Thread>>>; <crate::arch::Cpu as CpuTrait>::MAX_CPUS] =
+    [const { mutex(Vec::new()) }; <crate::arch::Cpu as CpuTrait>::MAX_CPUS];
+
+/// Get the thread running on this CPU
+pub fn current() -> Arc<Thread> {
+    CURRENT[crate::arch::Cpu::cpu_id()]
+        .lock()
+        .get_or_insert_with(Thread::boot)
+        .clone()
+}
+
+/// Start a new kernel thread with [`Priority::NORMAL`], see [`spawn_with_priority`]
+pub fn spawn(entry: fn(usize), arg: usize) -> MappingResult<Arc<Thread>> {
+    spawn_with_priority(entry, arg, Priority::NORMAL)
+}
+
+/// Start a new kernel thread on this CPU, that calls `entry(arg)`.
+/// Returning from `entry` exits the thread. The first thread,
+/// that is spawned, starts preemption
+pub fn spawn_with_priority(
+    entry: fn(usize),
+    arg: usize,
+    priority: Priority,
+) -> MappingResult<Arc<Thread>> {
+    scheduler::start()?;
+    let thread = Thread::new(entry, arg, priority)?;
+    scheduler::reserve(thread.cpu);
+    scheduler::enqueue(thread.clone());
+    Ok(thread)
+}
+
+/// First thing a new thread runs, `start` is the boxed entry point and argument
+extern "C" fn thread_start(start: usize) -> ! {
+    free_dead();
+    // Threads are switched with interrupts disabled
+    crate::arch::Cpu::enable_interrupts();
+    let (entry, arg) = *unsafe { Box::from_raw(start as *mut (fn(usize), usize)) };
+    entry(arg);
+    exit()
+}
+
+/// Let other threads, that are ready, run
+pub fn yield_now() {
+    debug_assert_eq!(
+        scheduler::preempt_count(),
+        0,
+        "Yielding while holding a spinlock"
+    );
+    scheduler::without_interrupts(|| scheduler::reschedule(true));
+}
+
+/// Block the running thread until it's unparked. Returns at once, if
+/// [`Thread::unpark`] was called since the last park. Callers should
+/// check what they wait for in a loop, as park might return spuriously
+pub fn park() {
+    debug_assert_eq!(
+        scheduler::preempt_count(),
+        0,
+        "Parking while holding a spinlock"
+    );
+    // Keeps the thread alive while it's not in any run queue
+    let current = current();
+    scheduler::without_interrupts(|| {
+        {
+            let mut state = current.state.lock();
+            if current.unparked.swap(false, Ordering::SeqCst) {
+                return;
+            }
+            *state = ThreadState::Blocked;
+        }
+        // If it's unparked meanwhile, it's in the run queue, and might be picked again
+        scheduler::reschedule(false);
+    });
+}
+
+/// Exit the running thread
+pub fn exit() -> ! {
+    crate::arch::Cpu::disable_interrupts();
+    let current = current();
+    current.set_state(ThreadState::Dead);
+    current.exited.set();
+    scheduler::unreserve(current.cpu);
+    DEAD[crate::arch::Cpu::cpu_id()].lock().push(current);
+    scheduler::reschedule(false);
+    unreachable!("Dead thread was switched to");
+}
+
+/// Switch from the running thread to `next`, interrupts must be disabled.
+/// The current thread must be kept alive by a run queue, the dead list or [`park`]
+fn switch(current: Arc<Thread>, next: Arc<Thread>) {
+    crate::sync::lockdep::switch(&current.held_locks, &next.held_locks);
+    let from = current.context.get();
+    let to = next.context.get();
+    *CURRENT[crate::arch::Cpu::cpu_id()].lock() = Some(next);
+    drop(current);
+    unsafe {
+        crate::arch::Context::switch_to(from, to);
+    }
+    // Back in this thread, the one switched from might have exited
+    free_dead();
+}
+
+/// Free threads, that exited on this CPU. None of them is
+/// running, as they are only added right before switching away
+fn free_dead() {
+    let dead = core::mem::take(&mut *DEAD[crate::arch::Cpu::cpu_id()].lock());
+    drop(dead);
+}
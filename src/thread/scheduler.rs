@@ -0,0 +1,350 @@
+use crate::arch::traits::*;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use super::{Thread, ThreadState};
+use crate::memory::MappingResult;
+use crate::sync::{irq_safe_mutex, IrqSafeMutex};
+
+/// Length of a time slice in timer ticks
+const SLICE_TICKS: usize = 5;
+
+/// Virtual runtime, that a tick adds to a thread of priority level 1
+const FAIR_SCALE: u64 = 1 << 16;
+
+/// Scheduling priority, threads with a higher one are picked first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Priority(u8);
+
+impl Priority {
+    /// Only the idle thread runs with it
+    const IDLE: Self = Self(0);
+    pub const LOW: Self = Self(1);
+    pub const NORMAL: Self = Self(16);
+    pub const HIGH: Self = Self(31);
+
+    /// Make a priority of the level, clamped between [`Self::LOW`] and [`Self::HIGH`]
+    pub const fn new(level: u8) -> Self {
+        if level < Self::LOW.0 {
+            Self::LOW
+        } else if level > Self::HIGH.0 {
+            Self::HIGH
+        } else {
+            Self(level)
+        }
+    }
+
+    pub const fn level(self) -> u8 {
+        self.0
+    }
+}
+
+/// How the next thread is picked from a run queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Policy {
+    /// Highest priority first, round-robin between threads of the same priority.
+    /// Lower priorities only run, when no higher one is ready
+    Priority,
+    /// Thread, that ran the least, weighted by it's priority, first.
+    /// Every thread gets CPU time proportional to it's priority level
+    FairShare,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(Policy::Priority as u8);
+
+/// Get the scheduling policy
+pub fn policy() -> Policy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => Policy::Priority,
+        _ => Policy::FairShare,
+    }
+}
+
+/// Change the scheduling policy. Takes effect at the next switch
+pub fn set_policy(policy: Policy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Scheduler state of a CPU. It's only changed by the CPU itself,
+/// interrupt handlers included, so atomics are used instead of a lock
+struct CpuState {
+    /// Number of spinlocks held, the running thread isn't preempted unless it's 0
+    preempt_count: AtomicUsize,
+    /// Running thread should be switched at the next preemption point
+    need_resched: AtomicBool,
+    /// Ticks left in the time slice of the running thread
+    slice: AtomicUsize,
+    /// Ticks the running thread ran since it was switched to
+    ticks: AtomicUsize,
+    /// Priority level of the running thread
+    priority: AtomicU8,
+}
+
+impl CpuState {
+    const fn new() -> Self {
+        Self {
+            preempt_count: AtomicUsize::new(0),
+            need_resched: AtomicBool::new(false),
+            slice: AtomicUsize::new(SLICE_TICKS),
+            ticks: AtomicUsize::new(0),
+            priority: AtomicU8::new(Priority::NORMAL.0),
+        }
+    }
+}
+
+/// Threads, that are ready to run on a CPU
+struct RunQueue {
+    threads: VecDeque<Arc<Thread>>,
+    /// Threads, that the queue has room for. Every thread of the CPU, but the
+    /// idle one, is counted, so that pushing never allocates. Interrupt
+    /// handlers push threads, and the heap lock might be held meanwhile
+    reserved: usize,
+    /// Runs when no other thread is ready
+    idle: Option<Arc<Thread>>,
+    /// Virtual runtime of the last thread picked by [`Policy::FairShare`].
+    /// Threads, that join the queue, start from it, so they can't run for
+    /// as long as everyone else did before them
+    min_vruntime: u64,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            threads: VecDeque::new(),
+            reserved: 0,
+            idle: None,
+            min_vruntime: 0,
+        }
+    }
+
+    fn push(&mut self, thread: Arc<Thread>) {
+        {
+            let mut vruntime = thread.vruntime.lock();
+            *vruntime = (*vruntime).max(self.min_vruntime);
+        }
+        thread.set_state(ThreadState::Ready);
+        debug_assert!(self.threads.len() < self.threads.capacity());
+        self.threads.push_back(thread);
+    }
+
+    /// Take the thread, that should run next according to the policy
+    fn pop(&mut self, policy: Policy) -> Option<Arc<Thread>> {
+        // Both return the first of equal threads, so the queue stays round-robin
+        let index = match policy {
+            Policy::Priority => {
+                self.threads
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, thread)| core::cmp::Reverse(thread.priority()))?
+                    .0
+            }
+            Policy::FairShare => {
+                self.threads
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, thread)| *thread.vruntime.lock())?
+                    .0
+            }
+        };
+        let thread = self.threads.remove(index)?;
+        if policy == Policy::FairShare {
+            self.min_vruntime = *thread.vruntime.lock();
+        }
+        Some(thread)
+    }
+
+    fn is_idle(&self, thread: &Arc<Thread>) -> bool {
+        self.idle
+            .as_ref()
+            .is_some_and(|idle| Arc::ptr_eq(idle, thread))
+    }
+}
+
+static CPUS: [CpuState; <crate::arch::Cpu as CpuTrait>::MAX_CPUS] =
+    [const { CpuState::new() }; <crate::arch::Cpu as CpuTrait>::MAX_CPUS];
+
+/// Run queues are used when an interrupt preempts a thread
+static RUN_QUEUES: [IrqSafeMutex<RunQueue>; <crate::arch::Cpu as CpuTrait>::MAX_CPUS] =
+    [const { irq_safe_mutex(RunQueue::new()) }; <crate::arch::Cpu as CpuTrait>::MAX_CPUS];
+
+/// Timer doesn't preempt anything, until the first thread is spawned
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+fn this_cpu() -> &'static CpuState {
+    &CPUS[crate::arch::Cpu::cpu_id()]
+}
+
+impl Thread {
+    pub fn priority(&self) -> Priority {
+        Priority(self.priority.load(Ordering::Relaxed))
+    }
+
+    /// Change the priority. If the thread is running, it
+    /// might get preempted only when it's time slice is over
+    pub fn set_priority(&self, priority: Priority) {
+        self.priority.store(priority.0, Ordering::Relaxed);
+    }
+
+    /// Account ticks, that the thread ran, to it's virtual runtime
+    fn charge(&self, ticks: usize) {
+        // Idle thread has no weight, it's never picked by the policy anyway
+        let weight = self.priority().0 as u64;
+        if let Some(runtime) = (ticks as u64 * FAIR_SCALE).checked_div(weight) {
+            *self.vruntime.lock() += runtime;
+        }
+    }
+}
+
+/// Run a closure with interrupts disabled on this CPU
+pub(super) fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = crate::arch::Cpu::disable_interrupts();
+    let result = f();
+    if enabled {
+        crate::arch::Cpu::enable_interrupts();
+    }
+    result
+}
+
+/// Create the idle thread of this CPU, if there is none, and start preemption
+pub(super) fn start() -> MappingResult<()> {
+    let cpu = crate::arch::Cpu::cpu_id();
+    if RUN_QUEUES[cpu].lock().idle.is_none() {
+        let idle = Thread::new(idle, 0, Priority::IDLE)?;
+        let mut queue = RUN_QUEUES[cpu].lock();
+        if queue.idle.is_none() {
+            queue.idle = Some(idle);
+            drop(queue);
+            // Thread, that booted the kernel, joins the queue when it yields
+            reserve(cpu);
+        }
+    }
+    STARTED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Idle thread, interrupt return switches away from it once something is ready
+fn idle(_: usize) {
+    loop {
+        crate::arch::Cpu::wait_for_interrupt();
+    }
+}
+
+/// Make room for one more thread in the run queue of a CPU
+pub(super) fn reserve(cpu: usize) {
+    loop {
+        let capacity = {
+            let mut queue = RUN_QUEUES[cpu].lock();
+            if queue.reserved < queue.threads.capacity() {
+                queue.reserved += 1;
+                return;
+            }
+            queue.threads.capacity()
+        };
+
+        // Never allocate or free while holding the queue lock
+        let mut grown = VecDeque::with_capacity(capacity * 2 + 4);
+        let mut queue = RUN_QUEUES[cpu].lock();
+        if queue.threads.capacity() == capacity {
+            grown.append(&mut queue.threads);
+            core::mem::swap(&mut queue.threads, &mut grown);
+        }
+        drop(queue);
+        drop(grown);
+    }
+}
+
+/// Give back the room, that [`reserve`] made, once a thread exits
+pub(super) fn unreserve(cpu: usize) {
+    RUN_QUEUES[cpu].lock().reserved -= 1;
+}
+
+/// Put a thread into the run queue of it's CPU. Never allocates, so
+/// interrupt handlers can wake threads up. It preempts the running
+/// thread, if that one is idle or, with [`Policy::Priority`], less important
+pub(super) fn enqueue(thread: Arc<Thread>) {
+    let cpu = thread.cpu;
+    let priority = thread.priority().0;
+    RUN_QUEUES[cpu].lock().push(thread);
+    let running = CPUS[cpu].priority.load(Ordering::Relaxed);
+    if running == Priority::IDLE.0 || (policy() == Policy::Priority && priority > running) {
+        CPUS[cpu].need_resched.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Pick the next thread and switch to it, interrupts must be disabled.
+/// The running thread is put back into the run queue if `requeue` is set
+pub(super) fn reschedule(requeue: bool) {
+    let cpu_id = crate::arch::Cpu::cpu_id();
+    let cpu = &CPUS[cpu_id];
+    cpu.need_resched.store(false, Ordering::SeqCst);
+    let current = super::current();
+    current.charge(cpu.ticks.swap(0, Ordering::Relaxed));
+
+    let next = {
+        let mut queue = RUN_QUEUES[cpu_id].lock();
+        if requeue && !queue.is_idle(&current) {
+            queue.push(current.clone());
+        }
+        queue.pop(policy()).or_else(|| queue.idle.clone())
+    };
+    let Some(next) = next else {
+        panic!("Thread {} stopped, but there is nothing to run", current.id);
+    };
+
+    cpu.slice.store(SLICE_TICKS, Ordering::Relaxed);
+    cpu.priority.store(next.priority().0, Ordering::Relaxed);
+    next.set_state(ThreadState::Running);
+    if !Arc::ptr_eq(&current, &next) {
+        super::switch(current, next);
+    }
+}
+
+/// Account a timer tick to the running thread. Called by the timer interrupt handler
+pub fn tick() {
+    if !STARTED.load(Ordering::Relaxed) {
+        return;
+    }
+    let cpu = this_cpu();
+    cpu.ticks.fetch_add(1, Ordering::Relaxed);
+    let slice = cpu.slice.load(Ordering::Relaxed);
+    if slice > 1 {
+        cpu.slice.store(slice - 1, Ordering::Relaxed);
+    } else {
+        cpu.need_resched.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Preemption point at the end of interrupt handlers, interrupts are disabled.
+/// Switches to another thread, if it's needed and no spinlock is held
+pub fn irq_return() {
+    let cpu = this_cpu();
+    if cpu.need_resched.load(Ordering::SeqCst) && cpu.preempt_count.load(Ordering::SeqCst) == 0 {
+        reschedule(true);
+    }
+}
+
+/// Number of spinlocks held on this CPU
+pub fn preempt_count() -> usize {
+    this_cpu().preempt_count.load(Ordering::SeqCst)
+}
+
+/// Don't preempt the running thread until [`preempt_enable`]. Calls can be nested
+pub fn preempt_disable() {
+    this_cpu().preempt_count.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Undo [`preempt_disable`]. Switches to another thread, if it was needed
+/// meanwhile. With interrupts disabled, either an interrupt handler will
+/// do that on return, or the scheduler itself is running
+pub fn preempt_enable() {
+    let cpu = this_cpu();
+    if cpu.preempt_count.fetch_sub(1, Ordering::SeqCst) == 1
+        && cpu.need_resched.load(Ordering::SeqCst)
+        && crate::arch::Cpu::disable_interrupts()
+    {
+        reschedule(true);
+        crate::arch::Cpu::enable_interrupts();
+    }
+}
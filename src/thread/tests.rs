@@ -0,0 +1,101 @@
+pub fn run() {
+    test_timer();
+    test_threads();
+    test_priorities();
+    test_preemption();
+}
+
+fn test_timer() {
+    use crate::arch::traits::*;
+
+    let start = crate::arch::Timer::ticks();
+    while crate::arch::Timer::ticks() < start + 10 {
+        crate::arch::Cpu::wait_for_interrupt();
+    }
+    crate::println!("Timer ticks: {}", crate::arch::Timer::ticks());
+}
+
+fn test_threads() {
+    use crate::thread::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    fn worker(step: usize) {
+        for _ in 0..3 {
+            COUNTER.fetch_add(step, Ordering::SeqCst);
+            yield_now();
+        }
+    }
+
+    let boot = current().id();
+    let threads = [1, 2, 3].map(|step| spawn(worker, step).unwrap());
+    for thread in &threads {
+        thread.join();
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 18);
+    assert_eq!(current().id(), boot);
+    crate::println!(
+        "Threads {:?} are done, counter is {}",
+        threads.each_ref().map(|thread| thread.id()),
+        COUNTER.load(Ordering::SeqCst)
+    );
+}
+
+fn test_priorities() {
+    use crate::sync::{mutex, Mutex};
+    use crate::thread::*;
+
+    static ORDER: Mutex<alloc::vec::Vec<u8>> = mutex(alloc::vec::Vec::new());
+    fn worker(level: usize) {
+        ORDER.lock().push(level as u8);
+    }
+
+    for priority in [Priority::LOW, Priority::HIGH] {
+        spawn_with_priority(worker, priority.level() as _, priority).unwrap();
+    }
+    // Low priority thread only runs, when nothing more important is ready
+    yield_now();
+    assert_eq!(*ORDER.lock(), [Priority::HIGH.level()]);
+    let boot = current();
+    boot.set_priority(Priority::LOW);
+    yield_now();
+    boot.set_priority(Priority::NORMAL);
+    assert_eq!(
+        *ORDER.lock(),
+        [Priority::HIGH.level(), Priority::LOW.level()]
+    );
+    crate::println!("Threads ran in order of priority: {:?}", *ORDER.lock());
+}
+
+fn test_preemption() {
+    use crate::thread::*;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static SPINS: [AtomicUsize; 2] = [const { AtomicUsize::new(0) }; 2];
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    // Never yields, only the timer can switch away from it
+    fn spinner(index: usize) {
+        while !STOP.load(Ordering::SeqCst) {
+            SPINS[index].fetch_add(1, Ordering::SeqCst);
+        }
+        DONE.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // With strict priorities, the high priority spinner would never let the boot thread run
+    scheduler::set_policy(Policy::FairShare);
+    spawn_with_priority(spinner, 0, Priority::NORMAL).unwrap();
+    spawn_with_priority(spinner, 1, Priority::HIGH).unwrap();
+    let start = crate::arch::Timer::ticks();
+    while crate::arch::Timer::ticks() < start + 50 {
+        core::hint::spin_loop();
+    }
+    STOP.store(true, Ordering::SeqCst);
+    scheduler::set_policy(Policy::Priority);
+    while DONE.load(Ordering::SeqCst) < 2 {
+        yield_now();
+    }
+    let spins = SPINS.each_ref().map(|spins| spins.load(Ordering::SeqCst));
+    assert!(spins.iter().all(|&spins| spins > 0));
+    crate::println!("Spinners were preempted, ran {:?} times in 50 ticks", spins);
+}